@@ -0,0 +1,52 @@
+use futures::future::{Either, select};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::BluetoothError;
+
+const BRIDGE_CHUNK_SIZE: usize = 4096;
+
+/// 在两个连接之间双向转发数据，比如把两个蓝牙会话接起来，或者把会话转到TCP上。
+///
+/// 两个方向同时转发，任意一边读到EOF就结束，返回`(a到b的字节数, b到a的字节数)`，
+/// 另一个方向正在写的那块可能只写了一部分。任意一边读写出错都直接返回错误。
+pub async fn bridge<A, B>(a: &mut A, b: &mut B) -> crate::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+
+    let mut a_to_b = 0;
+    let mut b_to_a = 0;
+
+    let forward = Box::pin(pump(&mut a_read, &mut b_write, &mut a_to_b));
+    let backward = Box::pin(pump(&mut b_read, &mut a_write, &mut b_to_a));
+
+    match select(forward, backward).await {
+        Either::Left((result, _)) | Either::Right((result, _)) => result?,
+    }
+
+    Ok((a_to_b, b_to_a))
+}
+
+// 单向转发到EOF，每块写完并flush了才算进字节数
+async fn pump<R, W>(reader: &mut R, writer: &mut W, copied: &mut u64) -> crate::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let io_error = |err: std::io::Error| BluetoothError::RuntimeError(err.to_string());
+    let mut buf = vec![0u8; BRIDGE_CHUNK_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf).await.map_err(io_error)?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        writer.write_all(&buf[..n]).await.map_err(io_error)?;
+        writer.flush().await.map_err(io_error)?;
+        *copied += n as u64;
+    }
+}
@@ -0,0 +1,69 @@
+use std::{future::Future, pin::pin, task::Poll, time::Duration};
+
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::{BluetoothError, TimeoutOp};
+
+/// 给`future`加上取消，取消返回`Cancelled`；`future`自己先完成的话以它的结果为准
+pub async fn with_cancel<T, F>(future: F, cancel: &CancellationToken) -> crate::Result<T>
+where
+    F: Future<Output = crate::Result<T>>,
+{
+    let mut future = pin!(future);
+    let mut cancelled = pin!(cancel.cancelled());
+
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(result) = future.as_mut().poll(cx) {
+            return Poll::Ready(result);
+        }
+
+        if cancelled.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(BluetoothError::Cancelled));
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// 给`future`同时加上超时和取消，超时返回`TimedOut`，取消返回`Cancelled`。
+///
+/// 两个同时发生时（比如取消和超时在同一次唤醒里都到了）算超时，结果是确定的；
+/// `future`自己已经完成的话以它的结果为准。
+pub async fn with_timeout_or_cancel<T, F>(
+    future: F,
+    timeout: Duration,
+    operation: TimeoutOp,
+    cancel: &CancellationToken,
+) -> crate::Result<T>
+where
+    F: Future<Output = crate::Result<T>>,
+{
+    let mut future = pin!(future);
+    let deadline = time::Instant::now() + timeout;
+    let mut sleep = pin!(time::sleep_until(deadline));
+    let mut cancelled = pin!(cancel.cancelled());
+
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(result) = future.as_mut().poll(cx) {
+            return Poll::Ready(result);
+        }
+
+        // 先看超时再看取消，保证两者同时到时总是超时。计时器有1ms的粒度，
+        // 时间到了但还没触发的也算超时
+        if sleep.as_mut().poll(cx).is_ready() || time::Instant::now() >= deadline {
+            return Poll::Ready(Err(BluetoothError::TimedOut {
+                duration: timeout,
+                operation,
+            }));
+        }
+
+        if cancelled.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(BluetoothError::Cancelled));
+        }
+
+        Poll::Pending
+    })
+    .await
+}
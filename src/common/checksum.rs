@@ -0,0 +1,68 @@
+/// `send_with_crc`用的校验算法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumKind {
+    /// CRC-32（IEEE 802.3，zip/png用的那个）
+    #[default]
+    Crc32,
+    /// CRC-16/CCITT-FALSE（多项式0x1021，初值0xFFFF，不反转），结果放在低16位
+    Crc16,
+}
+
+/// 可以分段喂数据的校验计算，分多少段算出来都一样
+#[derive(Debug, Clone)]
+pub struct Checksum {
+    kind: ChecksumKind,
+    state: u32,
+}
+
+impl Checksum {
+    pub fn new(kind: ChecksumKind) -> Checksum {
+        let state = match kind {
+            ChecksumKind::Crc32 => 0xFFFF_FFFF,
+            ChecksumKind::Crc16 => 0xFFFF,
+        };
+        Checksum { kind, state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self.kind {
+            ChecksumKind::Crc32 => {
+                for &byte in data {
+                    self.state ^= byte as u32;
+                    for _ in 0..8 {
+                        let mask = (self.state & 1).wrapping_neg();
+                        self.state = (self.state >> 1) ^ (0xEDB8_8320 & mask);
+                    }
+                }
+            }
+            ChecksumKind::Crc16 => {
+                let mut crc = self.state as u16;
+                for &byte in data {
+                    crc ^= (byte as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 {
+                            (crc << 1) ^ 0x1021
+                        } else {
+                            crc << 1
+                        };
+                    }
+                }
+                self.state = crc as u32;
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        match self.kind {
+            ChecksumKind::Crc32 => !self.state,
+            ChecksumKind::Crc16 => self.state,
+        }
+    }
+}
+
+/// 一次算完整段数据
+pub fn checksum(kind: ChecksumKind, data: &[u8]) -> u32 {
+    let mut checksum = Checksum::new(kind);
+    checksum.update(data);
+    checksum.finish()
+}
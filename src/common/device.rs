@@ -1,12 +1,23 @@
+use std::{collections::HashMap, time::SystemTime};
+
 use uuid::{Uuid, uuid};
 
-use crate::common::mac::{mac_string_to_u64, mac_u64_to_string};
+use crate::{
+    BluetoothError,
+    common::{
+        mac::{MacParseError, mac_string_to_u64, mac_u64_to_string},
+        uuid::service_label,
+    },
+};
 
 pub static SPP_UUID: Uuid = uuid!("00001101-0000-1000-8000-00805F9B34FB");
 
-#[derive(Clone)]
+/// 打开`serde`特性后可以序列化，地址存成`"AA:BB:CC:DD:EE:FF"`而不是数字
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BluetoothDevice {
     pub name: String,
+    #[cfg_attr(feature = "serde", serde(with = "addr_string"))]
     pub addr: u64,
 }
 
@@ -18,15 +29,12 @@ impl BluetoothDevice {
         };
     }
 
-    pub fn new_by_addr_string(name: String, addr: &String) -> Result<BluetoothDevice, ()> {
-        if let Some(u64_addr) = mac_string_to_u64(&addr) {
-            return Ok(BluetoothDevice {
-                name: name.clone(),
-                addr: u64_addr,
-            });
-        } else {
-            Err(())
-        }
+    pub fn new_by_addr_string(name: String, addr: &str) -> Result<BluetoothDevice, MacParseError> {
+        let u64_addr = mac_string_to_u64(addr)?;
+        Ok(BluetoothDevice {
+            name,
+            addr: u64_addr,
+        })
     }
 
     pub fn empty() -> BluetoothDevice {
@@ -44,4 +52,310 @@ impl BluetoothDevice {
     pub fn addr_string(&self) -> String {
         mac_u64_to_string(self.addr)
     }
+
+    /// 地址的6个字节，大端：第一个字节就是`addr_string`里最左边那段
+    pub fn addr_bytes(&self) -> [u8; 6] {
+        let bytes = self.addr.to_be_bytes();
+        [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]
+    }
+
+    /// 两个设备是不是同一个射频端点，只比较地址，名字不管。
+    ///
+    /// 双模设备的经典蓝牙和LE可能用同一个地址出现两次，这个类型里不记录传输方式，
+    /// 所以这两种身份在这里是分不出来的，会被当成同一个端点。
+    pub fn same_endpoint(&self, other: &BluetoothDevice) -> bool {
+        self.addr == other.addr
+    }
+
+    /// 返回一份名字规整过的拷贝：去掉首尾空白，中间连续的空白合成一个空格，地址不变。
+    ///
+    /// 设备相等只看地址，所以规整前后的设备仍然相等，只是显示和去重时名字更一致。
+    pub fn normalized(&self) -> BluetoothDevice {
+        BluetoothDevice::new(
+            self.name.split_whitespace().collect::<Vec<_>>().join(" "),
+            self.addr,
+        )
+    }
+}
+
+// 同一个地址就是同一台设备，名字只是显示用的
+impl PartialEq for BluetoothDevice {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr
+    }
+}
+
+impl Eq for BluetoothDevice {}
+
+impl std::hash::Hash for BluetoothDevice {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+    }
+}
+
+/// 扫描时看到的一台设备，是扫描那一刻的快照
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub device: BluetoothDevice,
+    pub paired: bool,
+    pub connected: bool,
+    pub rssi: Option<i16>,
+    /// Class of Device，系统没报就是`None`
+    pub class_of_device: Option<u32>,
+    /// 最后一次看到这台设备的时间，系统没提供时用扫描时间代替
+    pub last_seen: Option<SystemTime>,
+}
+
+impl DeviceInfo {
+    /// `last_seen`是系统报上来的时间，没有就记成现在
+    pub fn observed(device: BluetoothDevice, last_seen: Option<SystemTime>) -> DeviceInfo {
+        DeviceInfo {
+            device,
+            paired: false,
+            connected: false,
+            rssi: None,
+            class_of_device: None,
+            last_seen: Some(last_seen.unwrap_or_else(SystemTime::now)),
+        }
+    }
+}
+
+/// 已连接设备的详细信息，系统拿不到的字段都是`None`。
+///
+/// WinRT不提供LMP版本和特性位，这里只有系统能给出的那些。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceDetails {
+    /// 系统里的设备ID，例如`Bluetooth#Bluetooth00:1a:7d:da:71:13-d0:ae:05:05:1a:22`
+    pub device_id: Option<String>,
+    /// 原始的Class of Device，24位
+    pub class_of_device: Option<u32>,
+    /// 系统认为链路是否还连着
+    pub connected: Option<bool>,
+    /// 配对时有没有用Secure Connections
+    pub secure_connection: Option<bool>,
+}
+
+/// 按地址去重的设备集合，多次扫描的结果可以不断合并进来
+#[derive(Debug, Clone, Default)]
+pub struct DeviceSet {
+    devices: HashMap<BluetoothDevice, DeviceInfo>,
+}
+
+impl DeviceSet {
+    pub fn new() -> DeviceSet {
+        DeviceSet::default()
+    }
+
+    /// 新地址直接插入；已有的地址更新信息：名字取最新的非空名字，RSSI取最强的，
+    /// `last_seen`取最晚的，设备类型这次没报就保留原来的，配对和连接状态以这次为准
+    pub fn merge(&mut self, info: DeviceInfo) {
+        match self.devices.get_mut(&info.device) {
+            Some(existing) => {
+                if !info.device.name.is_empty() {
+                    existing.device.name = info.device.name;
+                }
+                existing.paired = info.paired;
+                existing.connected = info.connected;
+                existing.rssi = existing.rssi.max(info.rssi);
+                existing.class_of_device = info.class_of_device.or(existing.class_of_device);
+                existing.last_seen = existing.last_seen.max(info.last_seen);
+            }
+            None => {
+                self.devices.insert(info.device.clone(), info);
+            }
+        }
+    }
+
+    pub fn get(&self, device: &BluetoothDevice) -> Option<&DeviceInfo> {
+        self.devices.get(device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// 遍历顺序不固定
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceInfo> {
+        self.devices.values()
+    }
+}
+
+impl Extend<DeviceInfo> for DeviceSet {
+    fn extend<I: IntoIterator<Item = DeviceInfo>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|info| self.merge(info));
+    }
+}
+
+impl FromIterator<DeviceInfo> for DeviceSet {
+    fn from_iter<I: IntoIterator<Item = DeviceInfo>>(iter: I) -> DeviceSet {
+        let mut set = DeviceSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl IntoIterator for DeviceSet {
+    type Item = DeviceInfo;
+    type IntoIter = std::collections::hash_map::IntoValues<BluetoothDevice, DeviceInfo>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.devices.into_values()
+    }
+}
+
+/// 6字节的地址转成没有名字的设备，字节顺序和`addr_bytes`一样是大端
+impl From<[u8; 6]> for BluetoothDevice {
+    fn from(bytes: [u8; 6]) -> Self {
+        let mut addr = [0u8; 8];
+        addr[2..].copy_from_slice(&bytes);
+        BluetoothDevice::new(String::new(), u64::from_be_bytes(addr))
+    }
+}
+
+/// 同`From<[u8; 6]>`，长度不是6时返回`InvalidAddress`
+impl TryFrom<&[u8]> for BluetoothDevice {
+    type Error = BluetoothError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        match <[u8; 6]>::try_from(value) {
+            Ok(bytes) => Ok(BluetoothDevice::from(bytes)),
+            Err(_) => Err(BluetoothError::InvalidAddress(format!("{:02X?}", value))),
+        }
+    }
+}
+
+impl TryFrom<&str> for BluetoothDevice {
+    type Error = BluetoothError;
+
+    // 支持"name@MAC"或者只有MAC两种写法
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (name, addr) = match value.rsplit_once('@') {
+            Some((name, addr)) => (name.trim(), addr.trim()),
+            None => ("", value.trim()),
+        };
+
+        match mac_string_to_u64(addr) {
+            Ok(u64_addr) => Ok(BluetoothDevice::new(name.to_string(), u64_addr)),
+            Err(_) => Err(BluetoothError::InvalidAddress(addr.to_string())),
+        }
+    }
+}
+
+/// 写成`name (AA:BB:CC:DD:EE:FF)`，没有名字时只写地址
+impl std::fmt::Display for BluetoothDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.addr_string())
+        } else {
+            write!(f, "{} ({})", self.name, self.addr_string())
+        }
+    }
+}
+
+/// `BluetoothDevice`按`name (MAC)`格式解析失败的原因
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DeviceParseError {
+    #[error("Device name is missing")]
+    MissingName,
+
+    #[error("Invalid address: {:?}", _0)]
+    InvalidAddress(String),
+
+    #[error("Expected \"name (AA:BB:CC:DD:EE:FF)\", got {:?}", _0)]
+    Malformed(String),
+}
+
+/// 解析`Display`的格式：`name (AA:BB:CC:DD:EE:FF)`，或者没有名字时的裸地址，
+/// 后者解析成名字为空的设备，和`Display`对得上。
+///
+/// 名字前后的空白会被去掉。写了括号但括号前没有名字时返回`MissingName`。
+impl std::str::FromStr for BluetoothDevice {
+    type Err = DeviceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        let Some((name, rest)) = s.strip_suffix(')').and_then(|inner| inner.rsplit_once('('))
+        else {
+            return match mac_string_to_u64(s) {
+                Ok(addr) => Ok(BluetoothDevice::new(String::new(), addr)),
+                Err(_) => Err(DeviceParseError::Malformed(s.to_string())),
+            };
+        };
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(DeviceParseError::MissingName);
+        }
+
+        let addr = rest.trim();
+        match mac_string_to_u64(addr) {
+            Ok(addr) => Ok(BluetoothDevice::new(name.to_string(), addr)),
+            Err(_) => Err(DeviceParseError::InvalidAddress(addr.to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod addr_string {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    use crate::common::mac::{mac_string_to_u64, mac_u64_to_string};
+
+    pub fn serialize<S: Serializer>(addr: &u64, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&mac_u64_to_string(*addr))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        let value = String::deserialize(d)?;
+        mac_string_to_u64(&value).map_err(D::Error::custom)
+    }
+}
+
+/// 解析逗号分隔的设备列表，例如`"OBDII@00:02:B0:57:7D:D6, D0:AE:05:05:1A:22"`。
+///
+/// 空项会被跳过；遇到第一个解析失败的项时返回`InvalidDeviceEntry`，里面带着它的下标。
+pub fn parse_device_csv(s: &str) -> crate::Result<Vec<BluetoothDevice>> {
+    s.split(',')
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, entry)| !entry.is_empty())
+        .map(|(index, entry)| {
+            BluetoothDevice::try_from(entry)
+                .map_err(|_| BluetoothError::InvalidDeviceEntry(index, entry.to_string()))
+        })
+        .collect()
+}
+
+// 各个会话的summary()共用同一个格式，保证输出稳定
+pub(crate) fn session_summary(
+    label: &str,
+    device: &BluetoothDevice,
+    uuid: Uuid,
+    ready: bool,
+    read_pending: bool,
+) -> String {
+    let target = if device.name.is_empty() {
+        device.addr_string()
+    } else {
+        format!("{} ({})", device.name, device.addr_string())
+    };
+
+    let summary = format!(
+        "{} service={} ready={} read_pending={}",
+        target,
+        service_label(uuid),
+        ready,
+        read_pending
+    );
+
+    if label.is_empty() {
+        summary
+    } else {
+        format!("[{}] {}", label, summary)
+    }
 }
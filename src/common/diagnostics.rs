@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use crate::BluetoothError;
+
+/// 连接过程中某一步的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepOutcome {
+    Ok,
+    /// 查询类的步骤，找到了几个
+    Found(usize),
+    TimedOut,
+    Failed(String),
+}
+
+/// 连接过程中的一步，比如`FindAll`、`FromId`、`Pair`、`GetServices`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectStep {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub outcome: StepOutcome,
+}
+
+impl std::fmt::Display for ConnectStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.outcome {
+            StepOutcome::Ok => write!(f, "{}: {}ms ok", self.name, self.elapsed.as_millis()),
+            StepOutcome::Found(count) => write!(
+                f,
+                "{}: {}ms {} found",
+                self.name,
+                self.elapsed.as_millis(),
+                count
+            ),
+            StepOutcome::TimedOut => write!(f, "{}: timeout", self.name),
+            StepOutcome::Failed(err) => write!(
+                f,
+                "{}: {}ms failed ({})",
+                self.name,
+                self.elapsed.as_millis(),
+                err
+            ),
+        }
+    }
+}
+
+/// 一次连接的时间线，每一步花了多久、结果如何。
+///
+/// 和日志不同，它是个可以拿回来的值：连接失败后照样能取到，直接贴进bug报告里。
+/// `Display`每行一步。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectDiagnostics {
+    steps: Vec<ConnectStep>,
+    // 开始了还没结束的那一步，超时被打断时它就是卡住的地方
+    pending: Option<(&'static str, Instant)>,
+}
+
+impl ConnectDiagnostics {
+    pub fn new() -> ConnectDiagnostics {
+        ConnectDiagnostics::default()
+    }
+
+    pub fn steps(&self) -> &[ConnectStep] {
+        &self.steps
+    }
+
+    /// 第一个超时或出错的步骤，没有时是`None`。查询找到0个不算出错，看最后一步的`Found(0)`
+    pub fn failed_step(&self) -> Option<&ConnectStep> {
+        self.steps
+            .iter()
+            .find(|step| matches!(step.outcome, StepOutcome::TimedOut | StepOutcome::Failed(_)))
+    }
+
+    pub(crate) fn begin(&mut self, name: &'static str) {
+        self.pending = Some((name, Instant::now()));
+    }
+
+    pub(crate) fn end(&mut self, outcome: StepOutcome) {
+        if let Some((name, started)) = self.pending.take() {
+            self.steps.push(ConnectStep {
+                name,
+                elapsed: started.elapsed(),
+                outcome,
+            });
+        }
+    }
+
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub(crate) fn end_with<T>(&mut self, result: &crate::Result<T>) {
+        self.end(match result {
+            Ok(_) => StepOutcome::Ok,
+            Err(BluetoothError::TimedOut { .. }) => StepOutcome::TimedOut,
+            Err(err) => StepOutcome::Failed(err.to_string()),
+        });
+    }
+
+    // 整个连接超时被打断了，没走完的那一步记成超时
+    pub(crate) fn time_out_pending(&mut self) {
+        self.end(StepOutcome::TimedOut);
+    }
+}
+
+impl std::fmt::Display for ConnectDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
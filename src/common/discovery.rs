@@ -0,0 +1,275 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use futures::{Stream, StreamExt, stream};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    BluetoothError, BluetoothSppSession,
+    common::{
+        device::{BluetoothDevice, DeviceInfo},
+        mac::mac_string_to_u64,
+        pairing::PairingConfig,
+    },
+};
+
+/// 同时查询服务的设备数上限，太多了系统的SDP查询会排队甚至超时
+pub const SERVICE_RESOLVE_CONCURRENCY: usize = 4;
+
+/// 并发地（最多`limit`个）给每个设备查服务，结果按原来的顺序配对。
+///
+/// 单个设备查询失败时给空列表，不影响其他设备。
+pub async fn resolve_services<F, Fut>(
+    devices: Vec<BluetoothDevice>,
+    limit: usize,
+    resolve: F,
+) -> Vec<(BluetoothDevice, Vec<Uuid>)>
+where
+    F: Fn(BluetoothDevice) -> Fut,
+    Fut: Future<Output = crate::Result<Vec<Uuid>>>,
+{
+    stream::iter(devices)
+        .map(|device| {
+            let services = resolve(device.clone());
+            async move { (device, services.await.unwrap_or_default()) }
+        })
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
+
+// connect_by_name的流程：先按地址连，设备找不到并且有名字时用`resolve`按名字重新找地址，
+// `rebind`换过去以后再连一次。别的错误不重试
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) async fn connect_or_resolve<S, R, Fut, B>(
+    session: &mut S,
+    device: &BluetoothDevice,
+    uuid: Uuid,
+    pairing: PairingConfig,
+    resolve: R,
+    rebind: B,
+) -> crate::Result<()>
+where
+    S: BluetoothSppSession,
+    R: FnOnce(String) -> Fut,
+    Fut: Future<Output = crate::Result<BluetoothDevice>>,
+    B: FnOnce(&mut S, &BluetoothDevice),
+{
+    match session
+        .connect_by_uuid_async(device, uuid, pairing.clone())
+        .await
+    {
+        Err(BluetoothError::DeviceNotFound) if !device.name.is_empty() => {
+            let resolved = resolve(device.name.clone()).await?;
+            rebind(session, &resolved);
+            session
+                .connect_by_uuid_async(&resolved, uuid, pairing)
+                .await
+        }
+        result => result,
+    }
+}
+
+/// 从`resolve_services`的结果里挑出提供`service`的设备，保持原来的顺序
+pub fn devices_with_service(
+    devices: Vec<(BluetoothDevice, Vec<Uuid>)>,
+    service: Uuid,
+) -> Vec<BluetoothDevice> {
+    devices
+        .into_iter()
+        .filter(|(_, services)| services.contains(&service))
+        .map(|(device, _)| device)
+        .collect()
+}
+
+/// 名字里包含`pattern`的设备（不分大小写），保持原来的顺序。`pattern`为空时全部保留
+pub fn devices_matching(devices: Vec<BluetoothDevice>, pattern: &str) -> Vec<BluetoothDevice> {
+    let pattern = pattern.to_lowercase();
+    devices
+        .into_iter()
+        .filter(|device| device.name.to_lowercase().contains(&pattern))
+        .collect()
+}
+
+/// 持续扫描时设备列表的变化
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Added(DeviceInfo),
+    /// 属性有变化，带的是合并之后的完整快照
+    Updated(DeviceInfo),
+    /// 设备消失了，带的是最后一次的快照
+    Removed(DeviceInfo),
+}
+
+/// `DeviceEvent`的精简版，只关心设备出现和消失，不带属性快照
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiscoveryEvent {
+    Added(BluetoothDevice),
+    /// 消失的设备的地址
+    Removed(u64),
+}
+
+impl DiscoveryEvent {
+    /// 属性变化（`Updated`）没有对应的事件，返回`None`
+    pub fn from_device_event(event: DeviceEvent) -> Option<DiscoveryEvent> {
+        match event {
+            DeviceEvent::Added(info) => Some(DiscoveryEvent::Added(info.device)),
+            DeviceEvent::Updated(_) => None,
+            DeviceEvent::Removed(info) => Some(DiscoveryEvent::Removed(info.device.addr())),
+        }
+    }
+}
+
+/// 把`DeviceEvent`流转成`DiscoveryEvent`流，属性变化被过滤掉
+pub fn discovery_events<S>(events: S) -> impl Stream<Item = DiscoveryEvent>
+where
+    S: Stream<Item = DeviceEvent>,
+{
+    events.filter_map(|event| std::future::ready(DiscoveryEvent::from_device_event(event)))
+}
+
+/// 系统推过来的属性变化，没变的字段是`None`
+#[derive(Debug, Clone, Default)]
+pub struct DeviceUpdate {
+    pub name: Option<String>,
+    pub paired: Option<bool>,
+    pub connected: Option<bool>,
+    pub rssi: Option<i16>,
+    pub last_seen: Option<SystemTime>,
+}
+
+// 系统枚举出来的一条记录，属性已经取出来了，还没转成DeviceInfo
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RawDeviceEntry {
+    pub(crate) name: String,
+    pub(crate) address: Option<String>,
+    pub(crate) properties: DeviceUpdate,
+    pub(crate) cod_major: Option<u16>,
+    pub(crate) cod_minor: Option<u16>,
+}
+
+// 没有地址或者地址解析不了的条目没法连，返回None
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) fn device_from_entry(entry: RawDeviceEntry) -> Option<DeviceInfo> {
+    let addr = mac_string_to_u64(&entry.address?).ok()?;
+    let props = entry.properties;
+
+    let mut device = DeviceInfo::observed(BluetoothDevice::new(entry.name, addr), props.last_seen);
+    device.paired = props.paired.unwrap_or(false);
+    device.connected = props.connected.unwrap_or(false);
+    device.rssi = props.rssi;
+    // 系统只给了主次类型，拼回CoD里对应的位，服务类那几位拿不到
+    if let (Some(major), Some(minor)) = (entry.cod_major, entry.cod_minor) {
+        device.class_of_device = Some(((major as u32 & 0x1F) << 8) | ((minor as u32 & 0x3F) << 2));
+    }
+
+    Some(device)
+}
+
+// 一批枚举结果转成设备列表，没法连的跳过，顺序不变
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) fn map_device_entries(entries: Vec<RawDeviceEntry>) -> Vec<DeviceInfo> {
+    entries.into_iter().filter_map(device_from_entry).collect()
+}
+
+// 按系统的设备id记住见过的设备，把watcher的增/改/删回调翻译成DeviceEvent
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+#[derive(Default)]
+pub(crate) struct DeviceTracker {
+    devices: HashMap<String, DeviceInfo>,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+impl DeviceTracker {
+    pub(crate) fn added(&mut self, id: &str, info: DeviceInfo) -> DeviceEvent {
+        self.devices.insert(id.to_string(), info.clone());
+        DeviceEvent::Added(info)
+    }
+
+    // 没见过的id直接忽略，watcher偶尔会先发Updated
+    pub(crate) fn updated(&mut self, id: &str, update: DeviceUpdate) -> Option<DeviceEvent> {
+        let info = self.devices.get_mut(id)?;
+
+        if let Some(name) = update.name {
+            info.device.name = name;
+        }
+        if let Some(paired) = update.paired {
+            info.paired = paired;
+        }
+        if let Some(connected) = update.connected {
+            info.connected = connected;
+        }
+        if update.rssi.is_some() {
+            info.rssi = update.rssi;
+        }
+        info.last_seen = Some(update.last_seen.unwrap_or_else(SystemTime::now));
+
+        Some(DeviceEvent::Updated(info.clone()))
+    }
+
+    pub(crate) fn removed(&mut self, id: &str) -> Option<DeviceEvent> {
+        self.devices.remove(id).map(DeviceEvent::Removed)
+    }
+}
+
+/// 把事件流汇总成设备列表，直到流结束、超时或者`cancel`被取消，返回目前为止攒下的结果。
+///
+/// 同一地址的设备只保留最新的快照，已经`Removed`的设备会被去掉。
+pub async fn collect_devices<S>(
+    events: S,
+    timeout: Duration,
+    cancel: CancellationToken,
+) -> Vec<DeviceInfo>
+where
+    S: Stream<Item = DeviceEvent> + Unpin,
+{
+    collect_devices_limited(events, usize::MAX, timeout, cancel).await
+}
+
+/// 和`collect_devices`一样，但手里的设备凑够`max`个就立刻返回，不再等超时。
+pub async fn collect_devices_limited<S>(
+    mut events: S,
+    max: usize,
+    timeout: Duration,
+    cancel: CancellationToken,
+) -> Vec<DeviceInfo>
+where
+    S: Stream<Item = DeviceEvent> + Unpin,
+{
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    if max == 0 {
+        return devices;
+    }
+
+    let collect = async {
+        while let Some(event) = events.next().await {
+            // 取消之后才到的事件不算
+            if cancel.is_cancelled() {
+                break;
+            }
+
+            match event {
+                DeviceEvent::Added(info) | DeviceEvent::Updated(info) => {
+                    match devices.iter_mut().find(|known| known.device == info.device) {
+                        Some(known) => *known = info,
+                        None => devices.push(info),
+                    }
+                }
+                DeviceEvent::Removed(info) => devices.retain(|known| known.device != info.device),
+            }
+
+            if devices.len() >= max {
+                break;
+            }
+        }
+    };
+    let _ = time::timeout(timeout, cancel.run_until_cancelled(collect)).await;
+
+    devices
+}
@@ -0,0 +1,65 @@
+use crate::common::device::DeviceInfo;
+
+/// CSV的表头，列和`to_csv`每行的字段一一对应
+pub const CSV_HEADER: &str = "address,name,paired,connected,rssi,class";
+
+/// 扫描结果转成CSV，第一行是表头。没有的RSSI和设备类型留空，设备类型写成`0x`开头的十六进制。
+pub fn to_csv(devices: &[DeviceInfo]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+
+    for info in devices {
+        let row = [
+            info.device.addr_string(),
+            csv_field(&info.device.name),
+            info.paired.to_string(),
+            info.connected.to_string(),
+            info.rssi.map(|rssi| rssi.to_string()).unwrap_or_default(),
+            info.class_of_device
+                .map(|class| format!("0x{:06X}", class))
+                .unwrap_or_default(),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+// 名字里有逗号、引号或者换行时整个用引号包起来，里面的引号写两遍
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct DeviceRecord<'a> {
+    address: String,
+    name: &'a str,
+    paired: bool,
+    connected: bool,
+    rssi: Option<i16>,
+    class: Option<u32>,
+}
+
+#[cfg(feature = "serde")]
+impl DeviceInfo {
+    /// 转成一行JSON（不带换行），字段和`to_csv`的列一样，方便一行一个设备地输出
+    pub fn to_json_line(&self) -> String {
+        let record = DeviceRecord {
+            address: self.device.addr_string(),
+            name: &self.device.name,
+            paired: self.paired,
+            connected: self.connected,
+            rssi: self.rssi,
+            class: self.class_of_device,
+        };
+
+        // 字段都是普通的值，不会序列化失败
+        serde_json::to_string(&record).unwrap_or_default()
+    }
+}
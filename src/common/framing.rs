@@ -0,0 +1,348 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::BluetoothError;
+
+/// 默认允许的最大帧长度（长度字段声明的值）
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+impl Endianness {
+    /// 本机的字节序
+    pub const NATIVE: Endianness = if cfg!(target_endian = "big") {
+        Endianness::Big
+    } else {
+        Endianness::Little
+    };
+}
+
+/// 把多字节字段的字节序反过来，`read_struct`按协议的字节序读结构体时用。
+///
+/// 自己的结构体逐个字段调`swap_bytes`就行，单字节字段原样返回。
+pub trait SwapBytes: Copy {
+    fn swap_bytes(self) -> Self;
+}
+
+macro_rules! impl_swap_bytes {
+    ($($ty:ty),*) => {
+        $(impl SwapBytes for $ty {
+            fn swap_bytes(self) -> Self {
+                <$ty>::swap_bytes(self)
+            }
+        })*
+    };
+}
+
+impl_swap_bytes!(u8, i8, u16, i16, u32, i32, u64, i64, u128, i128);
+
+impl<T: SwapBytes, const N: usize> SwapBytes for [T; N] {
+    fn swap_bytes(self) -> Self {
+        self.map(SwapBytes::swap_bytes)
+    }
+}
+
+/// 描述一种"魔数 + 长度字段 + 数据 + 校验"的帧格式。
+///
+/// 例如`a5a5 0200 1600 ...`这种：魔数`a5a5`，偏移4处是2字节小端长度，头一共6字节，结尾2字节校验。
+#[derive(Debug, Clone)]
+pub struct FrameDescriptor {
+    pub magic: Vec<u8>,
+    /// 长度字段相对帧开头的偏移
+    pub length_offset: usize,
+    /// 长度字段的字节数，1到8（32位平台上到4）
+    pub length_size: usize,
+    pub endianness: Endianness,
+    /// 头的总长度，长度字段算的是头后面的数据
+    pub header_len: usize,
+    /// 数据后面跟着的校验字节数，不算在长度字段里
+    pub checksum_len: usize,
+    /// 长度字段超过这个值的帧直接拒绝，不去分配内存
+    pub max_frame_size: usize,
+}
+
+impl FrameDescriptor {
+    /// 魔数为空或者长度字段不是1到8字节时返回`InvalidFrameDescriptor`
+    pub fn new(
+        magic: &[u8],
+        length_offset: usize,
+        length_size: usize,
+    ) -> crate::Result<FrameDescriptor> {
+        let descriptor = FrameDescriptor {
+            magic: magic.to_vec(),
+            length_offset,
+            length_size,
+            endianness: Endianness::Big,
+            header_len: length_offset + length_size,
+            checksum_len: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        };
+        descriptor.validate()?;
+        Ok(descriptor)
+    }
+
+    // 字段都是pub的，构造完还可能被改，解析前都要再查一遍
+    fn validate(&self) -> crate::Result<()> {
+        if self.magic.is_empty() {
+            return Err(BluetoothError::InvalidFrameDescriptor("magic is empty"));
+        }
+        // 超过usize的宽度时移位会溢出
+        if self.length_size == 0 || self.length_size > std::mem::size_of::<usize>() {
+            return Err(BluetoothError::InvalidFrameDescriptor(
+                "length field must be 1 byte up to the width of usize",
+            ));
+        }
+        if self.header_len < self.length_offset + self.length_size
+            || self.header_len < self.magic.len()
+        {
+            return Err(BluetoothError::InvalidFrameDescriptor(
+                "header is shorter than the magic or length field",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn endianness(mut self, endianness: Endianness) -> FrameDescriptor {
+        self.endianness = endianness;
+        self
+    }
+
+    pub fn header_len(mut self, header_len: usize) -> FrameDescriptor {
+        self.header_len = header_len;
+        self
+    }
+
+    pub fn checksum_len(mut self, checksum_len: usize) -> FrameDescriptor {
+        self.checksum_len = checksum_len;
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> FrameDescriptor {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// 从帧头里取出长度字段，超过`max_frame_size`时返回`FrameTooLarge`
+    pub fn body_len(&self, header: &[u8]) -> crate::Result<usize> {
+        self.validate()?;
+        let field = &header[self.length_offset..self.length_offset + self.length_size];
+
+        let declared = field.iter().enumerate().fold(0usize, |acc, (i, b)| {
+            let shift = match self.endianness {
+                Endianness::Big => (self.length_size - 1 - i) * 8,
+                Endianness::Little => i * 8,
+            };
+            acc | ((*b as usize) << shift)
+        });
+
+        if declared > self.max_frame_size {
+            return Err(BluetoothError::FrameTooLarge {
+                declared,
+                max: self.max_frame_size,
+            });
+        }
+
+        Ok(declared)
+    }
+
+    /// 尝试从`buf`开头切出一个完整的帧，魔数前面的垃圾会被丢掉；数据不够时返回`None`
+    pub fn decode(&self, buf: &mut Vec<u8>) -> crate::Result<Option<Vec<u8>>> {
+        self.validate()?;
+        match buf
+            .windows(self.magic.len())
+            .position(|window| window == self.magic.as_slice())
+        {
+            Some(start) => {
+                buf.drain(..start);
+            }
+            None => {
+                // 留下可能是半个魔数的尾巴
+                let keep = self.magic.len().saturating_sub(1).min(buf.len());
+                buf.drain(..buf.len() - keep);
+                return Ok(None);
+            }
+        }
+
+        if buf.len() < self.header_len {
+            return Ok(None);
+        }
+
+        let total = match self.body_len(&buf[..self.header_len]) {
+            Ok(len) => self.header_len + len + self.checksum_len,
+            Err(err) => {
+                // 跳过这个魔数，下次从后面重新找帧
+                buf.drain(..1);
+                return Err(err);
+            }
+        };
+
+        if buf.len() < total {
+            return Ok(None);
+        }
+
+        Ok(Some(buf.drain(..total).collect()))
+    }
+}
+
+/// 猜长度字段的字节序：`sample`是抓到的一个完整帧（前面可以有垃圾），分别按大端和小端解析长度，
+/// 看哪种正好等于魔数之后剩下的字节数（算上校验）。
+///
+/// `descriptor`里的`endianness`会被忽略。两种都对得上（比如长度是对称的）、都对不上，
+/// 或者`descriptor`本身不合法（比如魔数为空）时返回`None`。
+pub fn detect_endianness(sample: &[u8], descriptor: &FrameDescriptor) -> Option<Endianness> {
+    descriptor.validate().ok()?;
+    let start = sample
+        .windows(descriptor.magic.len())
+        .position(|window| window == descriptor.magic.as_slice())?;
+    let frame = &sample[start..];
+
+    if frame.len() < descriptor.header_len {
+        return None;
+    }
+
+    let consistent: Vec<Endianness> = [Endianness::Big, Endianness::Little]
+        .into_iter()
+        .filter(|endianness| {
+            let candidate = descriptor
+                .clone()
+                .endianness(*endianness)
+                .max_frame_size(usize::MAX);
+            // 不限长度时按错的字节序解出来可能接近usize::MAX，加起来会溢出
+            match candidate.body_len(&frame[..descriptor.header_len]) {
+                Ok(len) => {
+                    descriptor
+                        .header_len
+                        .checked_add(len)
+                        .and_then(|total| total.checked_add(descriptor.checksum_len))
+                        == Some(frame.len())
+                }
+                Err(_) => false,
+            }
+        })
+        .collect();
+
+    match consistent.as_slice() {
+        [endianness] => Some(*endianness),
+        _ => None,
+    }
+}
+
+/// 从`reader`里读一个完整的帧（头+数据+校验），先跳过魔数之前的字节。
+///
+/// 头读完就会检查长度字段，超过`max_frame_size`的帧不会分配数据缓冲区。
+pub async fn read_frame_with_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    descriptor: &FrameDescriptor,
+) -> crate::Result<Vec<u8>> {
+    read_frame_or_eof(reader, descriptor).await?.ok_or_else(|| {
+        BluetoothError::RuntimeError(
+            std::io::Error::from(std::io::ErrorKind::UnexpectedEof).to_string(),
+        )
+    })
+}
+
+/// 同`read_frame_with_header`，但魔数还没对上就读到EOF时返回`Ok(None)`，
+/// 帧读到一半才EOF的照样报错。
+pub async fn read_frame_or_eof<R: AsyncRead + Unpin + ?Sized>(
+    reader: &mut R,
+    descriptor: &FrameDescriptor,
+) -> crate::Result<Option<Vec<u8>>> {
+    let io_error = |err: std::io::Error| BluetoothError::RuntimeError(err.to_string());
+
+    // 一个字节一个字节地对魔数，对不上就往后滑
+    let mut header = vec![0; descriptor.header_len];
+    let magic_len = descriptor.magic.len();
+    let mut matched = 0;
+    while matched < magic_len {
+        let n = reader
+            .read(&mut header[matched..matched + 1])
+            .await
+            .map_err(io_error)?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        matched += 1;
+        while matched > 0 && header[..matched] != descriptor.magic[..matched] {
+            header.copy_within(1..matched, 0);
+            matched -= 1;
+        }
+    }
+
+    reader
+        .read_exact(&mut header[magic_len..])
+        .await
+        .map_err(io_error)?;
+
+    let len = descriptor.body_len(&header)?;
+
+    let mut frame = header;
+    frame.resize(descriptor.header_len + len + descriptor.checksum_len, 0);
+    reader
+        .read_exact(&mut frame[descriptor.header_len..])
+        .await
+        .map_err(io_error)?;
+
+    Ok(Some(frame))
+}
+
+/// 把一个字节流切成帧的`Stream`，流结束时返回`None`
+pub struct FrameStream<S> {
+    inner: S,
+    descriptor: FrameDescriptor,
+    buffer: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> FrameStream<S> {
+    pub fn new(inner: S, descriptor: FrameDescriptor) -> FrameStream<S> {
+        FrameStream {
+            inner,
+            descriptor,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> Stream for FrameStream<S> {
+    type Item = crate::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.descriptor.decode(&mut this.buffer) {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Err(err) => return Poll::Ready(Some(Err(err))),
+                Ok(None) => {}
+            }
+
+            let mut chunk = [0; 1024];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {
+                    if read_buf.filled().is_empty() {
+                        return Poll::Ready(None);
+                    }
+                    this.buffer.extend_from_slice(read_buf.filled());
+                }
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Some(Err(BluetoothError::RuntimeError(err.to_string()))));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -11,14 +11,78 @@ pub fn mac_u64_to_string(addr: u64) -> String {
     mac_parts.join(":")
 }
 
-pub fn mac_string_to_u64(addr: &String) -> Option<u64> {
-    let cleaned = addr.split(':').collect::<Vec<_>>().join("");
-    if cleaned.len() != 12 {
+/// 同`mac_u64_to_string`，但48位以上有值时返回`None`，不会把不是地址的数悄悄截断成一个假地址
+pub fn mac_u64_to_string_checked(addr: u64) -> Option<String> {
+    if addr >> 48 != 0 {
         return None;
     }
 
-    match u64::from_str_radix(&cleaned, 16) {
-        Ok(value) => return Some(value),
-        Err(_) => return None,
+    Some(mac_u64_to_string(addr))
+}
+
+/// MAC地址字符串解析失败的原因
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum MacParseError {
+    /// 去掉分隔符以后不是12个十六进制数字，带的是实际的个数
+    #[error("Expected 12 hex digits, got {}", _0)]
+    WrongLength(usize),
+
+    #[error("Invalid hex digit {:?}", _0)]
+    InvalidHexDigit(char),
+
+    #[error("Unexpected separator {:?}", _0)]
+    BadSeparator(char),
+}
+
+/// 解析MAC地址，大小写都行。分隔符可以是`:`（`AA:BB:CC:DD:EE:FF`）、`-`（`AA-BB-CC-DD-EE-FF`，
+/// 设备管理器里复制出来的）、`.`（`aabb.ccdd.eeff`），也可以是不带分隔符的12位十六进制。
+///
+/// 一个地址里只能用一种分隔符，混着用返回`BadSeparator`，带的是第二种分隔符。
+/// 分隔符的位置也要对：`:`和`-`分成6组、每组2位，`.`分成3组、每组4位，
+/// 像`AAB:BCCDDEEFF`这样位置不对的也返回`BadSeparator`。
+pub fn mac_string_to_u64(addr: &str) -> Result<u64, MacParseError> {
+    let mut digits = String::with_capacity(12);
+    let mut separator = None;
+
+    for c in addr.chars() {
+        match c {
+            ':' | '-' | '.' => match separator {
+                None => separator = Some(c),
+                Some(first) if first == c => {}
+                Some(_) => return Err(MacParseError::BadSeparator(c)),
+            },
+            c if c.is_ascii_hexdigit() => digits.push(c),
+            // 字母数字以外的字符当成用错了分隔符
+            c if c.is_alphanumeric() => return Err(MacParseError::InvalidHexDigit(c)),
+            c => return Err(MacParseError::BadSeparator(c)),
+        }
     }
+
+    if digits.len() != 12 {
+        return Err(MacParseError::WrongLength(digits.len()));
+    }
+
+    // 数字个数对了再看分组，免得`:AABBCCDDEEFF`这种也能过
+    if let Some(separator) = separator {
+        let (count, width) = match separator {
+            '.' => (3, 4),
+            _ => (6, 2),
+        };
+        let mut groups = addr.split(separator);
+        let layout_ok = groups.clone().count() == count && groups.all(|group| group.len() == width);
+        if !layout_ok {
+            return Err(MacParseError::BadSeparator(separator));
+        }
+    }
+
+    // 上面已经保证全是十六进制数字
+    u64::from_str_radix(&digits, 16).map_err(|_| MacParseError::WrongLength(digits.len()))
+}
+
+/// 把48位蓝牙地址拆成NAP（高16位）和SAP（低32位），BNEP/PAN的一些SDP、L2CAP负载里是这么分的。
+///
+/// 注意这和IEEE常说的OUI（高24位，厂商号）/NIC（低24位）不是一回事：两种拆法的分界线不同，
+/// 这里的SAP其实就是蓝牙规范里的UAP（8位）加LAP（24位）。
+pub fn nap_sap(addr: u64) -> (u16, u32) {
+    (((addr >> 32) & 0xFFFF) as u16, addr as u32)
 }
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time;
+
+use crate::{BluetoothError, BluetoothSppSession, TimeoutOp, common::device::BluetoothDevice};
+
+// 关闭单个会话最多等多久，超时就直接断开
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 按设备地址管理一组会话，网关这类同时连很多设备的场景用
+pub struct SessionManager<S: BluetoothSppSession> {
+    sessions: HashMap<BluetoothDevice, S>,
+    close_timeout: Duration,
+}
+
+impl<S: BluetoothSppSession> SessionManager<S> {
+    pub fn new() -> SessionManager<S> {
+        SessionManager {
+            sessions: HashMap::new(),
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+        }
+    }
+
+    /// `close_all`/`shutdown_all`里每个会话最多等多久
+    pub fn set_close_timeout(&mut self, timeout: Duration) {
+        self.close_timeout = timeout;
+    }
+
+    /// 按会话当前的设备放进来，同一台设备原来的会话会被换出来返回
+    pub fn insert(&mut self, session: S) -> Option<S> {
+        self.sessions.insert(session.device().clone(), session)
+    }
+
+    pub fn get(&self, device: &BluetoothDevice) -> Option<&S> {
+        self.sessions.get(device)
+    }
+
+    pub fn get_mut(&mut self, device: &BluetoothDevice) -> Option<&mut S> {
+        self.sessions.get_mut(device)
+    }
+
+    pub fn remove(&mut self, device: &BluetoothDevice) -> Option<S> {
+        self.sessions.remove(device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &S> {
+        self.sessions.values()
+    }
+
+    pub fn sessions_mut(&mut self) -> impl Iterator<Item = &mut S> {
+        self.sessions.values_mut()
+    }
+
+    /// 断开并移除空闲超过`threshold`的会话，返回被清掉的设备
+    pub fn reap_idle(&mut self, threshold: Duration) -> Vec<BluetoothDevice> {
+        let idle: Vec<BluetoothDevice> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.idle_since() > threshold)
+            .map(|(device, _)| device.clone())
+            .collect();
+
+        for device in &idle {
+            if let Some(mut session) = self.sessions.remove(device) {
+                session.disconnect();
+            }
+        }
+
+        idle
+    }
+
+    /// 挨个`close()`所有会话，每个最多等`close_timeout`，超时的直接断开。会话还留在管理器里。
+    ///
+    /// 有会话关闭失败时返回`CloseFailed`，里面是每个失败的设备和原因。
+    pub async fn close_all(&mut self) -> crate::Result<()> {
+        let mut failed = Vec::new();
+
+        for (device, session) in self.sessions.iter_mut() {
+            let result = match time::timeout(self.close_timeout, session.close()).await {
+                Ok(result) => result,
+                Err(_) => {
+                    session.disconnect();
+                    // close超时只可能是卡在等挂起的写
+                    Err(BluetoothError::TimedOut {
+                        duration: self.close_timeout,
+                        operation: TimeoutOp::Write,
+                    })
+                }
+            };
+
+            if let Err(err) = result {
+                failed.push((device.clone(), err));
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BluetoothError::CloseFailed(failed))
+        }
+    }
+
+    /// 程序退出前用：关闭所有会话然后全部移除，不留还挂着的WinRT操作
+    pub async fn shutdown_all(&mut self) -> crate::Result<()> {
+        let result = self.close_all().await;
+        self.sessions.clear();
+        result
+    }
+}
+
+impl<S: BluetoothSppSession> Default for SessionManager<S> {
+    fn default() -> SessionManager<S> {
+        SessionManager::new()
+    }
+}
@@ -1,2 +1,23 @@
+pub mod bridge;
+pub mod cancel;
+pub mod checksum;
 pub mod device;
+pub mod diagnostics;
+pub mod discovery;
+pub mod export;
+pub mod framing;
 pub mod mac;
+pub mod manager;
+pub mod oneshot;
+pub mod pairing;
+pub mod pool;
+pub mod profile;
+pub mod reconnect;
+pub(crate) mod rng;
+pub mod runtime;
+pub mod sdp;
+pub mod split;
+pub mod stats;
+pub mod status;
+pub mod text;
+pub mod uuid;
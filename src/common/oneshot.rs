@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use tokio::time;
+use uuid::Uuid;
+
+use crate::{BluetoothDevice, BluetoothError, BluetoothSppSession, TimeoutOp};
+
+/// 连上`device`的`uuid`服务，一问一答一次再关掉，返回回复。
+///
+/// Windows上用`WinrtSession`，Linux上用`BluezSession`。需要配对时用默认的`DefaultAgent`。
+/// `timeout`覆盖连接加一问一答的整个过程，卡在哪一步由`TimedOut`的`operation`区分。
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub async fn query(
+    device: &BluetoothDevice,
+    uuid: Uuid,
+    request: &[u8],
+    expected_len: usize,
+    timeout: Duration,
+) -> crate::Result<Vec<u8>> {
+    #[cfg(target_os = "windows")]
+    let mut session = crate::windows::session::WinrtSession::new();
+    #[cfg(target_os = "linux")]
+    let mut session = crate::linux::session::BluezSession::new();
+    query_with(&mut session, device, uuid, request, expected_len, timeout).await
+}
+
+/// 同`query`，但用调用方给的会话，结束后会话是关着的
+pub async fn query_with<S: BluetoothSppSession>(
+    session: &mut S,
+    device: &BluetoothDevice,
+    uuid: Uuid,
+    request: &[u8],
+    expected_len: usize,
+    timeout: Duration,
+) -> crate::Result<Vec<u8>> {
+    let started = Instant::now();
+
+    let connected =
+        match time::timeout(timeout, session.connect_by_uuid_async(device, uuid, true)).await {
+            Ok(result) => result,
+            Err(_) => Err(BluetoothError::TimedOut {
+                duration: timeout,
+                operation: TimeoutOp::Connect,
+            }),
+        };
+    // 连接失败也要断开，连到一半的状态不能留给调用方的会话
+    if let Err(err) = connected {
+        session.disconnect();
+        return Err(err);
+    }
+
+    let remaining = timeout.saturating_sub(started.elapsed());
+    let reply = session.transaction(request, expected_len, remaining).await;
+
+    // 一问一答失败时以它的错误为准，关闭的错误就不管了
+    let closed = session.close().await;
+    let reply = reply?;
+    closed?;
+
+    Ok(reply)
+}
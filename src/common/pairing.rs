@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use crate::common::device::BluetoothDevice;
+
+/// 配对流程里每一步的失败原因
+#[derive(Debug, thiserror::Error)]
+pub enum PairingError {
+    #[error("Device is not pairable")]
+    NotPairable,
+
+    #[error("Unpair failed: {}", _0)]
+    UnpairFailed(String),
+
+    #[error("Pair failed: {}", _0)]
+    PairFailed(String),
+
+    #[error("Pairing rejected by agent")]
+    Rejected,
+}
+
+/// 配对时负责和用户交互的一方，系统发起的每种配对请求都会转到这里。
+///
+/// 默认实现相当于只接受ConfirmOnly：确认都同意，不提供PIN。
+pub trait PairingAgent: Send + Sync {
+    /// 只需确认的配对，返回`false`拒绝
+    fn confirm(&self, _device: &BluetoothDevice) -> bool {
+        true
+    }
+
+    /// 需要输入PIN时调用，返回`None`拒绝
+    fn provide_pin(&self, _device: &BluetoothDevice) -> Option<String> {
+        None
+    }
+
+    /// 两边显示同一个PIN，需要用户确认是否一致
+    fn confirm_pin(&self, _device: &BluetoothDevice, _pin: &str) -> bool {
+        true
+    }
+
+    /// 需要把PIN显示给用户，让用户在对端输入
+    fn display_pin(&self, _device: &BluetoothDevice, _pin: &str) {}
+}
+
+/// 只处理直接就能配对的情况，`pair_handler`用的就是它
+pub struct DefaultAgent;
+
+impl PairingAgent for DefaultAgent {}
+
+/// 用闭包回答PIN请求的agent：`ProvidePin`时调用闭包拿PIN，其他请求和`DefaultAgent`一样
+pub struct PinAgent<F> {
+    provide: F,
+}
+
+impl<F> PinAgent<F>
+where
+    F: Fn(&BluetoothDevice) -> Option<String> + Send + Sync,
+{
+    pub fn new(provide: F) -> PinAgent<F> {
+        PinAgent { provide }
+    }
+}
+
+impl<F> PairingAgent for PinAgent<F>
+where
+    F: Fn(&BluetoothDevice) -> Option<String> + Send + Sync,
+{
+    fn provide_pin(&self, device: &BluetoothDevice) -> Option<String> {
+        (self.provide)(device)
+    }
+}
+
+/// 连接时要不要配对，配对请求交给谁处理。
+///
+/// connect系列的`pairing`参数都接受它，也接受`bool`：`true`等于`confirm_only()`，`false`等于`None`，
+/// 和以前的`need_pairing`一样。
+#[derive(Clone, Default)]
+pub enum PairingConfig {
+    /// 不配对，设备没配对时交给后面的服务查询去报错
+    #[default]
+    None,
+    /// 没配对时先配对，请求都交给这个agent
+    Agent(Arc<dyn PairingAgent>),
+}
+
+impl PairingConfig {
+    /// 只接受ConfirmOnly，要PIN的设备会配对失败
+    pub fn confirm_only() -> PairingConfig {
+        PairingConfig::Agent(Arc::new(DefaultAgent))
+    }
+
+    pub fn with_agent(agent: impl PairingAgent + 'static) -> PairingConfig {
+        PairingConfig::Agent(Arc::new(agent))
+    }
+
+    /// 设备要PIN时调用`provide`，返回`None`拒绝配对
+    pub fn with_pin(
+        provide: impl Fn(&BluetoothDevice) -> Option<String> + Send + Sync + 'static,
+    ) -> PairingConfig {
+        PairingConfig::with_agent(PinAgent::new(provide))
+    }
+
+    pub fn is_required(&self) -> bool {
+        matches!(self, PairingConfig::Agent(_))
+    }
+
+    pub fn agent(&self) -> Option<Arc<dyn PairingAgent>> {
+        match self {
+            PairingConfig::None => None,
+            PairingConfig::Agent(agent) => Some(agent.clone()),
+        }
+    }
+}
+
+impl From<bool> for PairingConfig {
+    fn from(need_pairing: bool) -> PairingConfig {
+        if need_pairing {
+            PairingConfig::confirm_only()
+        } else {
+            PairingConfig::None
+        }
+    }
+}
+
+impl std::fmt::Debug for PairingConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PairingConfig::None => f.write_str("None"),
+            PairingConfig::Agent(_) => f.write_str("Agent"),
+        }
+    }
+}
+
+// 平台相关的配对操作，抽出来是为了让重新配对的顺序能脱离系统测试
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) trait PairingBackend {
+    async fn is_paired(&mut self) -> Result<bool, PairingError>;
+    async fn unpair(&mut self) -> Result<(), PairingError>;
+    async fn pair(&mut self, agent: Arc<dyn PairingAgent>) -> Result<(), PairingError>;
+}
+
+// 先解除配对（如果已经配对了），再重新走一遍配对
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) async fn repair_with<B: PairingBackend>(
+    backend: &mut B,
+    agent: Arc<dyn PairingAgent>,
+) -> crate::Result<()> {
+    if backend.is_paired().await? {
+        backend.unpair().await?;
+    }
+
+    backend.pair(agent).await?;
+
+    Ok(())
+}
+
+// 连接时用：已经配对的直接跳过，不去注册handler；返回是否真的走了配对
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) async fn pair_if_needed<B: PairingBackend>(
+    backend: &mut B,
+    agent: Arc<dyn PairingAgent>,
+) -> Result<bool, PairingError> {
+    if backend.is_paired().await? {
+        return Ok(false);
+    }
+
+    backend.pair(agent).await?;
+
+    Ok(true)
+}
@@ -0,0 +1,42 @@
+/// 简单的对象池：用完的缓冲区放回来，下次优先拿旧的，最多留`limit`个
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) struct BufferPool<B> {
+    free: Vec<B>,
+    limit: usize,
+    created: usize,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+impl<B> BufferPool<B> {
+    pub(crate) fn new(limit: usize) -> BufferPool<B> {
+        BufferPool {
+            free: Vec::new(),
+            limit,
+            created: 0,
+        }
+    }
+
+    /// 池里有就直接拿，没有再用`create`新建
+    pub(crate) fn take<E>(&mut self, create: impl FnOnce() -> Result<B, E>) -> Result<B, E> {
+        match self.free.pop() {
+            Some(buffer) => Ok(buffer),
+            None => {
+                let buffer = create()?;
+                self.created += 1;
+                Ok(buffer)
+            }
+        }
+    }
+
+    /// 还回去。调用方要保证已经没有别的地方（比如还没完成的WinRT操作）在用它
+    pub(crate) fn put(&mut self, buffer: B) {
+        if self.free.len() < self.limit {
+            self.free.push(buffer);
+        }
+    }
+
+    /// 一共新建过多少个
+    pub(crate) fn created(&self) -> usize {
+        self.created
+    }
+}
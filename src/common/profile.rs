@@ -0,0 +1,44 @@
+use uuid::Uuid;
+
+use crate::common::device::BluetoothDevice;
+
+/// 一次成功连接的配置，存下来以后可以跳过服务查询直接重连
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionProfile {
+    #[cfg_attr(feature = "serde", serde(with = "device_string"))]
+    pub device: BluetoothDevice,
+    pub uuid: Uuid,
+    /// 连接时解析出来的服务名（WinRT里是`ConnectionServiceName`），没有的话重连要重新查服务
+    pub service_name: Option<String>,
+    /// 建立连接用的本地适配器地址
+    pub adapter: Option<String>,
+}
+
+impl ConnectionProfile {
+    pub fn new(device: BluetoothDevice, uuid: Uuid) -> ConnectionProfile {
+        ConnectionProfile {
+            device,
+            uuid,
+            service_name: None,
+            adapter: None,
+        }
+    }
+}
+
+// 设备存成"name@MAC"，和`BluetoothDevice::try_from(&str)`认的格式一样
+#[cfg(feature = "serde")]
+mod device_string {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    use crate::common::device::BluetoothDevice;
+
+    pub fn serialize<S: Serializer>(device: &BluetoothDevice, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format!("{}@{}", device.name, device.addr_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<BluetoothDevice, D::Error> {
+        let value = String::deserialize(d)?;
+        BluetoothDevice::try_from(value.as_str()).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,222 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+use uuid::Uuid;
+
+use crate::{
+    BluetoothError, BluetoothSppSession, SessionState,
+    common::{device::BluetoothDevice, pairing::PairingConfig, rng::XorShift},
+    from_io_error,
+};
+
+/// 默认的重连判断：断线、超时这类临时问题重连，权限、配对、找不到设备这类重连也没用的不重连
+pub fn default_should_reconnect(err: &BluetoothError) -> bool {
+    matches!(
+        err,
+        BluetoothError::NotConnected
+            | BluetoothError::TimedOut { .. }
+            | BluetoothError::PartialRead { .. }
+            | BluetoothError::ConnectionRefused(_)
+            | BluetoothError::RuntimeError(_)
+    )
+}
+
+/// 读写出错时自动重连的会话包装。
+///
+/// 出错后先用`should_reconnect`判断值不值得重连，值得的话最多重连`max_attempts`次，
+/// 每次间隔`retry_delay`（可以加随机抖动，见`with_jitter`），重连成功后把失败的那次读写再做一遍。
+pub struct ReconnectingSession<S: BluetoothSppSession> {
+    session: S,
+    device: BluetoothDevice,
+    uuid: Uuid,
+    pairing: PairingConfig,
+    max_attempts: u32,
+    retry_delay: Duration,
+    jitter: Jitter,
+    should_reconnect: fn(&BluetoothError) -> bool,
+    // recover还没结束，包括两次重连之间的等待
+    reconnecting: bool,
+}
+
+impl<S: BluetoothSppSession> ReconnectingSession<S> {
+    pub fn new(
+        session: S,
+        device: BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> Self {
+        ReconnectingSession {
+            session,
+            device: device.clone(),
+            uuid,
+            pairing: pairing.into(),
+            max_attempts: 3,
+            retry_delay: Duration::from_secs(1),
+            jitter: Jitter::new(0.0, jitter_seed(&device)),
+            should_reconnect: default_should_reconnect,
+            reconnecting: false,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// 每次重连前的等待在`retry_delay * (1 ± fraction)`里随机取，`fraction`限制在0到1之间，默认0不抖动。
+    ///
+    /// 一堆会话同时断线（比如射频重置）时，大家错开重连，不会挤在同一时刻。
+    /// 每个会话有自己的随机数，种子默认由设备地址和当前时间决定。
+    pub fn with_jitter(mut self, fraction: f64) -> Self {
+        self.jitter.fraction = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// 固定抖动的随机种子，方便复现
+    pub fn with_jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter.rng = XorShift::new(seed);
+        self
+    }
+
+    /// 换掉默认的重连判断，返回`false`的错误会直接交给调用方
+    pub fn with_should_reconnect(mut self, should_reconnect: fn(&BluetoothError) -> bool) -> Self {
+        self.should_reconnect = should_reconnect;
+        self
+    }
+
+    pub fn session(&self) -> &S {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut S {
+        &mut self.session
+    }
+
+    pub fn into_inner(self) -> S {
+        self.session
+    }
+
+    /// 连接状态。重连还没结束时（包括两次重连之间的等待，以及重连被外面的超时打断以后）
+    /// 是`Reconnecting`，其他时候就是里面会话的状态
+    pub fn state(&self) -> SessionState {
+        if self.reconnecting {
+            SessionState::Reconnecting
+        } else {
+            self.session.state()
+        }
+    }
+
+    pub async fn connect(&mut self) -> crate::Result<()> {
+        self.session
+            .connect_by_uuid_async(&self.device, self.uuid, self.pairing.clone())
+            .await
+    }
+
+    /// 按`err`决定要不要重连。不该重连的错误原样返回；重连全部失败时返回最后一次的错误。
+    pub async fn recover(&mut self, err: BluetoothError) -> crate::Result<()> {
+        if !(self.should_reconnect)(&err) {
+            return Err(err);
+        }
+
+        self.reconnecting = true;
+        let mut last = err;
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                time::sleep(self.jitter.apply(self.retry_delay)).await;
+            }
+
+            self.session.disconnect();
+            match self.connect().await {
+                Ok(()) => {
+                    self.reconnecting = false;
+                    return Ok(());
+                }
+                Err(err) => last = err,
+            }
+        }
+
+        self.reconnecting = false;
+        Err(last)
+    }
+
+    /// 读一次，断线的话重连后再读一次
+    pub async fn read(&mut self, buf: &mut [u8]) -> crate::Result<usize> {
+        match read_once(&mut self.session, buf).await {
+            Err(err) => {
+                self.recover(err).await?;
+                read_once(&mut self.session, buf).await
+            }
+            result => result,
+        }
+    }
+
+    /// 写完整个`data`，断线的话重连后整个重写
+    pub async fn write_all(&mut self, data: &[u8]) -> crate::Result<()> {
+        match write_once(&mut self.session, data).await {
+            Err(err) => {
+                self.recover(err).await?;
+                write_once(&mut self.session, data).await
+            }
+            result => result,
+        }
+    }
+}
+
+// 重连间隔的随机抖动
+pub(crate) struct Jitter {
+    fraction: f64,
+    rng: XorShift,
+}
+
+impl Jitter {
+    pub(crate) fn new(fraction: f64, seed: u64) -> Jitter {
+        Jitter {
+            fraction: fraction.clamp(0.0, 1.0),
+            rng: XorShift::new(seed),
+        }
+    }
+
+    pub(crate) fn apply(&mut self, delay: Duration) -> Duration {
+        if self.fraction == 0.0 {
+            return delay;
+        }
+
+        // [-fraction, fraction)
+        let offset = (self.rng.next_f64() * 2.0 - 1.0) * self.fraction;
+        delay.mul_f64(1.0 + offset)
+    }
+}
+
+// 同时断线的会话时间差不多，混上地址才能各不相同
+fn jitter_seed(device: &BluetoothDevice) -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    now ^ device.addr().rotate_left(17)
+}
+
+async fn read_once<S: BluetoothSppSession>(
+    session: &mut S,
+    buf: &mut [u8],
+) -> crate::Result<usize> {
+    match session.read(buf).await {
+        // 对端关了连接
+        Ok(0) if !buf.is_empty() => Err(BluetoothError::NotConnected),
+        Ok(n) => Ok(n),
+        Err(err) => Err(from_io_error(err)),
+    }
+}
+
+async fn write_once<S: BluetoothSppSession>(session: &mut S, data: &[u8]) -> crate::Result<()> {
+    session.write_all(data).await.map_err(from_io_error)?;
+    session.flush().await.map_err(from_io_error)
+}
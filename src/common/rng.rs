@@ -0,0 +1,24 @@
+// 不想为了这点随机数引入rand，xorshift64*够用了。同一个种子出来的序列总是一样的
+pub(crate) struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> XorShift {
+        // xorshift的状态不能是0
+        XorShift { state: seed.max(1) }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// [0, 1)上的均匀分布
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        // 取高53位
+        (self.next_u64() >> 11) as f64 / ((1u64 << 53) as f64)
+    }
+}
@@ -0,0 +1,116 @@
+use std::{future::Future, sync::OnceLock, thread};
+
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+
+use crate::BluetoothError;
+
+/// 同步版connect（`connect`、`connect_timeout`这些）在哪种COM套间里跑。
+///
+/// 同步connect会把整个连接和配对流程放到一个专门的工作线程上，线程先按这里的设置进入COM套间
+/// （`RoInitialize`），跑完就退出，调用线程原来的套间不受影响。只有Windows上有套间，
+/// 别的平台上两种设置只差在用哪种runtime。
+///
+/// 默认的MTA大多数情况下就够了。有些系统上的蓝牙驱动或配对组件只能在STA里调用，
+/// 在MTA里连接或配对会报`RPC_E_WRONG_THREAD`（0x8001010E）之类的套间错误，这时用`SingleThread`。
+/// 连上以后的读写不在这个线程上，不受这个设置影响。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectAffinity {
+    /// 工作线程进入MTA，用多线程runtime，原来的行为
+    #[default]
+    MultiThread,
+    /// 工作线程进入STA，用单线程runtime，整个连接过程都留在这一个线程上
+    SingleThread,
+}
+
+pub(crate) fn build_runtime(affinity: ConnectAffinity) -> std::io::Result<Runtime> {
+    match affinity {
+        ConnectAffinity::MultiThread => Builder::new_multi_thread().enable_all().build(),
+        ConnectAffinity::SingleThread => Builder::new_current_thread().enable_all().build(),
+    }
+}
+
+// 多线程runtime整个进程共用一个，第一次用到时才建
+static SHARED_RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fn runtime_error(err: std::io::Error) -> BluetoothError {
+    BluetoothError::RuntimeError(err.to_string())
+}
+
+fn shared_runtime() -> crate::Result<&'static Runtime> {
+    if let Some(runtime) = SHARED_RUNTIME.get() {
+        return Ok(runtime);
+    }
+
+    let runtime = build_runtime(ConnectAffinity::MultiThread).map_err(runtime_error)?;
+    // 并发初始化时别的线程可能先放进去了，用先放进去的那个
+    Ok(SHARED_RUNTIME.get_or_init(|| runtime))
+}
+
+/// 同步版connect里跑异步流程，放在专门的工作线程上，线程的COM套间见`ConnectAffinity`。
+///
+/// 已经在多线程tokio runtime里时借用当前runtime（`block_in_place`），不会因为嵌套runtime而panic；
+/// 在单线程runtime里没法阻塞等待，返回`RuntimeError`，这时应该用`connect_async`。
+pub(crate) fn block_on<F>(affinity: ConnectAffinity, future: F) -> crate::Result<F::Output>
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    let handle = match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() != RuntimeFlavor::MultiThread => {
+            return Err(BluetoothError::RuntimeError(
+                "blocking connect called inside a current-thread runtime, use connect_async"
+                    .to_string(),
+            ));
+        }
+        Ok(handle) => Some(handle),
+        Err(_) => None,
+    };
+    let in_runtime = handle.is_some();
+
+    // 调用方在等，future借用的东西在线程结束前都有效
+    let run = move || {
+        thread::scope(|scope| {
+            let worker = thread::Builder::new()
+                .name("bluetooth-connect".to_string())
+                .spawn_scoped(scope, move || run_on_worker(affinity, handle, future))
+                .map_err(runtime_error)?;
+            worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    };
+
+    if in_runtime {
+        tokio::task::block_in_place(run)
+    } else {
+        run()
+    }
+}
+
+fn run_on_worker<F: Future>(
+    affinity: ConnectAffinity,
+    handle: Option<Handle>,
+    future: F,
+) -> crate::Result<F::Output> {
+    #[cfg(target_os = "windows")]
+    let _apartment = crate::windows::utils::Apartment::enter(affinity)?;
+
+    match (affinity, handle) {
+        // 工作线程不在runtime里，可以直接借调用方的runtime等
+        (ConnectAffinity::MultiThread, Some(handle)) => Ok(handle.block_on(future)),
+        (ConnectAffinity::MultiThread, None) => Ok(shared_runtime()?.block_on(future)),
+        // 定时器也要留在这个线程上，每次建一个单线程runtime。这个runtime跑完就没了，
+        // 会话不能把连好的socket留在它的reactor上，要等读写时在调用方的runtime里注册
+        (ConnectAffinity::SingleThread, _) => Ok(build_runtime(affinity)
+            .map_err(runtime_error)?
+            .block_on(future)),
+    }
+}
+
+/// 当前线程是不是在tokio runtime里。
+///
+/// 同步版connect在多线程runtime里可以调，在单线程runtime里会返回错误，
+/// 包装这个库的代码可以据此选`connect_async`还是`connect`。
+pub fn in_async_context() -> bool {
+    Handle::try_current().is_ok()
+}
@@ -0,0 +1,305 @@
+use uuid::Uuid;
+
+use crate::common::uuid::from_short;
+
+/// ServiceName属性（主语言下的服务名）
+pub const SERVICE_NAME_ATTRIBUTE_ID: u32 = 0x0100;
+
+/// ServiceDescription属性
+pub const SERVICE_DESCRIPTION_ATTRIBUTE_ID: u32 = 0x0101;
+
+/// ProviderName属性
+pub const PROVIDER_NAME_ATTRIBUTE_ID: u32 = 0x0102;
+
+/// SDP数据元素，只覆盖服务记录里常用的几种类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum SdpValue {
+    Uint8(u8),
+    Uint16(u16),
+    Uint32(u32),
+    Uuid(Uuid),
+    Text(String),
+    Bool(bool),
+    Sequence(Vec<SdpValue>),
+}
+
+impl SdpValue {
+    /// 按SDP数据元素格式编码：1字节头（类型<<3 | 长度索引），必要时跟长度，再跟数据
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            SdpValue::Uint8(value) => vec![0x08, *value],
+            SdpValue::Uint16(value) => [&[0x09][..], &value.to_be_bytes()].concat(),
+            SdpValue::Uint32(value) => [&[0x0A][..], &value.to_be_bytes()].concat(),
+            SdpValue::Uuid(value) => [&[0x1C][..], value.as_bytes()].concat(),
+            SdpValue::Text(value) => encode_variable(4, value.as_bytes()),
+            SdpValue::Bool(value) => vec![0x28, *value as u8],
+            SdpValue::Sequence(values) => {
+                let body: Vec<u8> = values.iter().flat_map(|value| value.encode()).collect();
+                encode_variable(6, &body)
+            }
+        }
+    }
+}
+
+impl SdpValue {
+    /// 从数据元素的开头解析一个值，返回值和它占的字节数；不认识的类型或数据不完整返回`None`。
+    ///
+    /// 16位和32位的短UUID会按蓝牙基础UUID展开成完整的UUID。
+    pub fn decode(data: &[u8]) -> Option<(SdpValue, usize)> {
+        let header = *data.first()?;
+        let kind = header >> 3;
+        let size_index = header & 0x07;
+
+        // 先算出数据部分的位置和长度
+        let (offset, len) = match size_index {
+            0 => (1, if kind == 0 { 0 } else { 1 }),
+            1 => (1, 2),
+            2 => (1, 4),
+            3 => (1, 8),
+            4 => (1, 16),
+            5 => (2, *data.get(1)? as usize),
+            6 => (
+                3,
+                u16::from_be_bytes(data.get(1..3)?.try_into().ok()?) as usize,
+            ),
+            _ => (
+                5,
+                u32::from_be_bytes(data.get(1..5)?.try_into().ok()?) as usize,
+            ),
+        };
+        let body = data.get(offset..offset + len)?;
+
+        let value = match (kind, len) {
+            (1, 1) => SdpValue::Uint8(body[0]),
+            (1, 2) => SdpValue::Uint16(u16::from_be_bytes(body.try_into().ok()?)),
+            (1, 4) => SdpValue::Uint32(u32::from_be_bytes(body.try_into().ok()?)),
+            (3, 2) => SdpValue::Uuid(from_short(u16::from_be_bytes(body.try_into().ok()?) as u32)),
+            (3, 4) => SdpValue::Uuid(from_short(u32::from_be_bytes(body.try_into().ok()?))),
+            (3, 16) => SdpValue::Uuid(Uuid::from_slice(body).ok()?),
+            (4, _) => SdpValue::Text(String::from_utf8_lossy(body).into_owned()),
+            (5, 1) => SdpValue::Bool(body[0] != 0),
+            (6, _) => {
+                let mut values = Vec::new();
+                let mut rest = body;
+                while !rest.is_empty() {
+                    let (value, used) = SdpValue::decode(rest)?;
+                    values.push(value);
+                    rest = &rest[used..];
+                }
+                SdpValue::Sequence(values)
+            }
+            _ => return None,
+        };
+
+        Some((value, offset + len))
+    }
+}
+
+/// 解析ServiceName这类文本属性的原始数据，不是文本时返回`None`。
+///
+/// 很多设备的服务名后面带着`\0`，这里一起去掉。
+pub fn decode_text_attribute(raw: &[u8]) -> Option<String> {
+    match SdpValue::decode(raw)? {
+        (SdpValue::Text(text), _) => Some(text.trim_end_matches('\0').to_string()),
+        _ => None,
+    }
+}
+
+// 在一组(服务, 服务名)里挑出名字完全一致的第一个
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) fn select_service_by_name<T>(
+    services: impl IntoIterator<Item = (T, Option<String>)>,
+    name: &str,
+) -> Option<T> {
+    services
+        .into_iter()
+        .find(|(_, service_name)| service_name.as_deref() == Some(name))
+        .map(|(service, _)| service)
+}
+
+/// ProtocolDescriptorList属性，RFCOMM通道号在这里面
+pub const PROTOCOL_DESCRIPTOR_LIST_ATTRIBUTE_ID: u16 = 0x0004;
+
+// 协议UUID的短值
+const RFCOMM_PROTOCOL: u32 = 0x0003;
+
+// SDP的PDU编号
+const SERVICE_SEARCH_ATTRIBUTE_REQUEST: u8 = 0x06;
+const SERVICE_SEARCH_ATTRIBUTE_RESPONSE: u8 = 0x07;
+
+// 按UUID查服务、只要ProtocolDescriptorList的ServiceSearchAttributeRequest。
+// 回应太长时对端会给一个续传状态，原样放进`continuation`再发一次
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn service_search_attribute_request(
+    transaction: u16,
+    uuid: Uuid,
+    continuation: &[u8],
+) -> Vec<u8> {
+    let mut params = SdpValue::Sequence(vec![SdpValue::Uuid(uuid)]).encode();
+    // MaximumAttributeByteCount，多了对端会自己分段
+    params.extend_from_slice(&u16::MAX.to_be_bytes());
+    params.extend(
+        SdpValue::Sequence(vec![SdpValue::Uint16(
+            PROTOCOL_DESCRIPTOR_LIST_ATTRIBUTE_ID,
+        )])
+        .encode(),
+    );
+    params.push(continuation.len() as u8);
+    params.extend_from_slice(continuation);
+
+    let mut pdu = vec![SERVICE_SEARCH_ATTRIBUTE_REQUEST];
+    pdu.extend_from_slice(&transaction.to_be_bytes());
+    pdu.extend_from_slice(&(params.len() as u16).to_be_bytes());
+    pdu.extend(params);
+    pdu
+}
+
+// 解析ServiceSearchAttributeResponse，返回这一段属性列表的原始数据和续传状态（空的表示结束）。
+// 错误回应、编号对不上或者数据不完整都返回None
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn parse_service_search_attribute_response(
+    transaction: u16,
+    pdu: &[u8],
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    if *pdu.first()? != SERVICE_SEARCH_ATTRIBUTE_RESPONSE
+        || pdu.get(1..3)? != transaction.to_be_bytes()
+    {
+        return None;
+    }
+
+    let params = pdu.get(5..)?;
+    let count = u16::from_be_bytes(params.get(0..2)?.try_into().ok()?) as usize;
+    let lists = params.get(2..2 + count)?;
+    let continuation_len = *params.get(2 + count)? as usize;
+    let continuation = params.get(3 + count..3 + count + continuation_len)?;
+
+    Some((lists.to_vec(), continuation.to_vec()))
+}
+
+// 从拼好的属性列表里找出所有服务的RFCOMM通道号，按服务出现的顺序
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn rfcomm_channels(attribute_lists: &[u8]) -> Vec<u8> {
+    let Some((SdpValue::Sequence(records), _)) = SdpValue::decode(attribute_lists) else {
+        return Vec::new();
+    };
+
+    let mut channels = Vec::new();
+    for record in records {
+        let SdpValue::Sequence(attributes) = record else {
+            continue;
+        };
+
+        // 属性是(id, 值)交替排的
+        for pair in attributes.chunks(2) {
+            let [
+                SdpValue::Uint16(PROTOCOL_DESCRIPTOR_LIST_ATTRIBUTE_ID),
+                SdpValue::Sequence(protocols),
+            ] = pair
+            else {
+                continue;
+            };
+
+            let channel = protocols.iter().find_map(|protocol| match protocol {
+                SdpValue::Sequence(fields) => match fields.as_slice() {
+                    [SdpValue::Uuid(uuid), SdpValue::Uint8(channel), ..]
+                        if *uuid == from_short(RFCOMM_PROTOCOL) =>
+                    {
+                        Some(*channel)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            });
+            channels.extend(channel);
+        }
+    }
+
+    channels
+}
+
+// 变长类型按长度选8/16/32位的长度字段
+fn encode_variable(kind: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5);
+
+    if data.len() <= u8::MAX as usize {
+        out.push((kind << 3) | 5);
+        out.push(data.len() as u8);
+    } else if data.len() <= u16::MAX as usize {
+        out.push((kind << 3) | 6);
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    } else {
+        out.push((kind << 3) | 7);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    }
+
+    out.extend_from_slice(data);
+    out
+}
+
+/// 设备上的一个RFCOMM服务，`name`是SDP里的ServiceName，设备没发布或者读不到时是`None`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInfo {
+    pub uuid: Uuid,
+    pub name: Option<String>,
+}
+
+impl std::fmt::Display for ServiceInfo {
+    /// `SPP Dev (00001101-0000-1000-8000-00805f9b34fb)`，没有名字时只有UUID
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.uuid),
+            None => write!(f, "{}", self.uuid),
+        }
+    }
+}
+
+/// 作为服务端时要发布的服务记录
+#[derive(Debug, Clone)]
+pub struct ServiceRecord {
+    pub uuid: Uuid,
+    pub service_name: Option<String>,
+    pub attributes: Vec<(u32, SdpValue)>,
+}
+
+impl ServiceRecord {
+    pub fn new(uuid: Uuid) -> ServiceRecord {
+        ServiceRecord {
+            uuid,
+            service_name: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// 设置对外显示的服务名，客户端扫描服务时能看到
+    pub fn service_name(mut self, name: &str) -> ServiceRecord {
+        self.service_name = Some(name.to_string());
+        self
+    }
+
+    /// 追加任意SDP属性，同一个id后加的会覆盖先加的
+    pub fn attribute(mut self, id: u32, value: SdpValue) -> ServiceRecord {
+        self.attributes.retain(|(existing, _)| *existing != id);
+        self.attributes.push((id, value));
+        self
+    }
+
+    /// 生成`属性id -> 编码后数据元素`的列表，可以直接塞进`SdpRawAttributes`
+    pub fn raw_attributes(&self) -> Vec<(u32, Vec<u8>)> {
+        let mut raw = Vec::new();
+
+        if let Some(name) = &self.service_name {
+            raw.push((
+                SERVICE_NAME_ATTRIBUTE_ID,
+                SdpValue::Text(name.clone()).encode(),
+            ));
+        }
+
+        for (id, value) in &self.attributes {
+            // 显式给了ServiceName属性时以它为准
+            raw.retain(|(existing, _)| existing != id);
+            raw.push((*id, value.encode()));
+        }
+
+        raw
+    }
+}
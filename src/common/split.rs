@@ -0,0 +1,67 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+
+/// 会话拆出来的读的一半，可以和`SppWriteHalf`放在两个任务里同时用
+pub struct SppReadHalf<S> {
+    inner: ReadHalf<S>,
+}
+
+/// 会话拆出来的写的一半
+pub struct SppWriteHalf<S> {
+    inner: WriteHalf<S>,
+}
+
+/// 把会话拆成读写两半，用法同`TcpStream::into_split`。
+///
+/// 两半共用同一个会话，每次poll只短暂锁一下，挂起的读不会挡住写。
+/// WinRT的输入输出流本来就是分开的，读写同时挂着没问题。用完可以`unsplit`拿回会话。
+pub fn into_split<S: AsyncRead + AsyncWrite>(session: S) -> (SppReadHalf<S>, SppWriteHalf<S>) {
+    let (read, write) = tokio::io::split(session);
+    (SppReadHalf { inner: read }, SppWriteHalf { inner: write })
+}
+
+impl<S> SppReadHalf<S> {
+    /// 和拆出来的另一半合回原来的会话，不是同一个会话拆出来的会panic
+    pub fn unsplit(self, write: SppWriteHalf<S>) -> S
+    where
+        S: Unpin,
+    {
+        self.inner.unsplit(write.inner)
+    }
+
+    pub fn is_pair_of(&self, write: &SppWriteHalf<S>) -> bool {
+        self.inner.is_pair_of(&write.inner)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for SppReadHalf<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for SppWriteHalf<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
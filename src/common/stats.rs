@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+/// 会话的读写统计，只记成功收发的数据
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    created: Instant,
+    bytes_read: u64,
+    bytes_written: u64,
+    last_read: Option<Instant>,
+    last_write: Option<Instant>,
+    write_latency: LatencyStats,
+    pending_writes: usize,
+}
+
+impl SessionStats {
+    pub fn new() -> SessionStats {
+        SessionStats {
+            created: Instant::now(),
+            bytes_read: 0,
+            bytes_written: 0,
+            last_read: None,
+            last_write: None,
+            write_latency: LatencyStats::default(),
+            pending_writes: 0,
+        }
+    }
+
+    // 没传输字节的读写（比如读到EOF）不算数
+    pub(crate) fn record_read(&mut self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.bytes_read += len as u64;
+        self.last_read = Some(Instant::now());
+    }
+
+    pub(crate) fn record_write(&mut self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.bytes_written += len as u64;
+        self.last_write = Some(Instant::now());
+    }
+
+    // 一次写从发起到完成用了多久
+    pub(crate) fn record_write_latency(&mut self, latency: Duration) {
+        self.write_latency.record(latency);
+    }
+
+    // 已经收下但还没写完的写有几个
+    pub(crate) fn set_pending_writes(&mut self, depth: usize) {
+        self.pending_writes = depth;
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn last_read(&self) -> Option<Instant> {
+        self.last_read
+    }
+
+    pub fn last_write(&self) -> Option<Instant> {
+        self.last_write
+    }
+
+    /// 最后一次真正收发了数据的时间，一次都没有就是`None`
+    pub fn last_progress(&self) -> Option<Instant> {
+        self.last_read.max(self.last_write)
+    }
+
+    /// 最后一次成功读写的时间，一次都没有时算会话创建的时间
+    pub fn last_activity(&self) -> Instant {
+        self.last_progress().unwrap_or(self.created)
+    }
+
+    /// 写操作从发起到完成的耗时统计
+    pub fn write_latency(&self) -> &LatencyStats {
+        &self.write_latency
+    }
+
+    /// 当前排着队还没写完的写有几个。`WinrtSession`同一时间只有一个写在跑，只会是0或1
+    pub fn pending_writes(&self) -> usize {
+        self.pending_writes
+    }
+
+    /// 距离最后一次成功读写过了多久
+    pub fn idle_since(&self) -> Duration {
+        self.last_activity().elapsed()
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> SessionStats {
+        SessionStats::new()
+    }
+}
+
+/// 一组耗时的最小、最大和平均值，链路拥塞时写耗时会先涨上去
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    count: u64,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+}
+
+impl LatencyStats {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// 一次都没记录过时是`None`，下同
+    pub fn min(&self) -> Option<Duration> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.total.div_f64(self.count as f64))
+    }
+}
@@ -0,0 +1,61 @@
+use std::{future::Future, time::Duration};
+
+use tokio::{sync::mpsc, time};
+
+use crate::BluetoothError;
+
+/// 系统报上来的链路状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Disconnected,
+    Connected,
+}
+
+// 连上以后等`wait`再用`probe`看一眼链路，有些设备接受连接后几毫秒就断开，
+// 这时返回`ConnectionRefused`，而不是等第一次读写才报错
+pub(crate) async fn validate_link(
+    wait: Duration,
+    probe: impl Future<Output = crate::Result<ConnectionStatus>>,
+) -> crate::Result<()> {
+    time::sleep(wait).await;
+
+    match probe.await? {
+        ConnectionStatus::Connected => Ok(()),
+        ConnectionStatus::Disconnected => Err(BluetoothError::ConnectionRefused(
+            "peer dropped the connection right after connect".to_string(),
+        )),
+    }
+}
+
+// 系统的状态回调往这里推，会话按顺序一个个取。没人取的变化会一直留着，
+// 所以取的时候已经有变化了就立刻返回
+pub(crate) struct StatusChanges {
+    sender: mpsc::UnboundedSender<ConnectionStatus>,
+    receiver: mpsc::UnboundedReceiver<ConnectionStatus>,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+impl StatusChanges {
+    pub(crate) fn new() -> StatusChanges {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        StatusChanges { sender, receiver }
+    }
+
+    // 给回调用的发送端
+    pub(crate) fn sender(&self) -> mpsc::UnboundedSender<ConnectionStatus> {
+        self.sender.clone()
+    }
+
+    // 丢掉还没取的变化，换了连接以后旧设备的变化就没意义了
+    pub(crate) fn clear(&mut self) {
+        while self.receiver.try_recv().is_ok() {}
+    }
+
+    pub(crate) async fn next(&mut self) -> crate::Result<ConnectionStatus> {
+        // 自己拿着一个发送端，正常不会关掉
+        self.receiver
+            .recv()
+            .await
+            .ok_or(BluetoothError::NotConnected)
+    }
+}
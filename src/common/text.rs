@@ -0,0 +1,98 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{BluetoothError, BluetoothSppSession};
+
+const TEXT_READ_CHUNK: usize = 256;
+
+/// 读到的行不是合法UTF-8时怎么办
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// 返回`InvalidUtf8`，里面是原始字节
+    #[default]
+    Strict,
+    /// 非法的部分换成`U+FFFD`
+    Lossy,
+}
+
+/// 按行收发文本的会话包装，AT指令、NMEA这类文本协议用。
+///
+/// 行以`\n`结尾，返回的行去掉了结尾的`\n`和`\r\n`。一次读多出来的数据留在包装里，
+/// 所以用了`read_line`以后就别再直接读里面的会话了。
+pub struct TextSession<S: BluetoothSppSession> {
+    session: S,
+    policy: Utf8Policy,
+    pending: Vec<u8>,
+}
+
+impl<S: BluetoothSppSession> TextSession<S> {
+    pub fn new(session: S) -> Self {
+        TextSession {
+            session,
+            policy: Utf8Policy::default(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn with_utf8_policy(mut self, policy: Utf8Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn session(&self) -> &S {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut S {
+        &mut self.session
+    }
+
+    /// 拿回里面的会话，已经读进来但还没组成行的数据会被丢掉
+    pub fn into_inner(self) -> S {
+        self.session
+    }
+
+    /// 读一行。连接在行中间断开时返回`NotConnected`，读到一半的数据留着，重连后接着拼。
+    pub async fn read_line(&mut self) -> crate::Result<String> {
+        let mut chunk = [0u8; TEXT_READ_CHUNK];
+        let mut searched = 0;
+
+        loop {
+            if let Some(pos) = self.pending[searched..].iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.pending.drain(..=searched + pos).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return self.decode(line);
+            }
+            searched = self.pending.len();
+
+            let n = self
+                .session
+                .read(&mut chunk)
+                .await
+                .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+            if n == 0 {
+                return Err(BluetoothError::NotConnected);
+            }
+            self.pending.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// 原样写出`s`，不会自动加换行
+    pub async fn write_str(&mut self, s: &str) -> crate::Result<()> {
+        self.session
+            .write_all(s.as_bytes())
+            .await
+            .map_err(|err| BluetoothError::RuntimeError(err.to_string()))
+    }
+
+    fn decode(&self, line: Vec<u8>) -> crate::Result<String> {
+        match self.policy {
+            Utf8Policy::Strict => {
+                String::from_utf8(line).map_err(|err| BluetoothError::InvalidUtf8(err.into_bytes()))
+            }
+            Utf8Policy::Lossy => Ok(String::from_utf8_lossy(&line).into_owned()),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use uuid::{Uuid, uuid};
+
+use crate::BluetoothError;
+
+/// 蓝牙基础UUID，16位/32位短UUID都是在它上面展开的
+pub static BLUETOOTH_BASE_UUID: Uuid = uuid!("00000000-0000-1000-8000-00805F9B34FB");
+
+/// 如果是基于蓝牙基础UUID的短UUID，返回它的短值
+pub fn short_uuid(uuid: Uuid) -> Option<u32> {
+    let value = uuid.as_u128();
+    let base = BLUETOOTH_BASE_UUID.as_u128();
+    let mask = (1u128 << 96) - 1;
+
+    if value & mask == base & mask {
+        Some((value >> 96) as u32)
+    } else {
+        None
+    }
+}
+
+/// 把16位/32位短UUID展开成完整的UUID
+pub fn from_short(short: u32) -> Uuid {
+    Uuid::from_u128(BLUETOOTH_BASE_UUID.as_u128() | ((short as u128) << 96))
+}
+
+/// 解析配置里写的UUID，支持：
+///
+/// - 完整UUID，带不带横线、带不带`{}`、大小写都行，例如`{00001101-0000-1000-8000-00805F9B34FB}`
+/// - 16位/32位短UUID的十六进制，可以带`0x`，例如`1101`、`0x1101`，按蓝牙基础UUID展开
+///
+/// 其他格式返回`InvalidUuid`。
+pub fn parse_uuid(s: &str) -> crate::Result<Uuid> {
+    let trimmed = s.trim();
+    let short = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+
+    if matches!(short.len(), 4 | 8) && short.chars().all(|c| c.is_ascii_hexdigit()) {
+        return u32::from_str_radix(short, 16)
+            .map(from_short)
+            .map_err(|_| BluetoothError::InvalidUuid(s.to_string()));
+    }
+
+    Uuid::try_parse(trimmed).map_err(|_| BluetoothError::InvalidUuid(s.to_string()))
+}
+
+/// 给日志和界面用的服务名：常见服务显示缩写，其他短UUID显示成0x1101这种，剩下的原样输出
+pub fn service_label(uuid: Uuid) -> String {
+    match short_uuid(uuid) {
+        Some(0x1101) => "SPP".to_string(),
+        Some(0x1103) => "DUN".to_string(),
+        Some(0x1105) => "OPP".to_string(),
+        Some(0x1106) => "FTP".to_string(),
+        Some(short) if short <= 0xFFFF => format!("0x{:04X}", short),
+        Some(short) => format!("0x{:08X}", short),
+        None => uuid.to_string(),
+    }
+}
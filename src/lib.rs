@@ -1,8 +1,21 @@
 use std::{result, time::Duration};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
 use uuid::Uuid;
 
-use crate::common::device::BluetoothDevice;
+use tokio_util::sync::CancellationToken;
+
+use crate::common::{
+    cancel::with_cancel,
+    checksum::{Checksum, ChecksumKind},
+    device::BluetoothDevice,
+    framing::{FrameDescriptor, read_frame_or_eof},
+    pairing::{PairingConfig, PairingError},
+    split::{self, SppReadHalf, SppWriteHalf},
+    stats::SessionStats,
+};
 
 pub mod common;
 
@@ -11,6 +24,9 @@ pub mod mock;
 #[cfg(target_os = "windows")]
 pub mod windows;
 
+#[cfg(target_os = "linux")]
+pub mod linux;
+
 #[derive(Debug, thiserror::Error)]
 pub enum BluetoothError {
     #[error("Permission denied")]
@@ -25,62 +41,687 @@ pub enum BluetoothError {
     #[error("Service not found")]
     ServiceNotFound,
 
+    /// 配对成功了，但设备没有要连的RFCOMM服务，一般是设备模式或固件不对。配对会保留
+    #[error("Device paired successfully but exposes no matching RFCOMM service")]
+    ServiceNotFoundAfterPairing,
+
     #[error("Not connected")]
     NotConnected,
 
-    #[error("Timed out after {:?}", _0)]
-    TimedOut(Duration),
+    #[error("Connection refused: {}", _0)]
+    ConnectionRefused(String),
+
+    #[error("{} timed out after {:?}", operation, duration)]
+    TimedOut {
+        duration: Duration,
+        operation: TimeoutOp,
+    },
+
+    #[error("Peer disconnected")]
+    PeerDisconnected,
+
+    #[error("Operation cancelled")]
+    Cancelled,
 
     #[error("Runtime Error: {}", _0)]
     RuntimeError(String),
+
+    #[error("Invalid address: {:?}", _0)]
+    InvalidAddress(String),
+
+    #[error("Invalid device entry at index {}: {:?}", _0, _1)]
+    InvalidDeviceEntry(usize, String),
+
+    #[error("Timed out after reading {} bytes", filled)]
+    PartialRead { filled: usize },
+
+    #[error("Pairing failed: {}", _0)]
+    Pairing(#[from] PairingError),
+
+    #[error("Frame length {} exceeds the limit of {} bytes", declared, max)]
+    FrameTooLarge { declared: usize, max: usize },
+
+    #[error("Invalid frame descriptor: {}", _0)]
+    InvalidFrameDescriptor(&'static str),
+
+    #[error("Unsupported operation: {}", _0)]
+    Unsupported(&'static str),
+
+    #[error("Pattern not found within {} bytes", scanned)]
+    PatternNotFound { scanned: usize },
+
+    #[error("Invalid UUID: {:?}", _0)]
+    InvalidUuid(String),
+
+    #[error("Invalid device selector: {:?}", _0)]
+    InvalidSelector(String),
+
+    #[error("Invalid UTF-8: {:02X?}", _0)]
+    InvalidUtf8(Vec<u8>),
+
+    #[error("{} session(s) failed to close", _0.len())]
+    CloseFailed(Vec<(BluetoothDevice, BluetoothError)>),
 }
 
 pub type Result<T> = result::Result<T, BluetoothError>;
 
-pub trait BluetoothSppSession: AsyncRead + AsyncWrite {
-    fn connect(&mut self, device: &BluetoothDevice, need_pairing: bool) -> Result<()>;
+// 会话当AsyncRead/AsyncWrite用的时候，错误要以io::Error的形式交出去，原来的BluetoothError放在里面
+impl From<BluetoothError> for std::io::Error {
+    fn from(err: BluetoothError) -> std::io::Error {
+        let kind = match err {
+            BluetoothError::PermissionDenied => std::io::ErrorKind::PermissionDenied,
+            BluetoothError::DeviceNotFound
+            | BluetoothError::ServiceNotFound
+            | BluetoothError::ServiceNotFoundAfterPairing => std::io::ErrorKind::NotFound,
+            BluetoothError::NotConnected => std::io::ErrorKind::NotConnected,
+            BluetoothError::ConnectionRefused(_) => std::io::ErrorKind::ConnectionRefused,
+            BluetoothError::TimedOut { .. } | BluetoothError::PartialRead { .. } => {
+                std::io::ErrorKind::TimedOut
+            }
+            BluetoothError::Unsupported(_) => std::io::ErrorKind::Unsupported,
+            BluetoothError::Cancelled => std::io::ErrorKind::Interrupted,
+            BluetoothError::PeerDisconnected => std::io::ErrorKind::BrokenPipe,
+            _ => std::io::ErrorKind::Other,
+        };
+
+        std::io::Error::new(kind, err)
+    }
+}
+
+// 反过来：io::Error里面如果包着BluetoothError就把它取出来，不然当成运行时错误
+pub(crate) fn from_io_error(err: std::io::Error) -> BluetoothError {
+    err.downcast::<BluetoothError>()
+        .unwrap_or_else(|err| BluetoothError::RuntimeError(err.to_string()))
+}
+
+/// 超时的是哪一步，放在`TimedOut`里方便看日志
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutOp {
+    Connect,
+    Pairing,
+    Read,
+    Write,
+    Discovery,
+}
+
+impl std::fmt::Display for TimeoutOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TimeoutOp::Connect => "Connect",
+            TimeoutOp::Pairing => "Pairing",
+            TimeoutOp::Read => "Read",
+            TimeoutOp::Write => "Write",
+            TimeoutOp::Discovery => "Discovery",
+        };
+        f.write_str(name)
+    }
+}
+
+/// 会话的连接状态，给UI和状态机用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Disconnected,
+    Connecting,
+    Pairing,
+    Connected,
+    Reconnecting,
+    Failed,
+}
+
+/// 每次读写请求实际用的大小（字节），`None`表示不拆，调用方给多少就一次交给后端多少
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoSizes {
+    pub read_chunk: Option<usize>,
+    pub write_chunk: Option<usize>,
+}
+
+/// 后端支持哪些能力，通用代码可以据此调整用法，比如不能同时读写就退回一问一答
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SessionCapabilities {
+    /// 读和写可以同时挂起
+    pub concurrent_read_write: bool,
+    /// `send_break`能用
+    pub supports_break: bool,
+    /// 能拿到已连接设备的RSSI
+    pub supports_rssi: bool,
+    /// 能打开链路层的keep-alive
+    pub supports_keepalive: bool,
+}
+
+pub trait BluetoothSppSession: AsyncRead + AsyncWrite + Unpin {
+    /// connect系列的`pairing`可以直接传`bool`（`true`是只接受ConfirmOnly的配对），
+    /// 要PIN的设备传`PairingConfig::with_pin`或者自己的agent，见`PairingConfig`
+    fn connect(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+    ) -> Result<()>;
     fn connect_timeout(
         &mut self,
         device: &BluetoothDevice,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
         timeout: Duration,
     ) -> Result<()>;
     fn connect_by_uuid(
         &mut self,
         device: &BluetoothDevice,
         uuid: Uuid,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
     ) -> Result<()>;
     fn connect_by_uuid_timeout(
         &mut self,
         device: &BluetoothDevice,
         uuid: Uuid,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
         timeout: Duration,
     ) -> Result<()>;
     fn connect_async(
         &mut self,
         device: &BluetoothDevice,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
     ) -> impl std::future::Future<Output = Result<()>>;
     fn connect_by_uuid_async(
         &mut self,
         device: &BluetoothDevice,
         uuid: Uuid,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
     ) -> impl std::future::Future<Output = Result<()>>;
     fn device(&self) -> &BluetoothDevice;
     fn into_device(self) -> BluetoothDevice;
+
+    /// 粗暴断开：直接关掉socket，挂起的读写一律丢弃，已经交出去但还没写完的数据不保证送达。
+    fn disconnect(&mut self);
+
+    /// 优雅关闭：先等挂起的写完成并flush，再关闭socket并标记为未连接，socket真正关掉之后才返回。
+    ///
+    /// 和`disconnect`（立即断开）、`AsyncWriteExt::shutdown`（只走`poll_shutdown`，不会等挂起的写）
+    /// 相比，正常收尾时一般用这个。
+    fn close(&mut self) -> impl std::future::Future<Output = Result<()>>;
+
+    /// 当前连接状态，连接、配对、断开的过程中都会更新
+    fn state(&self) -> SessionState;
+
+    /// 会话现在能不能读写。读写出错把连接拆掉以后就是`false`，不用等到下次读写报错
+    fn is_connected(&self) -> bool {
+        self.state() == SessionState::Connected
+    }
+
+    /// 取消正在进行的读，已经读到一半的数据会被丢掉。之后再读会重新发起请求。
+    fn cancel_read(&mut self);
+
+    /// 丢掉会话内部缓存的所有读写数据，包括读了没交出去的和写了还没发出去的，连接本身不动。
+    ///
+    /// 每次connect开始时都会调用，旧连接的数据不会串到新连接里。
+    fn clear_buffers(&mut self);
+
+    /// 这个后端支持的能力
+    fn capabilities(&self) -> SessionCapabilities;
+
+    /// 读写统计
+    fn stats(&self) -> &SessionStats;
+
+    /// 算上默认值和各种上限以后，每次读写实际用的大小
+    fn effective_io_sizes(&self) -> IoSizes;
+
+    /// 拆成读写两半，两个任务可以一个读一个写，见`common::split::into_split`
+    fn into_split(self) -> (SppReadHalf<Self>, SppWriteHalf<Self>)
+    where
+        Self: Sized,
+    {
+        split::into_split(self)
+    }
+
+    /// 距离最后一次成功读写过了多久，还没读写过就从会话创建时算起
+    fn idle_since(&self) -> Duration {
+        self.stats().idle_since()
+    }
+
+    /// 最后一次读写真正传了字节的时间，还没有过就是`None`
+    fn last_progress(&self) -> Option<std::time::Instant> {
+        self.stats().last_progress()
+    }
+
+    /// `within`之内有没有收发过数据，用来区分链路只是安静还是已经卡住了
+    fn made_progress_recently(&self, within: Duration) -> bool {
+        self.last_progress()
+            .is_some_and(|last| last.elapsed() <= within)
+    }
+
+    /// 把`buf`整个读满，`timeout`算的是整次读满的时间，而不是每次读之间的间隔。
+    ///
+    /// 超时后会取消还在进行的读，返回`PartialRead`，`filled`是超时前已经写进`buf`的字节数。
+    fn read_exact_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<usize>> {
+        async move {
+            let mut filled = 0;
+
+            let result = time::timeout(timeout, async {
+                while filled < buf.len() {
+                    match self.read(&mut buf[filled..]).await {
+                        Ok(0) => return Err(BluetoothError::NotConnected),
+                        Ok(n) => filled += n,
+                        Err(err) => return Err(BluetoothError::RuntimeError(err.to_string())),
+                    }
+                }
+
+                Ok(())
+            })
+            .await;
+
+            match result {
+                Ok(Ok(())) => Ok(filled),
+                Ok(Err(err)) => Err(err),
+                Err(_) => {
+                    self.cancel_read();
+                    Err(BluetoothError::PartialRead { filled })
+                }
+            }
+        }
+    }
+
+    /// 读一次，但最晚只等到`deadline`，适合整个交互有一个固定截止时间的场景。
+    ///
+    /// 超时返回`TimedOut`，`duration`是调用时剩下的时间；调用时已经过了截止时间就直接返回`duration`为0的`TimedOut`，不会发起读。
+    fn read_until_deadline(
+        &mut self,
+        buf: &mut [u8],
+        deadline: std::time::Instant,
+    ) -> impl std::future::Future<Output = Result<usize>> {
+        async move {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                return Err(BluetoothError::TimedOut {
+                    duration: remaining,
+                    operation: TimeoutOp::Read,
+                });
+            }
+
+            match time::timeout(remaining, self.read(buf)).await {
+                Ok(Ok(n)) => Ok(n),
+                Ok(Err(err)) => Err(from_io_error(err)),
+                Err(_) => {
+                    self.cancel_read();
+                    Err(BluetoothError::TimedOut {
+                        duration: remaining,
+                        operation: TimeoutOp::Read,
+                    })
+                }
+            }
+        }
+    }
+
+    /// 一问一答：写出`request`，再读回`expected_len`字节的回复，`timeout`覆盖整个过程，超时返回`TimedOut`，
+    /// 卡在写还是读由`operation`区分。
+    fn transaction(
+        &mut self,
+        request: &[u8],
+        expected_len: usize,
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<Vec<u8>>> {
+        async move {
+            let mut reply = vec![0; expected_len];
+            let mut operation = TimeoutOp::Write;
+
+            let result = time::timeout(timeout, async {
+                let io_error = |err: std::io::Error| BluetoothError::RuntimeError(err.to_string());
+
+                self.write_all(request).await.map_err(io_error)?;
+                self.flush().await.map_err(io_error)?;
+                operation = TimeoutOp::Read;
+                self.read_exact(&mut reply).await.map_err(io_error)?;
+
+                Ok(())
+            })
+            .await;
+
+            match result {
+                Ok(Ok(())) => Ok(reply),
+                Ok(Err(err)) => Err(err),
+                Err(_) => {
+                    self.cancel_read();
+                    Err(BluetoothError::TimedOut {
+                        duration: timeout,
+                        operation,
+                    })
+                }
+            }
+        }
+    }
+
+    /// 一直读到出现`pattern`为止，前面的字节都丢掉，`pattern`后面的数据不会被读走。
+    ///
+    /// 最多看`max_bytes`个字节，还没找到返回`PatternNotFound`；`timeout`是整个等待的时间，超时返回`TimedOut`。
+    fn wait_for_pattern(
+        &mut self,
+        pattern: &[u8],
+        max_bytes: usize,
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<()>> {
+        async move {
+            let result = time::timeout(timeout, async {
+                // 窗口里只留可能是pattern开头的那段尾巴
+                let mut window: Vec<u8> = Vec::with_capacity(pattern.len());
+                let mut chunk = vec![0u8; pattern.len()];
+                let mut scanned = 0;
+
+                while window.len() < pattern.len() {
+                    // 最多只读凑满pattern还差的字节数，这样永远不会读过pattern的结尾
+                    let want = (pattern.len() - window.len()).min(max_bytes - scanned);
+                    if want == 0 {
+                        return Err(BluetoothError::PatternNotFound { scanned });
+                    }
+
+                    let n = match self.read(&mut chunk[..want]).await {
+                        Ok(0) => return Err(BluetoothError::NotConnected),
+                        Ok(n) => n,
+                        Err(err) => return Err(BluetoothError::RuntimeError(err.to_string())),
+                    };
+                    scanned += n;
+                    window.extend_from_slice(&chunk[..n]);
+
+                    if window.as_slice() == pattern {
+                        break;
+                    }
+
+                    // 整个窗口都可能是pattern的开头（分段读到的前半截），最多留pattern.len() - 1个
+                    let keep = (0..=window.len().min(pattern.len() - 1))
+                        .rev()
+                        .find(|&len| pattern.starts_with(&window[window.len() - len..]))
+                        .unwrap_or(0);
+                    window.drain(..window.len() - keep);
+                }
+
+                Ok(())
+            })
+            .await;
+
+            match result {
+                Ok(result) => result,
+                Err(_) => {
+                    self.cancel_read();
+                    Err(BluetoothError::TimedOut {
+                        duration: timeout,
+                        operation: TimeoutOp::Read,
+                    })
+                }
+            }
+        }
+    }
+
+    /// 读`size_of::<T>()`个字节，直接按`T`的内存布局解释。
+    ///
+    /// 字节是原样拷进去的，多字节字段要按`endianness`解释：和本机字节序（Windows上都是小端）
+    /// 不一样时会调`T::swap_bytes`把各个字段转过来。`T`里有填充字节时填充的内容是什么都不管。
+    #[cfg(feature = "bytemuck")]
+    fn read_struct<T: bytemuck::Pod + common::framing::SwapBytes>(
+        &mut self,
+        endianness: common::framing::Endianness,
+    ) -> impl std::future::Future<Output = Result<T>> {
+        async move {
+            let mut value = T::zeroed();
+            match self.read_exact(bytemuck::bytes_of_mut(&mut value)).await {
+                Ok(_) => {}
+                // 没读满对端就关了
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Err(BluetoothError::NotConnected);
+                }
+                Err(err) => return Err(BluetoothError::RuntimeError(err.to_string())),
+            }
+
+            if endianness == common::framing::Endianness::NATIVE {
+                Ok(value)
+            } else {
+                Ok(value.swap_bytes())
+            }
+        }
+    }
+
+    /// 同`connect_by_uuid_async`，但`cancel`取消以后返回`Cancelled`，会话是断开状态。
+    ///
+    /// 默认实现取消时直接扔掉connect的future。`WinrtSession`和`MockSession`会在每一步之间检查`cancel`，
+    /// 正在跑的那一步跑完才停，不会把系统的操作留在后台
+    fn connect_with_cancel(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+        cancel: CancellationToken,
+    ) -> impl std::future::Future<Output = Result<()>> {
+        async move {
+            let result =
+                with_cancel(self.connect_by_uuid_async(device, uuid, pairing), &cancel).await;
+            if let Err(BluetoothError::Cancelled) = result {
+                self.disconnect();
+            }
+            result
+        }
+    }
+
+    /// 同`connect_by_uuid_async`，但加上超时和取消：超时返回`TimedOut`（操作是`Connect`），
+    /// `cancel`被取消返回`Cancelled`，两个同时发生时算超时。
+    ///
+    /// 取消走`connect_with_cancel`，超时则直接停下并断开会话。
+    fn connect_by_uuid_timeout_or_cancel(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+        timeout: Duration,
+        cancel: &CancellationToken,
+    ) -> impl std::future::Future<Output = Result<()>> {
+        async move {
+            let timed_out = BluetoothError::TimedOut {
+                duration: timeout,
+                operation: TimeoutOp::Connect,
+            };
+            let deadline = time::Instant::now() + timeout;
+            let connect = self.connect_with_cancel(device, uuid, pairing, cancel.clone());
+
+            match time::timeout_at(deadline, connect).await {
+                // 会话停在取消上的时候超时也到了，还是算超时。计时器有1ms的粒度，
+                // 时间到了但还没触发的也算
+                Ok(Err(BluetoothError::Cancelled)) if time::Instant::now() >= deadline => {
+                    Err(timed_out)
+                }
+                Ok(result) => result,
+                Err(_) => {
+                    self.disconnect();
+                    Err(timed_out)
+                }
+            }
+        }
+    }
+
+    /// 测一次往返时间：基于`transaction`，返回从开始写到读完回复的耗时。
+    fn ping(
+        &mut self,
+        payload: &[u8],
+        expected_reply_len: usize,
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<Duration>> {
+        async move {
+            let start = std::time::Instant::now();
+            self.transaction(payload, expected_reply_len, timeout)
+                .await?;
+            Ok(start.elapsed())
+        }
+    }
+
+    /// 发送串口break信号。不是每个后端都支持，默认返回`Unsupported`。
+    fn send_break(&mut self, _duration: Duration) -> impl std::future::Future<Output = Result<()>> {
+        async { Err(BluetoothError::Unsupported("send_break")) }
+    }
+
+    /// 打开或关闭本机蓝牙射频。不是每个后端都支持，默认返回`Unsupported`。
+    fn set_radio_state(&mut self, _on: bool) -> impl std::future::Future<Output = Result<()>> {
+        async { Err(BluetoothError::Unsupported("set_radio_state")) }
+    }
+
+    /// 按`chunk`大小分块写，每块都等写完（并flush）才发下一块，然后用目前已发送的总字节数调用`on_chunk`。
+    ///
+    /// 适合应用层自己做流控的协议。
+    fn write_chunked(
+        &mut self,
+        data: &[u8],
+        chunk: usize,
+        mut on_chunk: impl FnMut(usize),
+    ) -> impl std::future::Future<Output = Result<()>> {
+        async move {
+            let mut sent = 0;
+
+            for piece in data.chunks(chunk.max(1)) {
+                self.write_all(piece)
+                    .await
+                    .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+                self.flush()
+                    .await
+                    .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+
+                sent += piece.len();
+                on_chunk(sent);
+            }
+
+            Ok(())
+        }
+    }
+
+    /// 按`chunk`大小分块写出`data`，边写边算校验，返回整段数据的校验值，调用方可以再把它作为尾巴发出去。
+    ///
+    /// 和`write_chunked`一样每块都等写完才发下一块。CRC-16的结果在低16位。
+    fn send_with_crc(
+        &mut self,
+        data: &[u8],
+        chunk: usize,
+        kind: ChecksumKind,
+    ) -> impl std::future::Future<Output = Result<u32>> {
+        async move {
+            let mut checksum = Checksum::new(kind);
+
+            for piece in data.chunks(chunk.max(1)) {
+                self.write_all(piece)
+                    .await
+                    .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+                self.flush()
+                    .await
+                    .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+                checksum.update(piece);
+            }
+
+            Ok(checksum.finish())
+        }
+    }
+}
+
+/// 给读写加超时，凡是`AsyncRead + AsyncWrite`的都能用，不只是会话。
+///
+/// 超时返回`TimedOut`，挂着的读写直接被丢掉；会话要把读彻底取消的话用`read_until_deadline`，它会调`cancel_read`。
+pub trait BluetoothSppSessionExt: AsyncRead + AsyncWrite + Unpin {
+    /// 读一次，最多等`timeout`
+    fn read_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<usize>> {
+        async move {
+            match time::timeout(timeout, self.read(buf)).await {
+                Ok(result) => result.map_err(from_io_error),
+                Err(_) => Err(BluetoothError::TimedOut {
+                    duration: timeout,
+                    operation: TimeoutOp::Read,
+                }),
+            }
+        }
+    }
+
+    /// 写一次，最多等`timeout`，返回这次写出去的字节数
+    fn write_timeout(
+        &mut self,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<usize>> {
+        async move {
+            match time::timeout(timeout, self.write(buf)).await {
+                Ok(result) => result.map_err(from_io_error),
+                Err(_) => Err(BluetoothError::TimedOut {
+                    duration: timeout,
+                    operation: TimeoutOp::Write,
+                }),
+            }
+        }
+    }
+
+    /// 读一个完整的帧（头+数据+校验），整帧最多等`timeout`，超时返回`TimedOut`。
+    ///
+    /// 两帧之间读到EOF返回`Ok(None)`。循环里一次处理一帧的时候比`FrameStream`顺手；
+    /// 超时时已经读了一半的帧会丢掉
+    fn next_frame(
+        &mut self,
+        descriptor: &FrameDescriptor,
+        timeout: Duration,
+    ) -> impl std::future::Future<Output = Result<Option<Vec<u8>>>> {
+        async move {
+            match time::timeout(timeout, read_frame_or_eof(self, descriptor)).await {
+                Ok(result) => result,
+                Err(_) => Err(BluetoothError::TimedOut {
+                    duration: timeout,
+                    operation: TimeoutOp::Read,
+                }),
+            }
+        }
+    }
 }
 
+impl<T: AsyncRead + AsyncWrite + Unpin + ?Sized> BluetoothSppSessionExt for T {}
+
 #[cfg(test)]
 mod tests {
 
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     use crate::{
-        common::mac::{mac_string_to_u64, mac_u64_to_string},
-        mock::session::MockSession,
+        common::{
+            bridge::bridge,
+            checksum::checksum,
+            device::{
+                DeviceDetails, DeviceInfo, DeviceParseError, DeviceSet, SPP_UUID, parse_device_csv,
+            },
+            diagnostics::StepOutcome,
+            discovery::{
+                DeviceEvent, DeviceTracker, DeviceUpdate, DiscoveryEvent, RawDeviceEntry,
+                collect_devices, collect_devices_limited, connect_or_resolve, devices_matching,
+                devices_with_service, discovery_events, map_device_entries, resolve_services,
+            },
+            export::{CSV_HEADER, to_csv},
+            framing::{
+                Endianness, FrameDescriptor, FrameStream, detect_endianness, read_frame_with_header,
+            },
+            mac::{
+                MacParseError, mac_string_to_u64, mac_u64_to_string, mac_u64_to_string_checked,
+                nap_sap,
+            },
+            manager::SessionManager,
+            oneshot::query_with,
+            pairing::{
+                DefaultAgent, PairingAgent, PairingBackend, PairingConfig, pair_if_needed,
+                repair_with,
+            },
+            pool::BufferPool,
+            reconnect::{Jitter, ReconnectingSession},
+            runtime::{ConnectAffinity, block_on, build_runtime, in_async_context},
+            sdp::{
+                PROTOCOL_DESCRIPTOR_LIST_ATTRIBUTE_ID, SERVICE_DESCRIPTION_ATTRIBUTE_ID,
+                SERVICE_NAME_ATTRIBUTE_ID, SdpValue, ServiceInfo, ServiceRecord,
+                decode_text_attribute, parse_service_search_attribute_response, rfcomm_channels,
+                select_service_by_name, service_search_attribute_request,
+            },
+            status::{ConnectionStatus, StatusChanges, validate_link},
+            text::{TextSession, Utf8Policy},
+            uuid::{from_short, parse_uuid},
+        },
+        mock::{fault::FaultConfig, session::MockSession},
     };
 
     use super::*;
@@ -107,12 +748,14 @@ mod tests {
         session.blocked_connect(true);
         let error = session.connect_timeout(&device, true, Duration::from_secs(1));
 
-        match error {
-            Err(BluetoothError::TimedOut(_)) => {}
-            _ => {
-                assert!(true);
-            }
-        }
+        assert!(matches!(
+            error,
+            Err(BluetoothError::TimedOut {
+                operation: TimeoutOp::Connect,
+                ..
+            })
+        ));
+        assert_eq!(session.state(), SessionState::Failed);
     }
 
     #[test]
@@ -134,18 +777,2622 @@ mod tests {
     }
 
     #[test]
-    fn test_mac_addr_parse() {
-        let addr = "00:02:B0:57:7D:D6".to_string();
-        let result = mac_string_to_u64(&addr);
-        if let Some(value) = result {
-            if value != 11548458454 {
-                assert!(true);
-            }
+    fn test_close_drains_pending_write() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(50));
 
-            let text = mac_u64_to_string(value);
-            assert_eq!(text, addr);
-        } else {
-            assert!(true);
+        aw!(async {
+            session.write_all(&[1, 2, 3]).await.unwrap();
+            assert!(session.written().is_empty());
+
+            session.close().await.unwrap();
+        });
+
+        assert_eq!(session.written(), &[1, 2, 3]);
+        assert!(!session.is_connected());
+    }
+
+    #[test]
+    fn test_auto_flush_waits_for_delivery() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(50));
+        session.set_auto_flush(true);
+
+        aw!(async {
+            let start = std::time::Instant::now();
+            session.write_all(&[1, 2, 3]).await.unwrap();
+            // 返回时数据已经送达，不用再flush
+            assert!(start.elapsed() >= Duration::from_millis(50));
+            assert_eq!(session.written(), &[1, 2, 3]);
+
+            assert_eq!(session.write(&[4]).await.unwrap(), 1);
+            assert_eq!(session.written(), &[1, 2, 3, 4]);
+        });
+    }
+
+    #[test]
+    fn test_max_pending_writes() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(50));
+        session.set_max_pending_writes(Some(2));
+
+        aw!(async {
+            assert_eq!(session.write(&[1]).await.unwrap(), 1);
+            assert_eq!(session.write(&[2]).await.unwrap(), 1);
+            assert_eq!(session.stats().pending_writes(), 2);
+
+            // 队列满了，第三个写要等最早那笔送达
+            let start = std::time::Instant::now();
+            assert!(
+                tokio_test::task::spawn(session.write(&[3]))
+                    .poll()
+                    .is_pending()
+            );
+            assert_eq!(session.write(&[3]).await.unwrap(), 1);
+            assert!(start.elapsed() >= Duration::from_millis(40));
+            // 前两个几乎同时到期，可能一起送达了，但3肯定还在路上
+            assert!(session.written().starts_with(&[1]));
+            assert!(!session.written().contains(&3));
+            assert!(session.stats().pending_writes() >= 1);
+
+            session.flush().await.unwrap();
+            assert_eq!(session.written(), &[1, 2, 3]);
+            assert_eq!(session.stats().pending_writes(), 0);
+        });
+    }
+
+    #[test]
+    fn test_large_read_buf_returns_available() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        aw!(async {
+            session.write_all(b"ok\n").await.unwrap();
+            // 调用方给了1MB，只到了3个字节，读到多少返回多少
+            let mut buf = vec![0u8; 1 << 20];
+            assert_eq!(session.read(&mut buf).await.unwrap(), 3);
+            assert_eq!(&buf[..3], b"ok\n");
+        });
+    }
+
+    #[test]
+    fn test_can_read_without_blocking() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        aw!(async {
+            let mut buf = [0u8; 2];
+            session.write_all(b"abc").await.unwrap();
+            session.read_exact(&mut buf).await.unwrap();
+            assert!(session.can_read_without_blocking());
+
+            session.read_exact(&mut buf[..1]).await.unwrap();
+            assert!(!session.can_read_without_blocking());
+
+            // 还在路上的数据不算，到了才算
+            session.set_latency(Duration::from_millis(20));
+            session.write_all(b"d").await.unwrap();
+            assert!(!session.can_read_without_blocking());
+            time::sleep(Duration::from_millis(30)).await;
+            assert!(session.can_read_without_blocking());
+
+            session.pause_reads();
+            assert!(!session.can_read_without_blocking());
+        });
+    }
+
+    #[test]
+    fn test_pause_resume_reads() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        aw!(async {
+            session.pause_reads();
+            session.write_all(&[1, 2, 3]).await.unwrap();
+
+            // 暂停期间数据已经到了也读不出来
+            let mut buf = [0u8; 3];
+            let paused =
+                tokio::time::timeout(Duration::from_millis(30), session.read(&mut buf)).await;
+            assert!(paused.is_err());
+            assert!(session.is_connected());
+
+            session.resume_reads();
+            session.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn test_bridge_both_directions() {
+        let device = BluetoothDevice::empty();
+        let mut a = MockSession::new();
+        let mut b = MockSession::new();
+        a.connect(&device, false).unwrap();
+        b.connect(&device, false).unwrap();
+
+        // a那边来了ping，b那边来了pong，b随后断开
+        aw!(a.write_all(b"ping")).unwrap();
+        aw!(b.write_all(b"pong")).unwrap();
+        b.finish_reads();
+
+        let copied = aw!(bridge(&mut a, &mut b)).unwrap();
+        assert_eq!(copied, (4, 4));
+        assert_eq!(b.written(), b"pongping");
+        assert_eq!(a.written(), b"pingpong");
+    }
+
+    #[test]
+    fn test_text_session_utf8_policy() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        let mut text = TextSession::new(session);
+        aw!(async {
+            text.write_str("AT+OK\r\n温度 21°C\n").await.unwrap();
+            assert_eq!(text.read_line().await.unwrap(), "AT+OK");
+            assert_eq!(text.read_line().await.unwrap(), "温度 21°C");
+
+            text.session_mut()
+                .write_all(b"bad \xFF\r\nnext\n")
+                .await
+                .unwrap();
+            assert!(matches!(
+                text.read_line().await,
+                Err(BluetoothError::InvalidUtf8(raw)) if raw == b"bad \xFF"
+            ));
+            // 坏的那行扔掉了，后面的照常读
+            assert_eq!(text.read_line().await.unwrap(), "next");
+        });
+
+        let mut text = text.with_utf8_policy(Utf8Policy::Lossy);
+        aw!(async {
+            text.session_mut().write_all(b"bad \xFF\n").await.unwrap();
+            assert_eq!(text.read_line().await.unwrap(), "bad \u{FFFD}");
+        });
+    }
+
+    #[test]
+    fn test_connect_by_name_fallback() {
+        let old = BluetoothDevice::new("OBDII".to_string(), 1);
+        let uuid = Uuid::from_u128(0x1234);
+        let mut session = MockSession::new();
+        session.unreachable_address(Some(1));
+
+        // 旧地址找不到，按名字找到新地址，换过去再连
+        aw!(connect_or_resolve(
+            &mut session,
+            &old,
+            uuid,
+            false.into(),
+            |name| async move {
+                assert_eq!(name, "OBDII");
+                Ok(BluetoothDevice::new(name, 2))
+            },
+            MockSession::rebind_to,
+        ))
+        .unwrap();
+        assert!(session.is_connected());
+        assert_eq!(session.device().addr(), 2);
+        assert_eq!(session.connection_profile().device.addr(), 2);
+
+        // 没有名字就不重找，也不换地址
+        let nameless = BluetoothDevice::new(String::new(), 1);
+        let result = aw!(connect_or_resolve(
+            &mut session,
+            &nameless,
+            uuid,
+            false.into(),
+            |_| async { panic!("不该按名字找") },
+            |_: &mut MockSession, _: &BluetoothDevice| panic!("不该换地址"),
+        ));
+        assert!(matches!(result, Err(BluetoothError::DeviceNotFound)));
+
+        // 按名字也找不到时报找名字的错
+        let result = aw!(connect_or_resolve(
+            &mut session,
+            &old,
+            uuid,
+            false.into(),
+            |_| async { Err(BluetoothError::DeviceNotFound) },
+            MockSession::rebind_to,
+        ));
+        assert!(matches!(result, Err(BluetoothError::DeviceNotFound)));
+        assert_eq!(session.device().addr(), 1);
+
+        // 别的错误不重试
+        session.unreachable_address(None);
+        session.missing_service(true);
+        let result = aw!(connect_or_resolve(
+            &mut session,
+            &old,
+            uuid,
+            false.into(),
+            |_| async { panic!("不该按名字找") },
+            MockSession::rebind_to,
+        ));
+        assert!(matches!(result, Err(BluetoothError::ServiceNotFound)));
+    }
+
+    #[test]
+    fn test_rebind_to() {
+        let old = BluetoothDevice::new("OBDII".to_string(), 1);
+        let new = BluetoothDevice::new("OBDII".to_string(), 2);
+        let uuid = Uuid::from_u128(0x1234);
+
+        let mut session = MockSession::new();
+        session.connect_by_uuid(&old, uuid, false).unwrap();
+        session.rebind_to(&new);
+        // 当前连接不动
+        assert!(session.is_connected());
+
+        let profile = session.connection_profile();
+        assert_eq!(profile.device.addr(), 2);
+        assert_eq!(profile.uuid, uuid);
+
+        session.disconnect();
+        aw!(session.connect_by_uuid_async(&profile.device, profile.uuid, false)).unwrap();
+        assert_eq!(session.device().addr(), 2);
+    }
+
+    #[test]
+    fn test_mock_read_respects_buf_size() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.push_read_data(b"0123456789");
+
+        // 缓冲区比攒着的数据小，分几次读完，不会panic也不会丢
+        let mut buf = [0u8; 3];
+        let mut received = Vec::new();
+        while received.len() < 10 {
+            let n = aw!(session.read(&mut buf)).unwrap();
+            assert!(n > 0 && n <= 3);
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, b"0123456789");
+
+        // 读空了就挂起，来了数据再唤醒
+        let mut read = tokio_test::task::spawn(session.read(&mut buf));
+        assert!(read.poll().is_pending());
+        drop(read);
+        session.push_read_data(b"ab");
+        let mut read = tokio_test::task::spawn(session.read(&mut buf));
+        assert!(matches!(read.poll(), std::task::Poll::Ready(Ok(2))));
+    }
+
+    #[test]
+    fn test_abandon_reads_stress() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        for _ in 0..1000 {
+            let mut buf = [0u8; 8];
+            let mut read = tokio_test::task::spawn(session.read(&mut buf));
+            assert!(read.poll().is_pending());
+            drop(read);
+            session.cancel_read();
+        }
+
+        // 扔了那么多读以后照样能正常读写
+        let mut buf = [0u8; 3];
+        aw!(async {
+            session.write_all(&[1, 2, 3]).await.unwrap();
+            session.read_exact(&mut buf).await.unwrap();
+        });
+        assert_eq!(buf, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_status_changes() {
+        let mut changes = StatusChanges::new();
+        let sender = changes.sender();
+
+        // 取之前就已经有的变化立刻返回
+        sender.send(ConnectionStatus::Disconnected).unwrap();
+        assert_eq!(aw!(changes.next()).unwrap(), ConnectionStatus::Disconnected);
+
+        // 回调是在别的线程上来的
+        let result = aw!(async {
+            let handle = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                sender.send(ConnectionStatus::Connected).unwrap();
+            });
+            let status = changes.next().await;
+            handle.join().unwrap();
+            status
+        });
+        assert_eq!(result.unwrap(), ConnectionStatus::Connected);
+
+        // 换连接时旧的变化被清掉
+        changes.sender().send(ConnectionStatus::Connected).unwrap();
+        changes.clear();
+        let mut next = tokio_test::task::spawn(changes.next());
+        assert!(next.poll().is_pending());
+    }
+
+    #[test]
+    fn test_reconnect_discards_stale_data() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(20));
+
+        aw!(async {
+            session.write_all(b"old").await.unwrap();
+            session.flush().await.unwrap();
+            // 还有一笔在途
+            session.write_all(b"stale").await.unwrap();
+        });
+
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::ZERO);
+
+        let mut buf = [0u8; 3];
+        aw!(async {
+            session.write_all(b"new").await.unwrap();
+            session.read_exact(&mut buf).await.unwrap();
+        });
+        assert_eq!(&buf, b"new");
+
+        // 手动清
+        aw!(session.write_all(b"junk")).unwrap();
+        session.clear_buffers();
+        let mut read = tokio_test::task::spawn(session.read(&mut buf));
+        assert!(read.poll().is_pending());
+        assert!(read.poll().is_pending());
+    }
+
+    #[test]
+    fn test_write_latency_stats() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        assert_eq!(session.write_latency_stats().average(), None);
+
+        let latency = Duration::from_millis(30);
+        session.set_latency(latency);
+
+        aw!(async {
+            for _ in 0..3 {
+                session.write_all(&[1, 2, 3]).await.unwrap();
+                session.flush().await.unwrap();
+            }
+        });
+
+        let stats = session.write_latency_stats();
+        assert_eq!(stats.count(), 3);
+        assert!(stats.min().unwrap() >= latency);
+        let average = stats.average().unwrap();
+        assert!(average >= latency && average < latency * 3, "{:?}", average);
+    }
+
+    #[test]
+    fn test_mac_addr_parse() {
+        let addr = "00:02:B0:57:7D:D6".to_string();
+        let result = mac_string_to_u64(&addr);
+        if let Ok(value) = result {
+            if value != 11548458454 {
+                assert!(true);
+            }
+
+            let text = mac_u64_to_string(value);
+            assert_eq!(text, addr);
+        } else {
+            assert!(true);
+        }
+    }
+
+    #[test]
+    fn test_mac_parse_errors() {
+        assert_eq!(mac_string_to_u64("0002B0577DD6"), Ok(11548458454));
+        assert_eq!(
+            mac_string_to_u64("00:02:B0:57:7D"),
+            Err(MacParseError::WrongLength(10))
+        );
+        assert_eq!(
+            mac_string_to_u64("00:02:B0:57:7D:DG"),
+            Err(MacParseError::InvalidHexDigit('G'))
+        );
+        assert_eq!(
+            mac_string_to_u64("00_02_B0_57_7D_D6"),
+            Err(MacParseError::BadSeparator('_'))
+        );
+        assert_eq!(
+            BluetoothDevice::new_by_addr_string("OBDII".to_string(), "00:02:B0"),
+            Err(MacParseError::WrongLength(6))
+        );
+    }
+
+    #[test]
+    fn test_mac_separator_styles() {
+        for addr in [
+            "00:02:B0:57:7D:D6",
+            "00-02-B0-57-7D-D6",
+            "0002.b057.7dd6",
+            "0002B0577DD6",
+            "00:02:b0:57:7d:d6",
+        ] {
+            assert_eq!(mac_string_to_u64(addr), Ok(11548458454), "{}", addr);
+        }
+
+        // 混用分隔符不行
+        assert_eq!(
+            mac_string_to_u64("00:02-B0:57:7D:D6"),
+            Err(MacParseError::BadSeparator('-'))
+        );
+        assert_eq!(
+            mac_string_to_u64("0002.B057:7DD6"),
+            Err(MacParseError::BadSeparator(':'))
+        );
+
+        // 分隔符位置不对
+        for (addr, separator) in [
+            ("AAB:BCCDDEEFF", ':'),
+            (":AABBCCDDEEFF", ':'),
+            ("AABBCCDDEEFF:", ':'),
+            ("AA:BB:CC:DD:EEFF", ':'),
+            ("A-A-B-B-C-C-D-D-E-E-F-F", '-'),
+            ("aab.bccdde.eff", '.'),
+            ("aabbcc.ddeeff", '.'),
+        ] {
+            assert_eq!(
+                mac_string_to_u64(addr),
+                Err(MacParseError::BadSeparator(separator)),
+                "{}",
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn test_mac_u64_to_string_checked() {
+        assert_eq!(
+            mac_u64_to_string_checked(11548458454).as_deref(),
+            Some("00:02:B0:57:7D:D6")
+        );
+        assert_eq!(
+            mac_u64_to_string_checked(0xFFFF_FFFF_FFFF).as_deref(),
+            Some("FF:FF:FF:FF:FF:FF")
+        );
+        // 高16位有值说明不是地址
+        assert_eq!(mac_u64_to_string_checked(1 << 48), None);
+        assert_eq!(mac_u64_to_string_checked(u64::MAX), None);
+    }
+
+    #[test]
+    fn test_device_display_from_str() {
+        let device = BluetoothDevice::new("OBDII".to_string(), 11548458454);
+        assert_eq!(device.to_string(), "OBDII (00:02:B0:57:7D:D6)");
+        assert_eq!(BluetoothDevice::empty().to_string(), "00:00:00:00:00:00");
+
+        let parsed: BluetoothDevice = device.to_string().parse().unwrap();
+        assert_eq!(parsed, device);
+        assert_eq!(parsed.name(), "OBDII");
+
+        // 名字里可以有括号和空格，地址取最后一对括号里的
+        let parsed: BluetoothDevice = " My (old) Car  (D0:AE:05:05:1A:22) ".parse().unwrap();
+        assert_eq!(parsed.name(), "My (old) Car");
+        assert_eq!(parsed.addr_string(), "D0:AE:05:05:1A:22");
+
+        assert_eq!(
+            "(00:02:B0:57:7D:D6)".parse::<BluetoothDevice>(),
+            Err(DeviceParseError::MissingName)
+        );
+
+        // 没名字的设备只显示地址，也要能解析回来
+        let nameless = BluetoothDevice::new(String::new(), 11548458454);
+        let parsed: BluetoothDevice = nameless.to_string().parse().unwrap();
+        assert_eq!(parsed, nameless);
+        assert_eq!(parsed.name(), "");
+        assert_eq!(
+            "OBDII (00:02:B0:57:7D)".parse::<BluetoothDevice>(),
+            Err(DeviceParseError::InvalidAddress(
+                "00:02:B0:57:7D".to_string()
+            ))
+        );
+        assert!(matches!(
+            "OBDII".parse::<BluetoothDevice>(),
+            Err(DeviceParseError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_device_bytes() {
+        let bytes = [0x00, 0x02, 0xB0, 0x57, 0x7D, 0xD6];
+        let device = BluetoothDevice::from(bytes);
+        assert_eq!(device.addr_string(), "00:02:B0:57:7D:D6");
+        assert_eq!(device.addr_bytes(), bytes);
+        assert!(device.name().is_empty());
+
+        let device = BluetoothDevice::try_from(&bytes[..]).unwrap();
+        assert_eq!(device.addr(), 0x0002B0577DD6);
+
+        let result = BluetoothDevice::try_from(&bytes[..5]);
+        assert!(matches!(result, Err(BluetoothError::InvalidAddress(_))));
+        let result = BluetoothDevice::try_from(&[0u8; 7][..]);
+        assert!(matches!(result, Err(BluetoothError::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn test_nap_sap() {
+        let addr = mac_string_to_u64("00:02:B0:57:7D:D6").unwrap();
+        assert_eq!(nap_sap(addr), (0x0002, 0xB0577DD6));
+
+        let addr = mac_string_to_u64("D0:AE:05:05:1A:22").unwrap();
+        assert_eq!(nap_sap(addr), (0xD0AE, 0x05051A22));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_device_serde_round_trip() {
+        let device = BluetoothDevice::new("OBDII".to_string(), 0x0002B0577DD6);
+
+        let json = serde_json::to_string(&device).unwrap();
+        assert_eq!(json, r#"{"name":"OBDII","addr":"00:02:B0:57:7D:D6"}"#);
+
+        let restored: BluetoothDevice = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, device);
+        assert_eq!(restored.name, device.name);
+
+        assert!(
+            serde_json::from_str::<BluetoothDevice>(r#"{"name":"OBDII","addr":"not a mac"}"#)
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_connection_profile_round_trip() {
+        use crate::common::profile::ConnectionProfile;
+
+        let mut profile = ConnectionProfile::new(
+            BluetoothDevice::new("OBDII".to_string(), 0x0002B0577DD6),
+            SPP_UUID,
+        );
+        profile.service_name = Some("Bluetooth#Bluetooth00:1a:7d:da:71:13-00:02:b0:57:7d:d6#RFCOMM:00000000:{00001101-0000-1000-8000-00805f9b34fb}".to_string());
+        profile.adapter = Some("(00:1A:7D:DA:71:13)".to_string());
+
+        let json = serde_json::to_string(&profile).unwrap();
+        assert!(json.contains("\"OBDII@00:02:B0:57:7D:D6\""));
+
+        let restored: ConnectionProfile = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, profile);
+        assert_eq!(restored.device.name(), "OBDII");
+        assert_eq!(restored.service_name, profile.service_name);
+        assert_eq!(restored.adapter, profile.adapter);
+    }
+
+    #[test]
+    fn test_parse_device_csv() {
+        let devices =
+            parse_device_csv(" OBDII@00:02:B0:57:7D:D6 ,D0:AE:05:05:1A:22, Test@D0:AE:05:05:1A:23")
+                .unwrap();
+
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[0].name(), "OBDII");
+        assert_eq!(devices[0].addr(), 11548458454);
+        assert_eq!(devices[1].name(), "");
+        assert_eq!(devices[1].addr_string(), "D0:AE:05:05:1A:22");
+        assert_eq!(devices[2].name(), "Test");
+    }
+
+    #[test]
+    fn test_parse_device_csv_bad_entry() {
+        let result = parse_device_csv("OBDII@00:02:B0:57:7D:D6,Test@not-a-mac,D0:AE:05:05:1A:22");
+
+        match result {
+            Err(BluetoothError::InvalidDeviceEntry(index, entry)) => {
+                assert_eq!(index, 1);
+                assert_eq!(entry, "Test@not-a-mac");
+            }
+            _ => panic!("expected InvalidDeviceEntry"),
+        }
+    }
+
+    #[test]
+    fn test_service_record_raw_attributes() {
+        let record = ServiceRecord::new(SPP_UUID)
+            .service_name("Test SPP")
+            .attribute(
+                SERVICE_DESCRIPTION_ATTRIBUTE_ID,
+                SdpValue::Sequence(vec![SdpValue::Uint8(1), SdpValue::Uint16(0x0203)]),
+            );
+
+        let raw = record.raw_attributes();
+
+        assert_eq!(raw.len(), 2);
+        assert_eq!(raw[0].0, 0x0100);
+        assert_eq!(raw[0].1, b"\x25\x08Test SPP".to_vec());
+        assert_eq!(raw[1].0, 0x0101);
+        assert_eq!(raw[1].1, vec![0x35, 0x05, 0x08, 0x01, 0x09, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_sdp_rfcomm_channel_query() {
+        let request = service_search_attribute_request(7, SPP_UUID, &[]);
+        // PDU编号、事务号、参数长度
+        assert_eq!(
+            &request[..5],
+            &[0x06, 0x00, 0x07, 0x00, (request.len() - 5) as u8]
+        );
+        assert_eq!(request.last(), Some(&0x00));
+
+        // 一条SPP记录：L2CAP，RFCOMM通道5
+        let lists = SdpValue::Sequence(vec![SdpValue::Sequence(vec![
+            SdpValue::Uint16(PROTOCOL_DESCRIPTOR_LIST_ATTRIBUTE_ID),
+            SdpValue::Sequence(vec![
+                SdpValue::Sequence(vec![SdpValue::Uuid(from_short(0x0100))]),
+                SdpValue::Sequence(vec![SdpValue::Uuid(from_short(0x0003)), SdpValue::Uint8(5)]),
+            ]),
+        ])])
+        .encode();
+
+        // 对端分两段回，第一段带续传状态
+        let response = |transaction: u16, chunk: &[u8], continuation: &[u8]| {
+            let mut params = (chunk.len() as u16).to_be_bytes().to_vec();
+            params.extend_from_slice(chunk);
+            params.push(continuation.len() as u8);
+            params.extend_from_slice(continuation);
+            let mut pdu = vec![0x07];
+            pdu.extend_from_slice(&transaction.to_be_bytes());
+            pdu.extend_from_slice(&(params.len() as u16).to_be_bytes());
+            pdu.extend(params);
+            pdu
+        };
+        let (head, tail) = lists.split_at(6);
+
+        let (first, continuation) =
+            parse_service_search_attribute_response(1, &response(1, head, &[0xAB, 0xCD])).unwrap();
+        assert_eq!(continuation, [0xAB, 0xCD]);
+        assert!(
+            service_search_attribute_request(2, SPP_UUID, &continuation)
+                .ends_with(&[2, 0xAB, 0xCD])
+        );
+
+        let (second, continuation) =
+            parse_service_search_attribute_response(2, &response(2, tail, &[])).unwrap();
+        assert!(continuation.is_empty());
+        assert_eq!(rfcomm_channels(&[first, second].concat()), [5]);
+
+        // 事务号对不上、错误回应都不认
+        assert!(parse_service_search_attribute_response(3, &response(2, tail, &[])).is_none());
+        assert!(
+            parse_service_search_attribute_response(1, &[0x01, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03])
+                .is_none()
+        );
+        assert!(rfcomm_channels(&SdpValue::Sequence(vec![]).encode()).is_empty());
+    }
+
+    #[test]
+    fn test_service_info_display() {
+        let named = ServiceInfo {
+            uuid: SPP_UUID,
+            name: Some("Serial Port".to_string()),
+        };
+        assert_eq!(
+            named.to_string(),
+            "Serial Port (00001101-0000-1000-8000-00805f9b34fb)"
+        );
+
+        let unnamed = ServiceInfo {
+            uuid: SPP_UUID,
+            name: None,
+        };
+        assert_eq!(unnamed.to_string(), "00001101-0000-1000-8000-00805f9b34fb");
+    }
+
+    #[test]
+    fn test_select_service_by_name() {
+        // 模拟从各个服务读出来的ServiceName属性
+        let raw = [
+            (1, SdpValue::Text("Serial Port\0".to_string()).encode()),
+            (2, SdpValue::Uint16(0x0100).encode()),
+            (3, SdpValue::Text("OBD Data".to_string()).encode()),
+        ];
+        let named: Vec<(i32, Option<String>)> = raw
+            .iter()
+            .map(|(id, data)| (*id, decode_text_attribute(data)))
+            .collect();
+        assert_eq!(named[0].1.as_deref(), Some("Serial Port"));
+        assert_eq!(named[1].1, None);
+
+        assert_eq!(select_service_by_name(named.clone(), "OBD Data"), Some(3));
+        assert_eq!(
+            select_service_by_name(named.clone(), "Serial Port"),
+            Some(1)
+        );
+        // 只认完全一致的名字
+        assert_eq!(select_service_by_name(named.clone(), "OBD"), None);
+        assert_eq!(select_service_by_name(named, "obd data"), None);
+
+        // 解析和编码互为逆运算
+        let record = ServiceRecord::new(SPP_UUID).service_name("My Service");
+        let (id, data) = &record.raw_attributes()[0];
+        assert_eq!(*id, SERVICE_NAME_ATTRIBUTE_ID);
+        assert_eq!(decode_text_attribute(data).as_deref(), Some("My Service"));
+        let nested = SdpValue::Sequence(vec![SdpValue::Uuid(SPP_UUID), SdpValue::Uint8(3)]);
+        assert_eq!(
+            SdpValue::decode(&nested.encode()),
+            Some((nested.clone(), nested.encode().len()))
+        );
+    }
+
+    #[test]
+    fn test_session_summary() {
+        let device =
+            BluetoothDevice::new_by_addr_string("OBDII".to_string(), "00:02:B0:57:7D:D6").unwrap();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        let summary = session.summary();
+        assert!(summary.contains("00:02:B0:57:7D:D6"));
+        assert!(summary.contains("ready=true"));
+        assert_eq!(
+            summary,
+            "OBDII (00:02:B0:57:7D:D6) service=SPP ready=true read_pending=false"
+        );
+    }
+
+    #[test]
+    fn test_read_exact_timeout_partial() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(20));
+
+        let mut buf = [0; 8];
+        let result = aw!(async {
+            session.write_all(&[1, 2, 3]).await.unwrap();
+            session
+                .read_exact_timeout(&mut buf, Duration::from_millis(200))
+                .await
+        });
+
+        match result {
+            Err(BluetoothError::PartialRead { filled }) => {
+                assert_eq!(filled, 3);
+                assert_eq!(&buf[..3], &[1, 2, 3]);
+            }
+            _ => panic!("expected PartialRead"),
+        }
+    }
+
+    #[test]
+    fn test_device_normalized() {
+        let device = BluetoothDevice::new("  OBD  II ".to_string(), 11548458454);
+        let normalized = device.normalized();
+
+        assert_eq!(normalized.name(), "OBD II");
+        assert_eq!(normalized.addr(), device.addr());
+        assert!(normalized == device);
+    }
+
+    #[test]
+    fn test_device_details_default() {
+        let details = DeviceDetails::default();
+        assert_eq!(details.device_id, None);
+        assert_eq!(details.class_of_device, None);
+        assert_eq!(details.connected, None);
+        assert_eq!(details.secure_connection, None);
+    }
+
+    fn sample_scan_result() -> DeviceInfo {
+        let mut info = DeviceInfo::observed(
+            BluetoothDevice::new("OBD, II".to_string(), 11548458454),
+            None,
+        );
+        info.paired = true;
+        info.rssi = Some(-60);
+        info.class_of_device = Some(0x1F00);
+        info
+    }
+
+    #[test]
+    fn test_scan_result_csv() {
+        let anonymous = DeviceInfo::observed(BluetoothDevice::new(String::new(), 1), None);
+        let csv = to_csv(&[sample_scan_result(), anonymous]);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[0], "address,name,paired,connected,rssi,class");
+        assert_eq!(
+            lines[1],
+            "00:02:B0:57:7D:D6,\"OBD, II\",true,false,-60,0x001F00"
+        );
+        assert_eq!(lines[2], "00:00:00:00:00:01,,false,false,,");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_scan_result_json_line() {
+        let line = sample_scan_result().to_json_line();
+        assert!(!line.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["address"], "00:02:B0:57:7D:D6");
+        assert_eq!(value["name"], "OBD, II");
+        assert_eq!(value["paired"], true);
+        assert_eq!(value["rssi"], -60);
+        assert_eq!(value["class"], 0x1F00);
+    }
+
+    #[test]
+    fn test_device_same_endpoint() {
+        let classic = BluetoothDevice::new("OBDII".to_string(), 11548458454);
+        let le = BluetoothDevice::new("OBDII LE".to_string(), 11548458454);
+        let other = BluetoothDevice::new("OBDII".to_string(), 11548458455);
+
+        assert!(classic.same_endpoint(&le));
+        assert!(!classic.same_endpoint(&other));
+    }
+
+    #[test]
+    fn test_device_info_last_seen_defaults_to_now() {
+        let before = std::time::SystemTime::now();
+        let info = DeviceInfo::observed(BluetoothDevice::empty(), None);
+        let after = std::time::SystemTime::now();
+
+        let last_seen = info.last_seen.unwrap();
+        assert!(last_seen >= before && last_seen <= after);
+    }
+
+    #[test]
+    fn test_device_set_merge() {
+        let scan = |name: &str, addr: u64, rssi: i16, secs: u64| {
+            let mut info = DeviceInfo::observed(
+                BluetoothDevice::new(name.to_string(), addr),
+                Some(std::time::UNIX_EPOCH + Duration::from_secs(secs)),
+            );
+            info.rssi = Some(rssi);
+            info
+        };
+
+        let mut set: DeviceSet = vec![scan("A", 1, -70, 10), scan("B", 2, -50, 10)]
+            .into_iter()
+            .collect();
+        set.extend(vec![scan("A-renamed", 1, -80, 20), scan("C", 3, -60, 20)]);
+
+        assert_eq!(set.len(), 3);
+        let a = set.get(&BluetoothDevice::new(String::new(), 1)).unwrap();
+        assert_eq!(a.device.name(), "A-renamed");
+        assert_eq!(a.rssi, Some(-70));
+        assert_eq!(
+            a.last_seen,
+            Some(std::time::UNIX_EPOCH + Duration::from_secs(20))
+        );
+
+        // 没名字的结果不会把已有的名字冲掉
+        set.merge(scan("", 2, -40, 30));
+        let b = set.get(&BluetoothDevice::new(String::new(), 2)).unwrap();
+        assert_eq!(b.device.name(), "B");
+        assert_eq!(b.rssi, Some(-40));
+        assert_eq!(set.iter().count(), 3);
+    }
+
+    struct StubPairing {
+        paired: bool,
+        fail_unpair: bool,
+        calls: Vec<&'static str>,
+    }
+
+    impl PairingBackend for StubPairing {
+        async fn is_paired(&mut self) -> std::result::Result<bool, PairingError> {
+            Ok(self.paired)
+        }
+
+        async fn unpair(&mut self) -> std::result::Result<(), PairingError> {
+            self.calls.push("unpair");
+            if self.fail_unpair {
+                return Err(PairingError::UnpairFailed("stub".to_string()));
+            }
+            self.paired = false;
+            Ok(())
+        }
+
+        async fn pair(
+            &mut self,
+            _agent: std::sync::Arc<dyn PairingAgent>,
+        ) -> std::result::Result<(), PairingError> {
+            self.calls.push("pair");
+            self.paired = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_repair_sequence() {
+        let mut backend = StubPairing {
+            paired: true,
+            fail_unpair: false,
+            calls: Vec::new(),
+        };
+        aw!(repair_with(&mut backend, std::sync::Arc::new(DefaultAgent))).unwrap();
+        assert_eq!(backend.calls, vec!["unpair", "pair"]);
+        assert!(backend.paired);
+
+        // 没配对过就直接配对
+        let mut backend = StubPairing {
+            paired: false,
+            fail_unpair: false,
+            calls: Vec::new(),
+        };
+        aw!(repair_with(&mut backend, std::sync::Arc::new(DefaultAgent))).unwrap();
+        assert_eq!(backend.calls, vec!["pair"]);
+
+        // 解除配对失败时不再继续配对
+        let mut backend = StubPairing {
+            paired: true,
+            fail_unpair: true,
+            calls: Vec::new(),
+        };
+        let result = aw!(repair_with(&mut backend, std::sync::Arc::new(DefaultAgent)));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::Pairing(PairingError::UnpairFailed(_)))
+        ));
+        assert_eq!(backend.calls, vec!["unpair"]);
+    }
+
+    #[test]
+    fn test_pair_if_needed_skips_paired() {
+        // 已经配对的设备连pair都不会调用，也就不会注册handler
+        let mut backend = StubPairing {
+            paired: true,
+            fail_unpair: false,
+            calls: Vec::new(),
+        };
+        let paired = aw!(pair_if_needed(
+            &mut backend,
+            std::sync::Arc::new(DefaultAgent)
+        ))
+        .unwrap();
+        assert!(!paired);
+        assert!(backend.calls.is_empty());
+
+        let mut backend = StubPairing {
+            paired: false,
+            fail_unpair: false,
+            calls: Vec::new(),
+        };
+        let paired = aw!(pair_if_needed(
+            &mut backend,
+            std::sync::Arc::new(DefaultAgent)
+        ))
+        .unwrap();
+        assert!(paired);
+        assert_eq!(backend.calls, vec!["pair"]);
+    }
+
+    #[test]
+    fn test_read_frame_too_large() {
+        let descriptor = FrameDescriptor::new(&[0xA5, 0xA5], 2, 4).unwrap();
+        let mut session = MockSession::new();
+
+        // 只给一个声明了4GB长度的头，后面没有数据
+        let result = aw!(async {
+            session
+                .write_all(&[0x00, 0xA5, 0xA5, 0xFF, 0xFF, 0xFF, 0xFF])
+                .await
+                .unwrap();
+            let mut reader = tokio::io::BufReader::new(&mut session);
+            read_frame_with_header(&mut reader, &descriptor).await
+        });
+
+        match result {
+            Err(BluetoothError::FrameTooLarge { declared, max }) => {
+                assert_eq!(declared, 0xFFFF_FFFF);
+                assert_eq!(max, 64 * 1024);
+            }
+            _ => panic!("expected FrameTooLarge"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_frame_descriptor() {
+        // 空魔数和超过usize宽度的长度字段在构造时就拒绝
+        assert!(matches!(
+            FrameDescriptor::new(&[], 0, 2),
+            Err(BluetoothError::InvalidFrameDescriptor(_))
+        ));
+        assert!(matches!(
+            FrameDescriptor::new(&[0xA5], 1, 0),
+            Err(BluetoothError::InvalidFrameDescriptor(_))
+        ));
+        assert!(matches!(
+            FrameDescriptor::new(&[0xA5], 1, 9),
+            Err(BluetoothError::InvalidFrameDescriptor(_))
+        ));
+
+        // 构造完再改字段，解析时报错而不是panic
+        let mut descriptor = FrameDescriptor::new(&[0xA5], 1, 2).unwrap();
+        descriptor.magic.clear();
+        let mut buf = vec![0xA5, 0x00, 0x01, 0x10];
+        assert!(matches!(
+            descriptor.decode(&mut buf),
+            Err(BluetoothError::InvalidFrameDescriptor(_))
+        ));
+
+        let mut descriptor = FrameDescriptor::new(&[0xA5], 1, 2).unwrap();
+        descriptor.length_size = 16;
+        assert!(matches!(
+            descriptor.body_len(&[0xA5; 17]),
+            Err(BluetoothError::InvalidFrameDescriptor(_))
+        ));
+    }
+
+    #[test]
+    fn test_frame_stream() {
+        use futures::StreamExt;
+
+        let descriptor = FrameDescriptor::new(&[0xA5, 0xA5], 4, 2)
+            .unwrap()
+            .endianness(Endianness::Little)
+            .checksum_len(2);
+        let frame = [
+            0xA5, 0xA5, 0x02, 0x00, 0x03, 0x00, 0x01, 0x02, 0x03, 0xFC, 0x03,
+        ];
+
+        let mut session = MockSession::new();
+        let first = aw!(async {
+            // 前面带点垃圾，后面跟半个帧
+            session.write_all(&[0x11, 0x22]).await.unwrap();
+            session.write_all(&frame).await.unwrap();
+            session.write_all(&frame[..4]).await.unwrap();
+
+            let mut frames = FrameStream::new(&mut session, descriptor);
+            frames.next().await
+        });
+
+        assert_eq!(first.unwrap().unwrap(), frame.to_vec());
+    }
+
+    #[test]
+    fn test_mock_responder() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new().with_responder(|data| data.to_ascii_uppercase());
+        session.connect(&device, false).unwrap();
+
+        let reply = aw!(session.transaction(b"at+ver?", 7, Duration::from_secs(1))).unwrap();
+        assert_eq!(reply, b"AT+VER?");
+        assert_eq!(session.written(), b"at+ver?");
+
+        // 每次写各自调一次
+        aw!(async {
+            session.write_all(b"ok").await.unwrap();
+            session.flush().await.unwrap();
+            let mut buf = [0u8; 2];
+            session.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"OK");
+        });
+    }
+
+    #[test]
+    fn test_push_read_data_and_failures() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        aw!(async {
+            // 对端发来的数据不算写入
+            session.push_read_data(b"OK\r\n");
+            let mut buf = [0u8; 4];
+            session.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"OK\r\n");
+            assert!(session.written().is_empty());
+
+            session.set_fail_on_read(BluetoothError::PeerDisconnected);
+            let err = session.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+
+            session.set_fail_on_write(BluetoothError::NotConnected);
+            let err = session.write(b"AT").await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+            assert!(session.written().is_empty());
+
+            // 错误只报一次
+            session.write_all(b"AT").await.unwrap();
+            session.flush().await.unwrap();
+            session.push_read_data(b"!");
+            let mut buf = [0u8; 3];
+            session.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"AT!");
+        });
+    }
+
+    #[test]
+    fn test_shutdown_closes_session() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.set_latency(Duration::from_millis(10));
+        session.connect(&device, false).unwrap();
+
+        aw!(async {
+            session.write_all(b"AT").await.unwrap();
+            // 在途的数据送完才算关好
+            session.shutdown().await.unwrap();
+            assert_eq!(session.written(), b"AT");
+            assert_eq!(session.state(), SessionState::Disconnected);
+
+            let err = session.write(b"AT").await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+
+            // 再关一次不报错
+            session.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_next_frame() {
+        let descriptor = FrameDescriptor::new(&[0xA5, 0xA5], 2, 1).unwrap();
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        aw!(async {
+            session
+                .write_all(&[0xA5, 0xA5, 0x01, 0x10, 0x00, 0xA5, 0xA5, 0x02, 0x20, 0x21])
+                .await
+                .unwrap();
+            session.flush().await.unwrap();
+            session.finish_reads();
+
+            let timeout = Duration::from_millis(50);
+            assert_eq!(
+                session.next_frame(&descriptor, timeout).await.unwrap(),
+                Some(vec![0xA5, 0xA5, 0x01, 0x10])
+            );
+            // 两帧之间的垃圾会被跳过
+            assert_eq!(
+                session.next_frame(&descriptor, timeout).await.unwrap(),
+                Some(vec![0xA5, 0xA5, 0x02, 0x20, 0x21])
+            );
+            assert_eq!(
+                session.next_frame(&descriptor, timeout).await.unwrap(),
+                None
+            );
+        });
+
+        // 没有数据也没有EOF就是超时
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        let result = aw!(session.next_frame(&descriptor, Duration::from_millis(20)));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::TimedOut {
+                operation: TimeoutOp::Read,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_into_split() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(5));
+
+        let runtime = build_runtime(ConnectAffinity::MultiThread).unwrap();
+        let session = runtime.block_on(async {
+            let (mut read, mut write) = session.into_split();
+
+            // 读先挂起来，写在另一个任务里
+            let reader = tokio::spawn(async move {
+                let mut buf = [0u8; 6];
+                read.read_exact(&mut buf).await.unwrap();
+                (read, buf)
+            });
+            let writer = tokio::spawn(async move {
+                for piece in [b"abc", b"def"] {
+                    write.write_all(piece).await.unwrap();
+                    write.flush().await.unwrap();
+                }
+                write
+            });
+
+            let write = writer.await.unwrap();
+            let (read, buf) = reader.await.unwrap();
+            assert_eq!(&buf, b"abcdef");
+            assert!(read.is_pair_of(&write));
+            read.unsplit(write)
+        });
+
+        assert_eq!(session.written(), b"abcdef");
+    }
+
+    #[test]
+    fn test_session_state() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        assert_eq!(session.state(), SessionState::Disconnected);
+
+        session.connect(&device, true).unwrap();
+        assert_eq!(session.state(), SessionState::Connected);
+
+        session.disconnect();
+        assert_eq!(session.state(), SessionState::Disconnected);
+    }
+
+    #[test]
+    fn test_mock_capabilities() {
+        let session = MockSession::new();
+        assert_eq!(
+            session.capabilities(),
+            SessionCapabilities {
+                concurrent_read_write: true,
+                supports_break: false,
+                supports_rssi: false,
+                supports_keepalive: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_services() {
+        let devices = vec![
+            BluetoothDevice::new("A".to_string(), 1),
+            BluetoothDevice::new("B".to_string(), 2),
+            BluetoothDevice::new("C".to_string(), 3),
+        ];
+
+        let result = aw!(resolve_services(devices, 2, |device| async move {
+            match device.addr() {
+                1 => Ok(vec![SPP_UUID]),
+                2 => Err(BluetoothError::ServiceNotFound),
+                _ => Ok(vec![]),
+            }
+        }));
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].0.name(), "A");
+        assert_eq!(result[0].1, vec![SPP_UUID]);
+        // 出错的设备给空列表，不影响整体
+        assert_eq!(result[1].0.name(), "B");
+        assert!(result[1].1.is_empty());
+        assert_eq!(result[2].0.name(), "C");
+    }
+
+    #[test]
+    fn test_devices_matching() {
+        let paired = vec![
+            BluetoothDevice::new("OBDII".to_string(), 1),
+            BluetoothDevice::new("Car OBD Adapter".to_string(), 2),
+            BluetoothDevice::new("Headset".to_string(), 3),
+        ];
+
+        let names = |devices: Vec<BluetoothDevice>| -> Vec<String> {
+            devices.iter().map(|device| device.name()).collect()
+        };
+        assert_eq!(
+            names(devices_matching(paired.clone(), "obd")),
+            ["OBDII", "Car OBD Adapter"]
+        );
+        assert_eq!(names(devices_matching(paired.clone(), "SET")), ["Headset"]);
+        assert!(devices_matching(paired.clone(), "printer").is_empty());
+        assert_eq!(devices_matching(paired, "").len(), 3);
+    }
+
+    #[test]
+    fn test_devices_with_service() {
+        let devices = vec![
+            BluetoothDevice::new("OBDII".to_string(), 1),
+            BluetoothDevice::new("Headset".to_string(), 2),
+            BluetoothDevice::new("Offline".to_string(), 3),
+            BluetoothDevice::new("Printer".to_string(), 4),
+        ];
+        let headset = Uuid::from_u128(0x0000_1108_0000_1000_8000_0080_5F9B_34FB);
+
+        let resolved = aw!(resolve_services(devices, 2, |device| async move {
+            match device.addr() {
+                1 => Ok(vec![SPP_UUID]),
+                2 => Ok(vec![headset]),
+                3 => Err(BluetoothError::DeviceNotFound),
+                _ => Ok(vec![headset, SPP_UUID]),
+            }
+        }));
+
+        let spp = devices_with_service(resolved, SPP_UUID);
+        let names: Vec<_> = spp.iter().map(|device| device.name()).collect();
+        assert_eq!(names, ["OBDII", "Printer"]);
+    }
+
+    #[test]
+    fn test_session_label() {
+        let mut session = MockSession::new();
+        assert_eq!(session.label(), "");
+
+        session.set_label("left-sensor".to_string());
+        assert_eq!(session.label(), "left-sensor");
+        assert!(session.summary().starts_with("[left-sensor] "));
+        assert!(format!("{:?}", session).contains("left-sensor"));
+    }
+
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn test_read_struct() {
+        use crate::common::framing::SwapBytes;
+
+        #[repr(C)]
+        #[derive(Debug, Clone, Copy, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+        struct Header {
+            magic: u16,
+            len: u16,
+        }
+
+        impl SwapBytes for Header {
+            fn swap_bytes(self) -> Self {
+                Header {
+                    magic: self.magic.swap_bytes(),
+                    len: self.len.swap_bytes(),
+                }
+            }
+        }
+
+        let mut session = MockSession::new();
+        let (big, little): (Header, Header) = aw!(async {
+            session
+                .write_all(&[0xA5, 0x5A, 0x00, 0x10, 0xA5, 0x5A, 0x00, 0x10])
+                .await
+                .unwrap();
+            let big = session.read_struct(Endianness::Big).await.unwrap();
+            let little = session.read_struct(Endianness::Little).await.unwrap();
+            (big, little)
+        });
+
+        assert_eq!(
+            big,
+            Header {
+                magic: 0xA55A,
+                len: 0x0010
+            }
+        );
+        assert_eq!(
+            little,
+            Header {
+                magic: 0x5AA5,
+                len: 0x1000
+            }
+        );
+
+        // 只剩半个结构体时对端关了
+        let result: Result<Header> = aw!(async {
+            session.write_all(&[0xA5, 0x5A]).await.unwrap();
+            session.finish_reads();
+            session.read_struct(Endianness::Big).await
+        });
+        assert!(matches!(result, Err(BluetoothError::NotConnected)));
+    }
+
+    #[test]
+    fn test_wait_for_pattern() {
+        let mut session = MockSession::new();
+        let result = aw!(async {
+            session.write_all(b"\x00junkREAREADY\r\nOK").await.unwrap();
+            session
+                .wait_for_pattern(b"READY\r\n", 64, Duration::from_secs(1))
+                .await
+        });
+        result.unwrap();
+
+        // 模式后面的数据还在
+        let mut rest = [0u8; 2];
+        aw!(session.read_exact(&mut rest)).unwrap();
+        assert_eq!(&rest, b"OK");
+
+        // 超过上限还没找到
+        let result = aw!(async {
+            session.write_all(&[0u8; 16]).await.unwrap();
+            session
+                .wait_for_pattern(b"READY", 8, Duration::from_secs(1))
+                .await
+        });
+        assert!(matches!(
+            result,
+            Err(BluetoothError::PatternNotFound { scanned: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_wait_for_pattern_split_reads() {
+        let mut session = MockSession::new();
+        // 每次读都被切短，pattern至少要分两次才能读完
+        session.set_fault_injection(Some(FaultConfig {
+            split_prob: 1.0,
+            flip_rate: 0.0,
+            drop_rate: 0.0,
+            seed: 7,
+        }));
+
+        aw!(async {
+            session.write_all(b"READY\r\nOK").await.unwrap();
+            session
+                .wait_for_pattern(b"READY\r\n", 64, Duration::from_secs(1))
+                .await
+                .unwrap();
+
+            // 前半截被当成开头留着，和后半截拼起来才算找到，不会多读
+            session.set_fault_injection(None);
+            let mut rest = [0u8; 2];
+            session.read_exact(&mut rest).await.unwrap();
+            assert_eq!(&rest, b"OK");
+        });
+    }
+
+    #[test]
+    fn test_oneshot_query() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+
+        // mock是回环的，回复就是请求本身
+        let reply = aw!(query_with(
+            &mut session,
+            &device,
+            SPP_UUID,
+            b"ping",
+            4,
+            Duration::from_secs(1)
+        ))
+        .unwrap();
+        assert_eq!(reply, b"ping");
+        assert!(!session.is_connected());
+        assert_eq!(session.written(), b"ping");
+
+        let mut session = MockSession::new();
+        session.blocked_connect(true);
+        let result = aw!(query_with(
+            &mut session,
+            &device,
+            SPP_UUID,
+            b"ping",
+            4,
+            Duration::from_millis(30)
+        ));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::TimedOut {
+                operation: TimeoutOp::Connect,
+                ..
+            })
+        ));
+        assert!(!session.is_connected());
+
+        // 连接报错时会话也是断开的，不会停在Failed
+        let mut session = MockSession::new();
+        session.missing_service(true);
+        let result = aw!(query_with(
+            &mut session,
+            &device,
+            SPP_UUID,
+            b"ping",
+            4,
+            Duration::from_secs(1)
+        ));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::ServiceNotFoundAfterPairing)
+        ));
+        assert_eq!(session.state(), SessionState::Disconnected);
+    }
+
+    #[test]
+    fn test_timeout_operations() {
+        let device = BluetoothDevice::empty();
+        let timed_out = |result: Result<()>| match result {
+            Err(BluetoothError::TimedOut { operation, .. }) => operation,
+            other => panic!("unexpected result: {:?}", other),
+        };
+
+        let mut session = MockSession::new();
+        session.blocked_pairing(true);
+        let result = session.connect_timeout(&device, true, Duration::from_millis(50));
+        assert_eq!(timed_out(result), TimeoutOp::Pairing);
+
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        let result = aw!(session.transaction(b"AT\r", 4, Duration::from_millis(30)));
+        assert_eq!(timed_out(result.map(drop)), TimeoutOp::Read);
+
+        // 写一直送不到，卡在写这一步
+        session.set_latency(Duration::from_secs(5));
+        session.set_auto_flush(true);
+        let result = aw!(session.transaction(b"AT\r", 4, Duration::from_millis(30)));
+        assert_eq!(timed_out(result.map(drop)), TimeoutOp::Write);
+
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        let result = aw!(session.wait_for_pattern(b"OK", 16, Duration::from_millis(30)));
+        assert_eq!(timed_out(result), TimeoutOp::Read);
+
+        let mut manager = SessionManager::new();
+        manager.set_close_timeout(Duration::from_millis(30));
+        session.set_latency(Duration::from_secs(5));
+        aw!(session.write_all(b"bye")).unwrap();
+        manager.insert(session);
+        match aw!(manager.close_all()) {
+            Err(BluetoothError::CloseFailed(failed)) => {
+                assert_eq!(
+                    timed_out(Err(failed.into_iter().next().unwrap().1)),
+                    TimeoutOp::Write
+                )
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_io_error_after_disconnect() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.disconnect();
+
+        // 断开以后读写要报错，而不是一直Pending
+        let mut buf = [0u8; 4];
+        let err = aw!(session.read(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+        assert!(matches!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<BluetoothError>()),
+            Some(BluetoothError::NotConnected)
+        ));
+
+        let err = aw!(session.write(b"x")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+
+        // 重新连上就恢复
+        session.connect(&device, false).unwrap();
+        aw!(session.write_all(b"x")).unwrap();
+    }
+
+    #[test]
+    fn test_check_alive() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        assert!(!aw!(session.check_alive(Duration::from_millis(50))).unwrap());
+
+        session.connect(&device, false).unwrap();
+        assert!(aw!(session.check_alive(Duration::from_millis(50))).unwrap());
+
+        // 链路断了但还没人发现，状态还是已连接
+        session.drop_link();
+        assert_eq!(session.state(), SessionState::Connected);
+        assert!(!aw!(session.check_alive(Duration::from_millis(50))).unwrap());
+        assert_eq!(session.state(), SessionState::Disconnected);
+
+        // 探测本身超时不改状态
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(100));
+        assert!(matches!(
+            aw!(session.check_alive(Duration::from_millis(10))),
+            Err(BluetoothError::TimedOut {
+                operation: TimeoutOp::Read,
+                ..
+            })
+        ));
+        assert_eq!(session.state(), SessionState::Connected);
+    }
+
+    #[test]
+    fn test_is_connected_on_trait() {
+        fn usable<S: BluetoothSppSession>(session: &S) -> bool {
+            session.is_connected()
+        }
+
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        assert!(!usable(&session));
+
+        session.connect(&device, false).unwrap();
+        assert!(usable(&session));
+
+        session.disconnect();
+        assert!(!usable(&session));
+    }
+
+    #[test]
+    fn test_validate_after_connect() {
+        let wait = Duration::from_millis(5);
+        assert!(
+            aw!(validate_link(wait, async {
+                Ok(ConnectionStatus::Connected)
+            }))
+            .is_ok()
+        );
+        assert!(matches!(
+            aw!(validate_link(wait, async {
+                Ok(ConnectionStatus::Disconnected)
+            })),
+            Err(BluetoothError::ConnectionRefused(_))
+        ));
+
+        // 不打开校验时，连上就断的设备也算连接成功
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.drop_after_connect(true);
+        session.connect(&device, false).unwrap();
+
+        session.set_validate_after_connect(Some(wait));
+        session.set_connect_diagnostics(true);
+        assert!(matches!(
+            session.connect(&device, false),
+            Err(BluetoothError::ConnectionRefused(_))
+        ));
+        assert_eq!(session.state(), SessionState::Failed);
+        let failed = session
+            .connect_diagnostics()
+            .unwrap()
+            .failed_step()
+            .unwrap();
+        assert_eq!(failed.name, "Validate");
+
+        session.drop_after_connect(false);
+        session.connect(&device, false).unwrap();
+        assert!(session.is_connected());
+    }
+
+    #[test]
+    fn test_pairing_config() {
+        assert!(PairingConfig::from(true).is_required());
+        assert!(!PairingConfig::from(false).is_required());
+
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.require_pin(Some("1234"));
+        session.set_connect_diagnostics(true);
+
+        // 只接受ConfirmOnly的默认配置配不上要PIN的设备
+        assert!(matches!(
+            session.connect(&device, true),
+            Err(BluetoothError::Pairing(PairingError::Rejected))
+        ));
+        assert_eq!(
+            session
+                .connect_diagnostics()
+                .unwrap()
+                .failed_step()
+                .unwrap()
+                .name,
+            "Pair"
+        );
+
+        let wrong = PairingConfig::with_pin(|_| Some("0000".to_string()));
+        assert!(session.connect(&device, wrong).is_err());
+
+        let right = PairingConfig::with_pin(|_| Some("1234".to_string()));
+        session.connect(&device, right).unwrap();
+        assert!(session.is_connected());
+
+        // 不配对时不会去问agent
+        session.connect(&device, PairingConfig::None).unwrap();
+
+        struct RejectAll;
+        impl PairingAgent for RejectAll {
+            fn confirm(&self, _device: &BluetoothDevice) -> bool {
+                false
+            }
+        }
+        session.require_pin(None);
+        let result = session.connect(&device, PairingConfig::with_agent(RejectAll));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::Pairing(PairingError::Rejected))
+        ));
+    }
+
+    #[test]
+    fn test_paired_without_service() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.missing_service(true);
+
+        // 配对成功但没有服务，和单纯找不到服务区分开
+        let err = session.connect(&device, true).unwrap_err();
+        assert!(matches!(err, BluetoothError::ServiceNotFoundAfterPairing));
+        assert!(err.to_string().contains("paired successfully"));
+        assert_eq!(session.state(), SessionState::Failed);
+
+        assert!(matches!(
+            session.connect(&device, false),
+            Err(BluetoothError::ServiceNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_connect_diagnostics() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        assert!(session.connect_diagnostics().is_none());
+        session.set_connect_diagnostics(true);
+
+        // 找不到服务，失败了也能拿到时间线
+        session.missing_service(true);
+        assert!(matches!(
+            session.connect(&device, true),
+            Err(BluetoothError::ServiceNotFoundAfterPairing)
+        ));
+        let diagnostics = session.connect_diagnostics().unwrap();
+        let names: Vec<_> = diagnostics.steps().iter().map(|step| step.name).collect();
+        assert_eq!(names, ["FindAll", "Pair", "GetServices"]);
+        let last = diagnostics.steps().last().unwrap();
+        assert_eq!(last.outcome, StepOutcome::Found(0));
+        assert!(last.to_string().ends_with("0 found"));
+
+        // 卡在配对被超时打断，记成配对超时
+        session.missing_service(false);
+        session.blocked_pairing(true);
+        assert!(
+            session
+                .connect_timeout(&device, true, Duration::from_millis(20))
+                .is_err()
+        );
+        let diagnostics = session.connect_diagnostics().unwrap();
+        let failed = diagnostics.failed_step().unwrap();
+        assert_eq!(failed.name, "Pair");
+        assert_eq!(failed.outcome, StepOutcome::TimedOut);
+        assert_eq!(failed.to_string(), "Pair: timeout");
+
+        // 下次连接重新记
+        session.blocked_pairing(false);
+        session.connect(&device, false).unwrap();
+        let diagnostics = session.connect_diagnostics().unwrap();
+        assert_eq!(diagnostics.steps().len(), 3);
+        assert!(diagnostics.failed_step().is_none());
+        assert_eq!(diagnostics.to_string().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_retry_jitter() {
+        let delay = Duration::from_millis(100);
+
+        let mut jitter = Jitter::new(0.2, 7);
+        let delays: Vec<_> = (0..50).map(|_| jitter.apply(delay)).collect();
+        assert!(delays.iter().all(|d| *d >= Duration::from_millis(80)));
+        assert!(delays.iter().all(|d| *d <= Duration::from_millis(120)));
+        // 真的散开了，不是一直同一个值
+        assert!(delays.iter().any(|d| *d < delay));
+        assert!(delays.iter().any(|d| *d > delay));
+
+        // 同一个种子同样的序列
+        let mut again = Jitter::new(0.2, 7);
+        let repeated: Vec<_> = (0..50).map(|_| again.apply(delay)).collect();
+        assert_eq!(delays, repeated);
+
+        // 不抖动时原样返回
+        assert_eq!(Jitter::new(0.0, 7).apply(delay), delay);
+    }
+
+    #[test]
+    fn test_connect_with_cancel() {
+        let device = BluetoothDevice::empty();
+        let cancel_later = |cancel: &tokio_util::sync::CancellationToken| {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(30));
+                cancel.cancel();
+            })
+        };
+
+        // 卡在查设备
+        let mut session = MockSession::new();
+        session.blocked_connect(true);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let canceller = cancel_later(&cancel);
+        let result = aw!(session.connect_with_cancel(&device, SPP_UUID, false, cancel));
+        canceller.join().unwrap();
+        assert!(matches!(result, Err(BluetoothError::Cancelled)));
+        assert_eq!(session.state(), SessionState::Disconnected);
+
+        // 卡在配对
+        let mut session = MockSession::new();
+        session.blocked_pairing(true);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let canceller = cancel_later(&cancel);
+        let result = aw!(session.connect_with_cancel(&device, SPP_UUID, true, cancel));
+        canceller.join().unwrap();
+        assert!(matches!(result, Err(BluetoothError::Cancelled)));
+        assert!(!session.is_connected());
+
+        // 已经取消了就不会连上
+        let cancel = tokio_util::sync::CancellationToken::new();
+        cancel.cancel();
+        let result = aw!(session.connect_with_cancel(&device, SPP_UUID, false, cancel));
+        assert!(matches!(result, Err(BluetoothError::Cancelled)));
+
+        // 没取消就和普通连接一样
+        session.blocked_pairing(false);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        aw!(session.connect_with_cancel(&device, SPP_UUID, true, cancel)).unwrap();
+        assert!(session.is_connected());
+    }
+
+    #[test]
+    fn test_timeout_vs_cancel() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.blocked_connect(true);
+
+        aw!(async {
+            // 只有取消
+            let cancel = tokio_util::sync::CancellationToken::new();
+            cancel.cancel();
+            let result = session
+                .connect_by_uuid_timeout_or_cancel(
+                    &device,
+                    SPP_UUID,
+                    false,
+                    Duration::from_secs(10),
+                    &cancel,
+                )
+                .await;
+            assert!(matches!(result, Err(BluetoothError::Cancelled)));
+
+            // 只有超时
+            let cancel = tokio_util::sync::CancellationToken::new();
+            let result = session
+                .connect_by_uuid_timeout_or_cancel(
+                    &device,
+                    SPP_UUID,
+                    false,
+                    Duration::from_millis(20),
+                    &cancel,
+                )
+                .await;
+            assert!(matches!(
+                result,
+                Err(BluetoothError::TimedOut {
+                    operation: TimeoutOp::Connect,
+                    ..
+                })
+            ));
+
+            // 两个都已经到了，每次都是超时赢
+            for _ in 0..20 {
+                let cancel = tokio_util::sync::CancellationToken::new();
+                cancel.cancel();
+                let result = session
+                    .connect_by_uuid_timeout_or_cancel(
+                        &device,
+                        SPP_UUID,
+                        false,
+                        Duration::ZERO,
+                        &cancel,
+                    )
+                    .await;
+                assert!(matches!(result, Err(BluetoothError::TimedOut { .. })));
+            }
+        });
+
+        // 取消交给会话自己处理，卡住的那一步跑完才停，诊断里记得到
+        let mut session = MockSession::new();
+        session.set_connect_diagnostics(true);
+        session.blocked_pairing(true);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let canceller = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(30));
+                cancel.cancel();
+            })
+        };
+        let result = aw!(session.connect_by_uuid_timeout_or_cancel(
+            &device,
+            SPP_UUID,
+            true,
+            Duration::from_secs(10),
+            &cancel,
+        ));
+        canceller.join().unwrap();
+        assert!(matches!(result, Err(BluetoothError::Cancelled)));
+        let steps = session.connect_diagnostics().unwrap().steps();
+        assert_eq!(steps.last().unwrap().name, "Pair");
+        assert_eq!(session.state(), SessionState::Disconnected);
+
+        // 超时扔掉的connect不会把令牌留给下一次连接
+        session.blocked_pairing(false);
+        session.blocked_connect(true);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let result = aw!(session.connect_by_uuid_timeout_or_cancel(
+            &device,
+            SPP_UUID,
+            false,
+            Duration::from_millis(20),
+            &cancel,
+        ));
+        assert!(matches!(result, Err(BluetoothError::TimedOut { .. })));
+        cancel.cancel();
+        session.blocked_connect(false);
+        aw!(session.connect_by_uuid_async(&device, SPP_UUID, false)).unwrap();
+        assert!(session.is_connected());
+    }
+
+    #[test]
+    fn test_reconnect_predicate() {
+        let device = BluetoothDevice::empty();
+        let mut session = ReconnectingSession::new(MockSession::new(), device, SPP_UUID, false)
+            .with_retry_delay(Duration::ZERO);
+
+        // 配对问题重连也没用，直接交回来，不去碰连接
+        let result = aw!(session.recover(BluetoothError::DeviceNotPairing));
+        assert!(matches!(result, Err(BluetoothError::DeviceNotPairing)));
+        assert_eq!(session.session().state(), SessionState::Disconnected);
+
+        // 断线会重连
+        aw!(session.recover(BluetoothError::NotConnected)).unwrap();
+        assert_eq!(session.session().state(), SessionState::Connected);
+
+        // 写的时候发现断了，重连以后重写；旧连接的数据在重连时清掉了，读到的是重写的那份
+        session.session_mut().disconnect();
+        aw!(session.write_all(b"hi")).unwrap();
+        let mut buf = [0u8; 2];
+        assert_eq!(aw!(session.read(&mut buf)).unwrap(), 2);
+        assert_eq!(&buf, b"hi");
+
+        // 自定义判断：什么都不重连
+        let mut session = session.with_should_reconnect(|_| false);
+        session.session_mut().disconnect();
+        let result = aw!(session.read(&mut buf));
+        assert!(matches!(result, Err(BluetoothError::NotConnected)));
+    }
+
+    #[test]
+    fn test_reconnect_state() {
+        let device = BluetoothDevice::empty();
+        let mut session = ReconnectingSession::new(MockSession::new(), device, SPP_UUID, false)
+            .with_retry_delay(Duration::ZERO);
+        assert_eq!(session.state(), SessionState::Disconnected);
+
+        // 重连卡住、被外面的超时打断，还算在重连
+        session.session_mut().blocked_connect(true);
+        let result = aw!(async {
+            time::timeout(
+                Duration::from_millis(20),
+                session.recover(BluetoothError::NotConnected),
+            )
+            .await
+        });
+        assert!(result.is_err());
+        assert_eq!(session.state(), SessionState::Reconnecting);
+
+        session.session_mut().blocked_connect(false);
+        aw!(session.recover(BluetoothError::NotConnected)).unwrap();
+        assert_eq!(session.state(), SessionState::Connected);
+
+        // 全部失败以后就不再是重连中了，交回里面会话的状态
+        let mut session = session.with_max_attempts(2);
+        session.session_mut().missing_service(true);
+        let result = aw!(session.recover(BluetoothError::NotConnected));
+        assert!(result.is_err());
+        assert_ne!(session.state(), SessionState::Reconnecting);
+        assert_eq!(session.state(), session.session().state());
+    }
+
+    #[test]
+    fn test_read_until_deadline() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(200));
+
+        let mut buf = [0u8; 4];
+        let result = aw!(async {
+            session.write_all(b"slow").await.unwrap();
+            let deadline = std::time::Instant::now() + Duration::from_millis(50);
+            session.read_until_deadline(&mut buf, deadline).await
+        });
+        match result {
+            Err(BluetoothError::TimedOut {
+                duration,
+                operation: TimeoutOp::Read,
+            }) => {
+                assert!(duration <= Duration::from_millis(50))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // 已经过了截止时间就不读了
+        let past = std::time::Instant::now() - Duration::from_millis(1);
+        let result = aw!(session.read_until_deadline(&mut buf, past));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::TimedOut { duration, operation: TimeoutOp::Read }) if duration.is_zero()
+        ));
+
+        // 读出错时报会话原来的错误
+        session.set_fail_on_read(BluetoothError::PeerDisconnected);
+        let deadline = std::time::Instant::now() + Duration::from_millis(50);
+        let result = aw!(session.read_until_deadline(&mut buf, deadline));
+        assert!(matches!(result, Err(BluetoothError::PeerDisconnected)));
+    }
+
+    #[test]
+    fn test_fault_injection_reproducible() {
+        let staged: Vec<u8> = (0..=255).collect();
+        let config = FaultConfig {
+            split_prob: 0.5,
+            flip_rate: 0.05,
+            drop_rate: 0.05,
+            seed: 42,
+        };
+
+        let run = || {
+            let mut session = MockSession::new();
+            session.set_fault_injection(Some(config));
+            aw!(async {
+                session.write_all(&staged).await.unwrap();
+                let mut reads = Vec::new();
+                let mut received = Vec::new();
+                while received.len() < 200 {
+                    let mut buf = [0u8; 64];
+                    let n = session.read(&mut buf).await.unwrap();
+                    reads.push(n);
+                    received.extend_from_slice(&buf[..n]);
+                }
+                (reads, received)
+            })
+        };
+
+        let (reads, received) = run();
+        // 同一个种子结果完全一样
+        assert_eq!(run(), (reads.clone(), received.clone()));
+        // 确实被切短、改动过
+        assert!(reads.iter().any(|&n| n < 64));
+        assert_ne!(&received[..], &staged[..received.len()]);
+    }
+
+    #[test]
+    fn test_session_ext_timeouts() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        let mut buf = [0u8; 4];
+        let result = aw!(session.read_timeout(&mut buf, Duration::from_millis(20)));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::TimedOut {
+                operation: TimeoutOp::Read,
+                ..
+            })
+        ));
+
+        assert_eq!(
+            aw!(session.write_timeout(b"hi", Duration::from_millis(20))).unwrap(),
+            2
+        );
+        aw!(session.flush()).unwrap();
+        assert_eq!(
+            aw!(session.read_timeout(&mut buf, Duration::from_millis(20))).unwrap(),
+            2
+        );
+        assert_eq!(&buf[..2], b"hi");
+
+        // 会话报的错原样交出来，不变成RuntimeError
+        session.set_fail_on_read(BluetoothError::PeerDisconnected);
+        let result = aw!(session.read_timeout(&mut buf, Duration::from_millis(20)));
+        assert!(matches!(result, Err(BluetoothError::PeerDisconnected)));
+        session.set_fail_on_write(BluetoothError::NotConnected);
+        let result = aw!(session.write_timeout(b"hi", Duration::from_millis(20)));
+        assert!(matches!(result, Err(BluetoothError::NotConnected)));
+
+        // 不是会话也能用，比如普通的duplex管道
+        let (mut client, _server) = tokio::io::duplex(1);
+        aw!(client.write_timeout(b"x", Duration::from_millis(20))).unwrap();
+        let result = aw!(client.write_timeout(b"y", Duration::from_millis(20)));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::TimedOut {
+                operation: TimeoutOp::Write,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_effective_io_sizes() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        assert_eq!(session.effective_io_sizes(), IoSizes::default());
+
+        session.set_max_write_chunk(Some(4));
+        assert_eq!(session.effective_io_sizes().write_chunk, Some(4));
+
+        // 一次写只收下前4个字节，write_all会接着写完
+        session.connect(&device, false).unwrap();
+        assert_eq!(aw!(session.write(b"abcdef")).unwrap(), 4);
+        aw!(session.write_all(b"gh")).unwrap();
+        assert_eq!(session.written(), b"abcdgh");
+    }
+
+    #[test]
+    fn test_write_chunked() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(10));
+
+        let data: Vec<u8> = (0..10).collect();
+        let mut acks = Vec::new();
+        let start = std::time::Instant::now();
+        aw!(session.write_chunked(&data, 4, |sent| acks.push(sent))).unwrap();
+
+        assert_eq!(acks, vec![4, 8, 10]);
+        // 每块都等到送达才发下一块，三块至少三倍延迟
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(session.written(), data.as_slice());
+    }
+
+    #[test]
+    fn test_send_with_crc() {
+        // 标准校验值："123456789"
+        assert_eq!(checksum(ChecksumKind::Crc32, b"123456789"), 0xCBF4_3926);
+        assert_eq!(checksum(ChecksumKind::Crc16, b"123456789"), 0x29B1);
+
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let crc = aw!(session.send_with_crc(&data, 64, ChecksumKind::Crc32)).unwrap();
+        assert_eq!(crc, checksum(ChecksumKind::Crc32, &data));
+        assert_eq!(session.written(), data.as_slice());
+
+        let crc = aw!(session.send_with_crc(b"123456789", 2, ChecksumKind::Crc16)).unwrap();
+        assert_eq!(crc, 0x29B1);
+    }
+
+    #[test]
+    fn test_write_vectored() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        let header = [0xA5, 0x02];
+        let body = [1, 2, 3, 4];
+        let written = aw!(session.write_vectored(&[&header, &body])).unwrap();
+
+        assert_eq!(written, 6);
+        assert_eq!(session.written(), &[0xA5, 0x02, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_idle_since() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+
+        aw!(session.write_all(b"ping")).unwrap();
+        assert!(session.idle_since() < Duration::from_millis(20));
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(session.idle_since() >= Duration::from_millis(50));
+        assert_eq!(session.stats().bytes_written(), 4);
+    }
+
+    #[test]
+    fn test_made_progress_recently() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        assert_eq!(session.last_progress(), None);
+        assert!(!session.made_progress_recently(Duration::from_secs(1)));
+
+        aw!(session.write_all(b"ping")).unwrap();
+        assert!(session.made_progress_recently(Duration::from_millis(30)));
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!session.made_progress_recently(Duration::from_millis(30)));
+
+        let mut buf = [0u8; 4];
+        aw!(session.read_exact(&mut buf)).unwrap();
+        assert!(session.made_progress_recently(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn test_reap_idle() {
+        let mut manager = SessionManager::new();
+        for addr in 1..=2 {
+            let mut session = MockSession::new();
+            session
+                .connect(&BluetoothDevice::new(String::new(), addr), false)
+                .unwrap();
+            manager.insert(session);
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+        let busy = BluetoothDevice::new(String::new(), 2);
+        aw!(manager.get_mut(&busy).unwrap().write_all(b"x")).unwrap();
+
+        let reaped = manager.reap_idle(Duration::from_millis(30));
+        assert_eq!(reaped, vec![BluetoothDevice::new(String::new(), 1)]);
+        assert_eq!(manager.len(), 1);
+        assert!(manager.get(&busy).unwrap().is_connected());
+    }
+
+    #[test]
+    fn test_shutdown_all() {
+        let mut manager = SessionManager::new();
+        for addr in 1..=3 {
+            let mut session = MockSession::new();
+            session
+                .connect(&BluetoothDevice::new(String::new(), addr), false)
+                .unwrap();
+            session.set_latency(Duration::from_millis(10));
+            manager.insert(session);
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            for session in manager.sessions_mut() {
+                session.write_all(b"bye").await.unwrap();
+            }
+
+            manager.close_all().await.unwrap();
+            assert!(manager.iter().all(|session| !session.is_connected()));
+            // close会等在途的写送达
+            assert!(manager.iter().all(|session| session.written() == b"bye"));
+
+            manager.shutdown_all().await.unwrap();
+        });
+        assert!(manager.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_defaults() {
+        let mut session = MockSession::new();
+
+        let result = aw!(session.send_break(Duration::from_millis(100)));
+        assert!(matches!(
+            result,
+            Err(BluetoothError::Unsupported("send_break"))
+        ));
+
+        let result = aw!(session.set_radio_state(false));
+        assert!(matches!(result, Err(BluetoothError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_ping_latency() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.connect(&device, false).unwrap();
+        session.set_latency(Duration::from_millis(20));
+
+        let rtt = aw!(session.ping(&[0x55, 0xAA], 2, Duration::from_secs(1))).unwrap();
+        assert!(rtt >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_detect_endianness() {
+        // 长度字段是16 00，按小端是22，正好是头后面的数据长度
+        let sample = [
+            0xA5, 0xA5, 0x02, 0x00, 0x16, 0x00, 0x1D, 0x4D, 0x01, 0x01, 0x03, 0x00, 0x01, 0x00,
+            0x00, 0x02, 0x02, 0x00, 0x00, 0xFC, 0x03, 0x02, 0x00, 0x20, 0x00, 0x04, 0x02, 0x00,
+            0x10, 0x27,
+        ];
+        let descriptor = FrameDescriptor::new(&[0xA5, 0xA5], 4, 2)
+            .unwrap()
+            .checksum_len(2);
+
+        assert_eq!(
+            detect_endianness(&sample, &descriptor),
+            Some(Endianness::Little)
+        );
+
+        // 截掉一个字节之后哪种都对不上
+        assert_eq!(detect_endianness(&sample[..29], &descriptor), None);
+
+        // 魔数被清空的描述不去猜
+        let mut empty = descriptor.clone();
+        empty.magic.clear();
+        assert_eq!(detect_endianness(&sample, &empty), None);
+
+        // 8字节长度字段解出来是usize::MAX，算总长时不能溢出
+        let descriptor = FrameDescriptor::new(&[0xA5], 1, 8).unwrap();
+        let sample = [0xA5, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xAA];
+        assert_eq!(detect_endianness(&sample, &descriptor), None);
+    }
+
+    #[test]
+    fn test_connect_single_thread_affinity() {
+        let device = BluetoothDevice::empty();
+        let mut session = MockSession::new();
+        session.set_connect_affinity(ConnectAffinity::SingleThread);
+
+        session.connect(&device, false).unwrap();
+        assert_eq!(session.state(), SessionState::Connected);
+
+        session
+            .connect_timeout(&device, false, Duration::from_secs(1))
+            .unwrap();
+        assert!(session.is_connected());
+    }
+
+    #[test]
+    fn test_blocking_connect_worker_thread() {
+        let name = || std::thread::current().name().map(str::to_string);
+
+        // 两种套间都在专门的工作线程上跑，调用线程不动
+        for affinity in [ConnectAffinity::MultiThread, ConnectAffinity::SingleThread] {
+            let worker = block_on(affinity, async { name() }).unwrap();
+            assert_eq!(worker.as_deref(), Some("bluetooth-connect"));
+        }
+
+        // 在多线程runtime里也一样，套间设置不会被忽略
+        let rt = build_runtime(ConnectAffinity::MultiThread).unwrap();
+        rt.block_on(async {
+            let worker = block_on(ConnectAffinity::SingleThread, async { name() }).unwrap();
+            assert_eq!(worker.as_deref(), Some("bluetooth-connect"));
+        });
+    }
+
+    #[test]
+    fn test_device_tracker_events() {
+        let mut tracker = DeviceTracker::default();
+        let info = DeviceInfo::observed(BluetoothDevice::new("OBDII".to_string(), 1), None);
+
+        match tracker.added("dev-1", info) {
+            DeviceEvent::Added(info) => assert_eq!(info.device.addr(), 1),
+            _ => panic!("expected Added"),
+        }
+
+        let update = DeviceUpdate {
+            connected: Some(true),
+            rssi: Some(-60),
+            ..Default::default()
+        };
+        match tracker.updated("dev-1", update) {
+            Some(DeviceEvent::Updated(info)) => {
+                assert_eq!(info.device.name(), "OBDII");
+                assert!(info.connected);
+                assert_eq!(info.rssi, Some(-60));
+            }
+            _ => panic!("expected Updated"),
+        }
+
+        // 没见过的id不产生事件
+        assert!(tracker.updated("dev-2", DeviceUpdate::default()).is_none());
+        assert!(tracker.removed("dev-2").is_none());
+
+        match tracker.removed("dev-1") {
+            Some(DeviceEvent::Removed(info)) => assert!(info.connected),
+            _ => panic!("expected Removed"),
+        }
+        assert!(tracker.removed("dev-1").is_none());
+    }
+
+    #[test]
+    fn test_discovery_events() {
+        let obd = BluetoothDevice::new("OBDII".to_string(), 1);
+        let headset = BluetoothDevice::new("Headset".to_string(), 2);
+        let events = vec![
+            DeviceEvent::Added(DeviceInfo::observed(obd.clone(), None)),
+            DeviceEvent::Added(DeviceInfo::observed(headset.clone(), None)),
+            DeviceEvent::Updated(DeviceInfo::observed(obd.clone(), None)),
+            DeviceEvent::Removed(DeviceInfo::observed(obd.clone(), None)),
+        ];
+
+        // 属性变化被过滤掉，消失只带地址
+        let mapped: Vec<_> = aw!(futures::StreamExt::collect(discovery_events(
+            futures::stream::iter(events)
+        )));
+        assert_eq!(
+            mapped,
+            [
+                DiscoveryEvent::Added(obd),
+                DiscoveryEvent::Added(headset),
+                DiscoveryEvent::Removed(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_device_entries() {
+        let entries = vec![
+            RawDeviceEntry {
+                name: "OBDII".to_string(),
+                address: Some("00:02:B0:57:7D:D6".to_string()),
+                properties: DeviceUpdate {
+                    paired: Some(true),
+                    rssi: Some(-70),
+                    ..DeviceUpdate::default()
+                },
+                cod_major: Some(0x1F),
+                cod_minor: Some(0),
+            },
+            // 没地址的、地址不对的都跳过
+            RawDeviceEntry {
+                name: "Headset".to_string(),
+                ..RawDeviceEntry::default()
+            },
+            RawDeviceEntry {
+                name: "Broken".to_string(),
+                address: Some("not a mac".to_string()),
+                ..RawDeviceEntry::default()
+            },
+            RawDeviceEntry {
+                address: Some("D0:AE:05:05:1A:22".to_string()),
+                ..RawDeviceEntry::default()
+            },
+        ];
+
+        let devices = map_device_entries(entries);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device.name(), "OBDII");
+        assert_eq!(devices[0].device.addr(), 11548458454);
+        assert!(devices[0].paired);
+        assert!(!devices[0].connected);
+        assert_eq!(devices[0].rssi, Some(-70));
+        assert_eq!(devices[0].class_of_device, Some(0x1F00));
+        assert!(devices[0].last_seen.is_some());
+
+        assert_eq!(devices[1].device.addr_string(), "D0:AE:05:05:1A:22");
+        assert_eq!(devices[1].class_of_device, None);
+    }
+
+    #[test]
+    fn test_collect_devices_limited() {
+        let infos: Vec<DeviceInfo> = (1..=5)
+            .map(|addr| DeviceInfo::observed(BluetoothDevice::new(String::new(), addr), None))
+            .collect();
+
+        // 同一个设备的更新不算新设备，凑够2个就停，后面的事件不再消费
+        let mut events = futures::stream::iter(vec![
+            DeviceEvent::Added(infos[0].clone()),
+            DeviceEvent::Updated(infos[0].clone()),
+            DeviceEvent::Added(infos[1].clone()),
+            DeviceEvent::Added(infos[2].clone()),
+            DeviceEvent::Added(infos[3].clone()),
+        ]);
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let devices = aw!(collect_devices_limited(
+            &mut events,
+            2,
+            Duration::from_secs(10),
+            cancel.clone()
+        ));
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].device.addr(), 2);
+        assert_eq!(aw!(futures::StreamExt::count(events)), 2);
+
+        // 流一直不结束也不用等到超时
+        let start = std::time::Instant::now();
+        let events = futures::StreamExt::chain(
+            futures::stream::iter(vec![DeviceEvent::Added(infos[4].clone())]),
+            futures::stream::pending(),
+        );
+        let devices = aw!(collect_devices_limited(
+            events,
+            1,
+            Duration::from_secs(10),
+            cancel
+        ));
+        assert_eq!(devices.len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_collect_devices_cancel() {
+        let cancel = tokio_util::sync::CancellationToken::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let events = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+
+        let start = std::time::Instant::now();
+        let devices = aw!(async {
+            let stop = cancel.clone();
+            tokio::spawn(async move {
+                let info = DeviceInfo::observed(BluetoothDevice::new("A".to_string(), 1), None);
+                sender.send(DeviceEvent::Added(info)).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+
+                // 取消之后再来的事件不应该被收进去
+                stop.cancel();
+                let info = DeviceInfo::observed(BluetoothDevice::new("B".to_string(), 2), None);
+                let _ = sender.send(DeviceEvent::Added(info));
+            });
+
+            collect_devices(events, Duration::from_secs(10), cancel).await
+        });
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device.name(), "A");
+    }
+
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let mut pool: BufferPool<Vec<u8>> = BufferPool::new(2);
+
+        for _ in 0..100 {
+            let buffer = pool.take(|| Ok::<_, ()>(Vec::with_capacity(4096))).unwrap();
+            pool.put(buffer);
+        }
+        assert_eq!(pool.created(), 1);
+
+        // 同时拿着的多了才会新建，超过上限的还回来会被丢掉
+        let held: Vec<_> = (0..3)
+            .map(|_| pool.take(|| Ok::<_, ()>(Vec::new())).unwrap())
+            .collect();
+        assert_eq!(pool.created(), 3);
+        held.into_iter().for_each(|buffer| pool.put(buffer));
+        (0..3).for_each(|_| {
+            pool.take(|| Ok::<_, ()>(Vec::new())).unwrap();
+        });
+        assert_eq!(pool.created(), 4);
+    }
+
+    #[test]
+    fn test_in_async_context() {
+        assert!(!in_async_context());
+        assert!(aw!(async { in_async_context() }));
+
+        let rt = build_runtime(ConnectAffinity::SingleThread).unwrap();
+        assert!(rt.block_on(async { in_async_context() }));
+        assert!(!in_async_context());
+    }
+
+    #[test]
+    fn test_blocking_connect_inside_runtime() {
+        let device = BluetoothDevice::empty();
+
+        // 多线程runtime里调同步connect不会panic
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut session = MockSession::new();
+            session.connect(&device, false).unwrap();
+            assert!(session.is_connected());
+        });
+
+        // 单线程runtime里没法阻塞，报错而不是panic
+        aw!(async {
+            let mut session = MockSession::new();
+            assert!(matches!(
+                session.connect(&device, false),
+                Err(BluetoothError::RuntimeError(_))
+            ));
+        });
+
+        // runtime外面反复调用共用同一个runtime
+        let mut session = MockSession::new();
+        for _ in 0..3 {
+            session.connect(&device, false).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parse_uuid() {
+        let forms = [
+            "00001101-0000-1000-8000-00805F9B34FB",
+            "00001101-0000-1000-8000-00805f9b34fb",
+            "{00001101-0000-1000-8000-00805F9B34FB}",
+            "0000110100001000800000805F9B34FB",
+            " 1101 ",
+            "0x1101",
+            "00001101",
+        ];
+        for form in forms {
+            assert_eq!(parse_uuid(form).unwrap(), SPP_UUID, "{}", form);
+        }
+
+        for bad in ["", "110", "0x", "1101-xyz", "00001101-0000-1000-8000"] {
+            assert!(matches!(
+                parse_uuid(bad),
+                Err(BluetoothError::InvalidUuid(input)) if input == bad
+            ));
         }
     }
 }
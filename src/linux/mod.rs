@@ -0,0 +1,154 @@
+pub mod pair;
+pub mod session;
+pub(crate) mod socket;
+pub mod utils;
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::{Read, Write};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+    use tokio_test::block_on;
+
+    use crate::{
+        BluetoothError, BluetoothSppSession, SessionState,
+        common::{
+            device::BluetoothDevice,
+            runtime::{self, ConnectAffinity},
+        },
+        linux::{
+            session::BluezSession,
+            socket::{SockaddrL2, SockaddrRc, bdaddr, l2cap_addr},
+            utils::{bluez_connect_error, bluez_write_error, is_peer_disconnect},
+        },
+    };
+
+    #[test]
+    fn test_bdaddr_little_endian() {
+        // 00:02:B0:57:7D:D6，内核里最低字节在前
+        assert_eq!(bdaddr(0x0002B0577DD6), [0xD6, 0x7D, 0x57, 0xB0, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn test_sockaddr_layout() {
+        // 和内核的sockaddr_rc、sockaddr_l2一样大
+        assert_eq!(std::mem::size_of::<SockaddrRc>(), 10);
+        assert_eq!(std::mem::size_of::<SockaddrL2>(), 14);
+
+        let addr = l2cap_addr(0x0002B0577DD6, 1);
+        let bytes: [u8; 14] = unsafe { std::mem::transmute(addr) };
+        assert_eq!(&bytes[2..4], &[0x01, 0x00]);
+        assert_eq!(&bytes[4..10], &bdaddr(0x0002B0577DD6));
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let errno = std::io::Error::from_raw_os_error;
+
+        assert!(matches!(
+            bluez_connect_error(errno(libc::EHOSTDOWN)),
+            BluetoothError::DeviceNotFound
+        ));
+        assert!(matches!(
+            bluez_connect_error(errno(libc::EBUSY)),
+            BluetoothError::ConnectionRefused(_)
+        ));
+        assert!(matches!(
+            bluez_connect_error(errno(libc::EAFNOSUPPORT)),
+            BluetoothError::Unsupported(_)
+        ));
+
+        assert!(is_peer_disconnect(&errno(libc::ECONNRESET)));
+        assert_eq!(
+            bluez_write_error(errno(libc::EPIPE)).kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            bluez_write_error(errno(libc::EIO)).raw_os_error(),
+            Some(libc::EIO)
+        );
+    }
+
+    #[test]
+    fn test_unconnected_session() {
+        let mut session = BluezSession::new();
+        assert_eq!(session.state(), SessionState::Disconnected);
+        assert!(!session.is_connected());
+        assert_eq!(session.channel(), None);
+
+        block_on(async {
+            let mut buf = [0u8; 4];
+            let err = session.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+
+            let err = session.write(b"AT").await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+        });
+    }
+
+    #[test]
+    fn test_connect_failure_marks_failed() {
+        // 没有蓝牙时socket都建不起来，有蓝牙时这个地址也连不上，不管报什么错状态都要是Failed
+        let device = BluetoothDevice::new("none".to_string(), 0x0002B0577DD6);
+        let mut session = BluezSession::new();
+
+        let result = block_on(session.connect_by_channel_async(&device, 1, false));
+        assert!(result.is_err());
+        assert_eq!(session.state(), SessionState::Failed);
+        assert!(!session.is_connected());
+    }
+
+    #[test]
+    fn test_single_thread_connect_keeps_socket_usable() {
+        // SingleThread的同步connect跑完runtime就没了，连上的socket要能在调用方的runtime里继续读写
+        let mut session = BluezSession::new();
+        let mut peer = runtime::block_on(ConnectAffinity::SingleThread, async {
+            // 和connect_rfcomm一样，在连接用的runtime上连好再把fd拿出来
+            let (local, peer) = UnixStream::pair().unwrap();
+            session.attach(local.into_std().unwrap().into(), 1);
+            peer.into_std().unwrap()
+        })
+        .unwrap();
+        peer.set_nonblocking(false).unwrap();
+
+        block_on(async {
+            session.write_all(b"AT").await.unwrap();
+            let mut buf = [0u8; 2];
+            peer.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"AT");
+
+            peer.write_all(b"OK").unwrap();
+            session.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"OK");
+        });
+        assert_eq!(session.channel(), Some(1));
+    }
+
+    #[test]
+    fn test_read_after_peer_closed() {
+        let mut session = BluezSession::new();
+        let (local, peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        local.set_nonblocking(true).unwrap();
+        session.attach(local.into(), 1);
+        drop(peer);
+
+        // 对端关了以后每次读都是EOF，不会变成NotConnected
+        block_on(async {
+            let mut buf = [0u8; 4];
+            assert_eq!(session.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(session.read(&mut buf).await.unwrap(), 0);
+        });
+        assert_eq!(session.state(), SessionState::Disconnected);
+
+        // 主动断开以后就是没连接了
+        session.disconnect();
+        block_on(async {
+            let err = session.read(&mut [0u8; 4]).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+        });
+    }
+}
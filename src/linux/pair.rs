@@ -0,0 +1,255 @@
+use std::sync::Arc;
+
+use zbus::{
+    Connection, Proxy,
+    fdo::ObjectManagerProxy,
+    zvariant::{ObjectPath, OwnedObjectPath},
+};
+
+use crate::{
+    BluetoothError,
+    common::{
+        device::BluetoothDevice,
+        pairing::{PairingAgent, PairingBackend, PairingError},
+    },
+};
+
+const BLUEZ_SERVICE: &str = "org.bluez";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const AGENT_MANAGER_INTERFACE: &str = "org.bluez.AgentManager1";
+// 每次配对都开自己的D-Bus连接，路径固定也不会和别的会话冲突
+const AGENT_PATH: &str = "/bluetooth_classic/agent";
+// 能显示也能输入，BlueZ会把各种配对方式都转给agent
+const AGENT_CAPABILITY: &str = "KeyboardDisplay";
+
+// 回给BlueZ的错误，名字要是org.bluez.Error.Rejected它才认
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.bluez.Error")]
+enum AgentError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Rejected(String),
+}
+
+fn rejected() -> AgentError {
+    AgentError::Rejected("rejected by agent".to_string())
+}
+
+// 把BlueZ的org.bluez.Agent1请求转给`PairingAgent`
+struct BluezAgent {
+    agent: Arc<dyn PairingAgent>,
+    device: BluetoothDevice,
+}
+
+#[zbus::interface(name = "org.bluez.Agent1")]
+impl BluezAgent {
+    fn release(&self) {}
+
+    fn request_pin_code(&self, _device: ObjectPath<'_>) -> Result<String, AgentError> {
+        self.agent.provide_pin(&self.device).ok_or_else(rejected)
+    }
+
+    fn display_pin_code(&self, _device: ObjectPath<'_>, pincode: String) {
+        self.agent.display_pin(&self.device, &pincode);
+    }
+
+    // 要数字passkey的设备也走provide_pin，给的不是数字就拒绝
+    fn request_passkey(&self, _device: ObjectPath<'_>) -> Result<u32, AgentError> {
+        self.agent
+            .provide_pin(&self.device)
+            .and_then(|pin| pin.trim().parse().ok())
+            .ok_or_else(rejected)
+    }
+
+    // 对端每按一个键都会再调一次，只在开始时显示
+    fn display_passkey(&self, _device: ObjectPath<'_>, passkey: u32, entered: u16) {
+        if entered == 0 {
+            self.agent
+                .display_pin(&self.device, &format!("{:06}", passkey));
+        }
+    }
+
+    fn request_confirmation(
+        &self,
+        _device: ObjectPath<'_>,
+        passkey: u32,
+    ) -> Result<(), AgentError> {
+        self.agent
+            .confirm_pin(&self.device, &format!("{:06}", passkey))
+            .then_some(())
+            .ok_or_else(rejected)
+    }
+
+    fn request_authorization(&self, _device: ObjectPath<'_>) -> Result<(), AgentError> {
+        self.agent
+            .confirm(&self.device)
+            .then_some(())
+            .ok_or_else(rejected)
+    }
+
+    fn authorize_service(&self, _device: ObjectPath<'_>, _uuid: String) -> Result<(), AgentError> {
+        self.agent
+            .confirm(&self.device)
+            .then_some(())
+            .ok_or_else(rejected)
+    }
+
+    fn cancel(&self) {}
+}
+
+fn dbus_error(err: zbus::Error) -> BluetoothError {
+    BluetoothError::RuntimeError(err.to_string())
+}
+
+fn pair_error(err: zbus::Error) -> PairingError {
+    PairingError::PairFailed(err.to_string())
+}
+
+// BlueZ报回来的错误名，不是方法错误时是None
+fn bluez_error_name(err: &zbus::Error) -> Option<&str> {
+    match err {
+        zbus::Error::MethodError(name, _, _) => Some(name.as_str()),
+        _ => None,
+    }
+}
+
+pub(crate) struct BluezPairing {
+    connection: Connection,
+    device: BluetoothDevice,
+    path: OwnedObjectPath,
+}
+
+impl BluezPairing {
+    // 在BlueZ的对象里找到这个设备。BlueZ只认识扫描到过或者配对过的设备，没找到返回`DeviceNotFound`
+    pub(crate) async fn open(device: &BluetoothDevice) -> crate::Result<BluezPairing> {
+        let connection = Connection::system().await.map_err(dbus_error)?;
+        let manager = ObjectManagerProxy::builder(&connection)
+            .destination(BLUEZ_SERVICE)
+            .and_then(|builder| builder.path("/"))
+            .map_err(dbus_error)?
+            .build()
+            .await
+            .map_err(dbus_error)?;
+        let objects = manager
+            .get_managed_objects()
+            .await
+            .map_err(|err| dbus_error(err.into()))?;
+
+        // 设备对象的路径形如/org/bluez/hci0/dev_00_02_B0_57_7D_D6
+        let suffix = format!("/dev_{}", device.addr_string().replace(':', "_"));
+        let path = objects
+            .into_iter()
+            .find(|(path, interfaces)| {
+                path.as_str().ends_with(&suffix)
+                    && interfaces
+                        .keys()
+                        .any(|interface| interface.as_str() == DEVICE_INTERFACE)
+            })
+            .map(|(path, _)| path)
+            .ok_or(BluetoothError::DeviceNotFound)?;
+
+        Ok(BluezPairing {
+            connection,
+            device: device.clone(),
+            path,
+        })
+    }
+
+    async fn proxy(
+        &self,
+        path: OwnedObjectPath,
+        interface: &'static str,
+    ) -> zbus::Result<Proxy<'static>> {
+        Proxy::new(&self.connection, BLUEZ_SERVICE, path, interface).await
+    }
+
+    async fn device_proxy(&self) -> zbus::Result<Proxy<'static>> {
+        self.proxy(self.path.clone(), DEVICE_INTERFACE).await
+    }
+
+    // 注册agent、调Device1.Pair，不管结果如何都注销agent
+    async fn pair_with_agent(&self) -> zbus::Result<()> {
+        let manager = self
+            .proxy(
+                ObjectPath::from_static_str_unchecked("/org/bluez").into(),
+                AGENT_MANAGER_INTERFACE,
+            )
+            .await?;
+        let agent_path = ObjectPath::from_static_str_unchecked(AGENT_PATH);
+        manager
+            .call_method("RegisterAgent", &(&agent_path, AGENT_CAPABILITY))
+            .await?;
+
+        let result = self.device_proxy().await?.call_method("Pair", &()).await;
+
+        let _ = manager
+            .call_method("UnregisterAgent", &(&agent_path,))
+            .await;
+        result.map(|_| ())
+    }
+}
+
+impl PairingBackend for BluezPairing {
+    async fn is_paired(&mut self) -> Result<bool, PairingError> {
+        self.device_proxy()
+            .await
+            .map_err(pair_error)?
+            .get_property("Paired")
+            .await
+            .map_err(|err| PairingError::PairFailed(err.to_string()))
+    }
+
+    // BlueZ没有单独的解除配对，只能把设备整个删掉，之后要重新扫描到它才能再配对
+    async fn unpair(&mut self) -> Result<(), PairingError> {
+        let error = |err: zbus::Error| PairingError::UnpairFailed(err.to_string());
+
+        let adapter: OwnedObjectPath = self
+            .device_proxy()
+            .await
+            .map_err(error)?
+            .get_property("Adapter")
+            .await
+            .map_err(|err| PairingError::UnpairFailed(err.to_string()))?;
+        self.proxy(adapter, ADAPTER_INTERFACE)
+            .await
+            .map_err(error)?
+            .call_method("RemoveDevice", &(&self.path,))
+            .await
+            .map_err(error)?;
+
+        Ok(())
+    }
+
+    async fn pair(&mut self, agent: Arc<dyn PairingAgent>) -> Result<(), PairingError> {
+        let agent = BluezAgent {
+            agent,
+            device: self.device.clone(),
+        };
+        let server = self.connection.object_server();
+        server.at(AGENT_PATH, agent).await.map_err(pair_error)?;
+
+        let result = self.pair_with_agent().await;
+
+        let _ = server.remove::<BluezAgent, _>(AGENT_PATH).await;
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => match bluez_error_name(&err) {
+                // 别的程序刚好先配好了
+                Some("org.bluez.Error.AlreadyExists") => Ok(()),
+                Some("org.bluez.Error.AuthenticationRejected")
+                | Some("org.bluez.Error.AuthenticationCanceled") => Err(PairingError::Rejected),
+                _ => Err(pair_error(err)),
+            },
+        }
+    }
+}
+
+/// 解除配对。BlueZ没有单独的解除配对，会把设备从适配器上删掉，之后要重新扫描到才能再连接。
+/// BlueZ不认识这个设备时返回`DeviceNotFound`
+pub async fn unpair_device(device: &BluetoothDevice) -> crate::Result<()> {
+    let mut backend = BluezPairing::open(device).await?;
+
+    Ok(backend.unpair().await?)
+}
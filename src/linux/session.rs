@@ -0,0 +1,430 @@
+use std::{
+    io,
+    os::fd::OwnedFd,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, unix::AsyncFd},
+    runtime::Handle,
+    time,
+};
+use uuid::Uuid;
+
+use crate::{
+    BluetoothError, BluetoothSppSession, IoSizes, SessionCapabilities, SessionState, TimeoutOp,
+    common::{
+        device::{BluetoothDevice, SPP_UUID},
+        pairing::{PairingConfig, pair_if_needed},
+        runtime::{ConnectAffinity, block_on},
+        stats::SessionStats,
+    },
+    linux::{
+        pair::BluezPairing,
+        socket::{connect_rfcomm, poll_recv, poll_send, query_rfcomm_channels, shutdown},
+        utils::{bluez_connect_error, bluez_write_error, is_peer_disconnect},
+    },
+};
+
+/// 走BlueZ的RFCOMM会话，用法和`WinrtSession`一样。
+///
+/// 通道号通过SDP向设备查询，配对交给BlueZ的agent。BlueZ只能配对扫描到过的设备，
+/// 没配对过的设备要先用`bluetoothctl scan on`之类的方式扫到它。
+pub struct BluezSession {
+    uuid: Uuid,
+    device: BluetoothDevice,
+    affinity: ConnectAffinity,
+    // 刚连上、还没注册到reactor上的fd。同步connect的runtime连完就没了，
+    // 要等第一次读写时在调用方的runtime里注册
+    fd: Option<OwnedFd>,
+    // 注册好的socket，断开时直接drop掉就关了
+    socket: Option<AsyncFd<OwnedFd>>,
+    // 最近一次连接用的RFCOMM通道
+    channel: Option<u8>,
+    // 对端关了，之后的读一直返回EOF，直到disconnect或重新连接
+    eof: bool,
+    state: SessionState,
+    stats: SessionStats,
+}
+
+impl BluezSession {
+    pub fn new() -> BluezSession {
+        BluezSession {
+            uuid: SPP_UUID,
+            device: BluetoothDevice::empty(),
+            affinity: ConnectAffinity::default(),
+            fd: None,
+            socket: None,
+            channel: None,
+            eof: false,
+            state: SessionState::Disconnected,
+            stats: SessionStats::new(),
+        }
+    }
+
+    /// 同步connect用的runtime类型，见`ConnectAffinity`
+    pub fn set_connect_affinity(&mut self, affinity: ConnectAffinity) {
+        self.affinity = affinity;
+    }
+
+    /// 最近一次连接用的RFCOMM通道号，还没连过时是`None`
+    pub fn channel(&self) -> Option<u8> {
+        self.channel
+    }
+
+    /// 跳过SDP查询直接连指定的RFCOMM通道，适合SDP记录不对、但通道号固定的设备
+    pub async fn connect_by_channel_async(
+        &mut self,
+        device: &BluetoothDevice,
+        channel: u8,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        self.reset(device, self.uuid);
+
+        let result = async {
+            self.pair(device, &pairing).await?;
+            self.open_channel(device, channel, pairing.is_required())
+                .await
+        }
+        .await;
+        self.finish(result)
+    }
+
+    // 断开旧连接，换成新的目标
+    fn reset(&mut self, device: &BluetoothDevice, uuid: Uuid) {
+        self.disconnect();
+        self.device = device.clone();
+        self.uuid = uuid;
+        self.state = SessionState::Connecting;
+    }
+
+    fn finish(&mut self, result: crate::Result<()>) -> crate::Result<()> {
+        self.state = match result {
+            Ok(_) => SessionState::Connected,
+            Err(_) => SessionState::Failed,
+        };
+        result
+    }
+
+    async fn pair(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: &PairingConfig,
+    ) -> crate::Result<()> {
+        let Some(agent) = pairing.agent() else {
+            return Ok(());
+        };
+
+        self.state = SessionState::Pairing;
+        let mut backend = BluezPairing::open(device).await?;
+        pair_if_needed(&mut backend, agent).await?;
+        self.state = SessionState::Connecting;
+
+        Ok(())
+    }
+
+    async fn open_channel(
+        &mut self,
+        device: &BluetoothDevice,
+        channel: u8,
+        encrypt: bool,
+    ) -> crate::Result<()> {
+        let fd = connect_rfcomm(device.addr(), channel, encrypt)
+            .await
+            .map_err(bluez_connect_error)?;
+
+        self.attach(fd, channel);
+        Ok(())
+    }
+
+    // 连好的fd先存着，不在连接用的runtime上注册，见`registered`
+    pub(crate) fn attach(&mut self, fd: OwnedFd, channel: u8) {
+        self.fd = Some(fd);
+        self.channel = Some(channel);
+        self.eof = false;
+    }
+
+    // 连上以后第一次读写时才注册到当前runtime的reactor上
+    fn registered(&mut self) -> io::Result<&AsyncFd<OwnedFd>> {
+        if let Some(fd) = self.fd.take() {
+            // AsyncFd::new在runtime外面会panic，先把fd放回去再报错
+            if Handle::try_current().is_err() {
+                self.fd = Some(fd);
+                return Err(io::Error::other(
+                    "BluezSession must be polled inside a tokio runtime",
+                ));
+            }
+            self.socket = Some(AsyncFd::new(fd)?);
+        }
+
+        self.socket
+            .as_ref()
+            .ok_or_else(|| BluetoothError::NotConnected.into())
+    }
+
+    // 读写时发现链路断了，和disconnect一样关掉socket
+    fn drop_link(&mut self) {
+        self.fd = None;
+        self.socket = None;
+        self.state = SessionState::Disconnected;
+    }
+}
+
+impl Default for BluezSession {
+    fn default() -> Self {
+        BluezSession::new()
+    }
+}
+
+impl std::fmt::Debug for BluezSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BluezSession")
+            .field("device", &self.device)
+            .field("uuid", &self.uuid)
+            .field("channel", &self.channel)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl BluetoothSppSession for BluezSession {
+    fn connect(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.connect_by_uuid(device, SPP_UUID, pairing)
+    }
+
+    fn connect_timeout(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+        timeout: std::time::Duration,
+    ) -> crate::Result<()> {
+        self.connect_by_uuid_timeout(device, SPP_UUID, pairing, timeout)
+    }
+
+    fn connect_by_uuid(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        block_on(self.affinity, async {
+            self.connect_by_uuid_async(device, uuid, pairing).await
+        })?
+    }
+
+    fn connect_by_uuid_timeout(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+        timeout: std::time::Duration,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        let affinity = self.affinity;
+        let result = block_on(affinity, async {
+            time::timeout(timeout, async {
+                self.connect_by_uuid_async(device, uuid, pairing).await
+            })
+            .await
+        })?;
+
+        match result {
+            Ok(result) => result,
+            Err(_) => {
+                // 卡在配对就报配对超时
+                let operation = match self.state {
+                    SessionState::Pairing => TimeoutOp::Pairing,
+                    _ => TimeoutOp::Connect,
+                };
+                self.disconnect();
+                self.state = SessionState::Failed;
+                Err(BluetoothError::TimedOut {
+                    duration: timeout,
+                    operation,
+                })
+            }
+        }
+    }
+
+    async fn connect_by_uuid_async(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        self.reset(device, uuid);
+
+        let result = async {
+            self.pair(device, &pairing).await?;
+
+            let channels = query_rfcomm_channels(device.addr(), uuid)
+                .await
+                .map_err(bluez_connect_error)?;
+            // 和WinrtSession一样，配对过但找不到服务要单独报出来
+            let Some(&channel) = channels.first() else {
+                return Err(if pairing.is_required() {
+                    BluetoothError::ServiceNotFoundAfterPairing
+                } else {
+                    BluetoothError::ServiceNotFound
+                });
+            };
+
+            self.open_channel(device, channel, pairing.is_required())
+                .await
+        }
+        .await;
+        self.finish(result)
+    }
+
+    async fn connect_async(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.connect_by_uuid_async(device, SPP_UUID, pairing).await
+    }
+
+    fn device(&self) -> &BluetoothDevice {
+        &self.device
+    }
+
+    fn into_device(self) -> BluetoothDevice {
+        self.device
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(fd) = self.fd.take() {
+            shutdown(&fd);
+        }
+        if let Some(socket) = self.socket.take() {
+            shutdown(&socket);
+        }
+        self.eof = false;
+        self.state = SessionState::Disconnected;
+    }
+
+    // 写出去的数据已经在内核里了，关socket时内核会先发完
+    async fn close(&mut self) -> crate::Result<()> {
+        self.disconnect();
+        Ok(())
+    }
+
+    fn state(&self) -> SessionState {
+        self.state
+    }
+
+    // 读是直接从socket读的，没有挂起的请求要取消
+    fn cancel_read(&mut self) {}
+
+    // 会话自己不缓存数据，都在内核的socket缓冲区里
+    fn clear_buffers(&mut self) {}
+
+    fn capabilities(&self) -> SessionCapabilities {
+        SessionCapabilities {
+            concurrent_read_write: true,
+            ..SessionCapabilities::default()
+        }
+    }
+
+    fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+    fn effective_io_sizes(&self) -> IoSizes {
+        IoSizes::default()
+    }
+}
+
+impl AsyncRead for BluezSession {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let self_mut = self.get_mut();
+        if self_mut.eof {
+            return Poll::Ready(Ok(()));
+        }
+        let socket = match self_mut.registered() {
+            Ok(socket) => socket,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        match ready!(poll_recv(socket, cx, buf)) {
+            Ok(0) => {
+                // 对端关了，和WinrtSession一样交给调用方一个EOF，之后再读也还是EOF
+                self_mut.drop_link();
+                self_mut.eof = true;
+                Poll::Ready(Ok(()))
+            }
+            Ok(n) => {
+                self_mut.stats.record_read(n);
+                Poll::Ready(Ok(()))
+            }
+            Err(err) => {
+                self_mut.drop_link();
+                if is_peer_disconnect(&err) {
+                    self_mut.eof = true;
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for BluezSession {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let self_mut = self.get_mut();
+        let socket = match self_mut.registered() {
+            Ok(socket) => socket,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        match ready!(poll_send(socket, cx, buf)) {
+            Ok(n) => {
+                self_mut.stats.record_write(n);
+                Poll::Ready(Ok(n))
+            }
+            Err(err) => {
+                self_mut.drop_link();
+                Poll::Ready(Err(bluez_write_error(err)))
+            }
+        }
+    }
+
+    // send返回时数据已经交给内核了，没有别的要推
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        if self.fd.is_none() && self.socket.is_none() {
+            return Poll::Ready(Err(BluetoothError::NotConnected.into()));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        self.get_mut().disconnect();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    task::{Context, Poll, ready},
+};
+
+use tokio::io::{Interest, ReadBuf, unix::AsyncFd};
+use uuid::Uuid;
+
+use crate::common::sdp::{
+    parse_service_search_attribute_response, rfcomm_channels, service_search_attribute_request,
+};
+
+// linux/bluetooth.h、rfcomm.h、l2cap.h里的常量，libc没有
+const BTPROTO_L2CAP: libc::c_int = 0;
+const BTPROTO_RFCOMM: libc::c_int = 3;
+const SOL_BLUETOOTH: libc::c_int = 274;
+const BT_SECURITY: libc::c_int = 4;
+const BT_SECURITY_MEDIUM: u8 = 2;
+// SDP服务器固定在这个PSM上
+const SDP_PSM: u16 = 0x0001;
+// 一条SDP回应最多这么大，L2CAP默认MTU是672，留足余量
+const SDP_RECV_SIZE: usize = 4096;
+// 续传次数的上限，防止对端一直给续传状态
+const SDP_MAX_ROUNDS: u16 = 64;
+
+// struct sockaddr_rc
+#[repr(C)]
+pub(crate) struct SockaddrRc {
+    rc_family: libc::sa_family_t,
+    rc_bdaddr: [u8; 6],
+    rc_channel: u8,
+}
+
+// struct sockaddr_l2
+#[repr(C)]
+pub(crate) struct SockaddrL2 {
+    l2_family: libc::sa_family_t,
+    l2_psm: u16,
+    l2_bdaddr: [u8; 6],
+    l2_cid: u16,
+    l2_bdaddr_type: u8,
+}
+
+// struct bt_security
+#[repr(C)]
+struct BtSecurity {
+    level: u8,
+    key_size: u8,
+}
+
+// 内核的bdaddr_t是小端的，最低字节在前，和`addr_string`的顺序正好相反
+pub(crate) fn bdaddr(addr: u64) -> [u8; 6] {
+    let bytes = addr.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5]]
+}
+
+pub(crate) fn rfcomm_addr(addr: u64, channel: u8) -> SockaddrRc {
+    SockaddrRc {
+        rc_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        rc_bdaddr: bdaddr(addr),
+        rc_channel: channel,
+    }
+}
+
+pub(crate) fn l2cap_addr(addr: u64, psm: u16) -> SockaddrL2 {
+    SockaddrL2 {
+        l2_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        l2_psm: psm.to_le(),
+        l2_bdaddr: bdaddr(addr),
+        l2_cid: 0,
+        // BDADDR_BREDR，经典蓝牙
+        l2_bdaddr_type: 0,
+    }
+}
+
+fn socket(kind: libc::c_int, protocol: libc::c_int) -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_BLUETOOTH,
+            kind | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            protocol,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// 非阻塞connect：EINPROGRESS以后等socket可写，再从SO_ERROR里拿结果
+async fn connect<A>(fd: OwnedFd, addr: &A) -> io::Result<AsyncFd<OwnedFd>> {
+    let ret = unsafe {
+        libc::connect(
+            fd.as_raw_fd(),
+            (addr as *const A).cast(),
+            mem::size_of::<A>() as libc::socklen_t,
+        )
+    };
+    let in_progress = ret < 0;
+    if in_progress {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    let fd = AsyncFd::new(fd)?;
+    if in_progress {
+        fd.writable().await?.retain_ready();
+        if let Some(err) = take_socket_error(fd.get_ref())? {
+            return Err(err);
+        }
+    }
+
+    Ok(fd)
+}
+
+fn take_socket_error(fd: &OwnedFd) -> io::Result<Option<io::Error>> {
+    let mut err: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            (&mut err as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((err != 0).then(|| io::Error::from_raw_os_error(err)))
+}
+
+// 要求加密的链路，没配对的设备会在连接时由BlueZ发起配对
+fn require_encryption(fd: &OwnedFd) -> io::Result<()> {
+    let security = BtSecurity {
+        level: BT_SECURITY_MEDIUM,
+        key_size: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_BLUETOOTH,
+            BT_SECURITY,
+            (&security as *const BtSecurity).cast(),
+            mem::size_of::<BtSecurity>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// 连到设备的某个RFCOMM通道，`encrypt`为`true`时要求加密链路。
+///
+/// 返回的fd已经从这次连接用的reactor上注销了：同步connect的runtime连完就没了，
+/// 读写时要在调用方自己的runtime里重新注册。
+pub(crate) async fn connect_rfcomm(addr: u64, channel: u8, encrypt: bool) -> io::Result<OwnedFd> {
+    let fd = socket(libc::SOCK_STREAM, BTPROTO_RFCOMM)?;
+    if encrypt {
+        require_encryption(&fd)?;
+    }
+
+    Ok(connect(fd, &rfcomm_addr(addr, channel)).await?.into_inner())
+}
+
+/// 通过L2CAP向设备的SDP服务器查`uuid`服务的RFCOMM通道号，按服务记录的顺序
+pub(crate) async fn query_rfcomm_channels(addr: u64, uuid: Uuid) -> io::Result<Vec<u8>> {
+    let fd = socket(libc::SOCK_SEQPACKET, BTPROTO_L2CAP)?;
+    let fd = connect(fd, &l2cap_addr(addr, SDP_PSM)).await?;
+
+    let mut lists = Vec::new();
+    let mut continuation = Vec::new();
+    for transaction in 1..=SDP_MAX_ROUNDS {
+        let request = service_search_attribute_request(transaction, uuid, &continuation);
+        fd.async_io(Interest::WRITABLE, |fd| send(fd, &request))
+            .await?;
+
+        let mut response = vec![0u8; SDP_RECV_SIZE];
+        let len = fd
+            .async_io(Interest::READABLE, |fd| recv(fd, &mut response))
+            .await?;
+
+        let (chunk, next) = parse_service_search_attribute_response(transaction, &response[..len])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed SDP response"))?;
+        lists.extend(chunk);
+        if next.is_empty() {
+            return Ok(rfcomm_channels(&lists));
+        }
+        continuation = next;
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "SDP response did not finish",
+    ))
+}
+
+fn recv(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    let n = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// MSG_NOSIGNAL：对端断开时返回EPIPE，而不是给整个进程发SIGPIPE
+fn send(fd: &OwnedFd, buf: &[u8]) -> io::Result<usize> {
+    let n = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            buf.as_ptr().cast(),
+            buf.len(),
+            libc::MSG_NOSIGNAL,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// 读到`buf`里，返回读了多少字节，0表示对端关了
+pub(crate) fn poll_recv(
+    fd: &AsyncFd<OwnedFd>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<io::Result<usize>> {
+    loop {
+        let mut guard = ready!(fd.poll_read_ready(cx))?;
+        let unfilled = buf.initialize_unfilled();
+        match guard.try_io(|fd| recv(fd.get_ref(), unfilled)) {
+            Ok(result) => {
+                let n = result?;
+                buf.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+            // 可读是假的，清掉就绪状态重新等
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+pub(crate) fn poll_send(
+    fd: &AsyncFd<OwnedFd>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+) -> Poll<io::Result<usize>> {
+    loop {
+        let mut guard = ready!(fd.poll_write_ready(cx))?;
+        match guard.try_io(|fd| send(fd.get_ref(), buf)) {
+            Ok(result) => return Poll::Ready(result),
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+// 两个方向都关掉，对端会收到断开。fd本身drop时才close
+pub(crate) fn shutdown(fd: &impl AsRawFd) {
+    unsafe {
+        libc::shutdown(fd.as_raw_fd(), libc::SHUT_RDWR);
+    }
+}
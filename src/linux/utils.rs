@@ -0,0 +1,45 @@
+use std::io;
+
+use crate::BluetoothError;
+
+// 连接阶段的错误按errno分，和`winrt_connect_error`一样不先转成字符串
+pub fn bluez_connect_error(err: io::Error) -> BluetoothError {
+    match err.raw_os_error() {
+        // 设备关着或者不在范围内，内核的page超时也算
+        Some(libc::EHOSTDOWN) | Some(libc::EHOSTUNREACH) | Some(libc::ETIMEDOUT) => {
+            BluetoothError::DeviceNotFound
+        }
+        Some(libc::ECONNREFUSED) => BluetoothError::ConnectionRefused(err.to_string()),
+        Some(libc::EBUSY) => BluetoothError::ConnectionRefused(
+            "RFCOMM channel is in use by another application".to_string(),
+        ),
+        Some(libc::EACCES) | Some(libc::EPERM) => BluetoothError::PermissionDenied,
+        Some(libc::EAFNOSUPPORT) | Some(libc::EPROTONOSUPPORT) => {
+            BluetoothError::Unsupported("the kernel has no Bluetooth socket support")
+        }
+        _ => BluetoothError::RuntimeError(err.to_string()),
+    }
+}
+
+// 对端断开、走出范围时读写会报的errno
+const PEER_DISCONNECT_ERRNOS: [i32; 5] = [
+    libc::ECONNRESET,
+    libc::ECONNABORTED,
+    libc::ENOTCONN,
+    libc::EPIPE,
+    libc::EHOSTDOWN,
+];
+
+pub(crate) fn is_peer_disconnect(err: &io::Error) -> bool {
+    err.raw_os_error()
+        .is_some_and(|errno| PEER_DISCONNECT_ERRNOS.contains(&errno))
+}
+
+// 写的时候对端断开报BrokenPipe，别的错误照旧
+pub(crate) fn bluez_write_error(err: io::Error) -> io::Error {
+    if is_peer_disconnect(&err) {
+        BluetoothError::PeerDisconnected.into()
+    } else {
+        err
+    }
+}
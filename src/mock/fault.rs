@@ -0,0 +1,64 @@
+use crate::common::rng::XorShift;
+
+/// `MockSession`读数据时注入的故障，用来测试协议解析遇到脏数据时能不能自己恢复。
+///
+/// 三个概率都是0到1之间；同一个`seed`配同样的数据，每次注入的故障都一样，方便复现。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// 每次读只交出一部分数据的概率
+    pub split_prob: f64,
+    /// 每个字节随机翻转一位的概率
+    pub flip_rate: f64,
+    /// 每个字节被直接丢掉的概率
+    pub drop_rate: f64,
+    pub seed: u64,
+}
+
+pub(crate) struct FaultInjector {
+    config: FaultConfig,
+    rng: XorShift,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(config: FaultConfig) -> FaultInjector {
+        FaultInjector {
+            config,
+            rng: XorShift::new(config.seed),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn roll(&mut self, prob: f64) -> bool {
+        let sample = self.rng.next_f64();
+        prob > 0.0 && sample < prob
+    }
+
+    /// 这次读最多拿多少字节，可能被随机切短
+    pub(crate) fn read_len(&mut self, available: usize) -> usize {
+        if available > 1 && self.roll(self.config.split_prob) {
+            1 + (self.next_u64() % (available as u64 - 1)) as usize
+        } else {
+            available
+        }
+    }
+
+    /// 对读出来的字节做翻转和丢弃，返回真正交给调用方的数据
+    pub(crate) fn corrupt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            if self.roll(self.config.drop_rate) {
+                continue;
+            }
+
+            if self.roll(self.config.flip_rate) {
+                out.push(byte ^ (1 << (self.next_u64() % 8)));
+            } else {
+                out.push(byte);
+            }
+        }
+        out
+    }
+}
@@ -1,21 +1,82 @@
-use std::{task::Poll, time::Duration};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    task::{Poll, Waker, ready},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    runtime::Builder,
-    time::{self, sleep},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    time::{self, Instant, Sleep, sleep, sleep_until},
 };
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::{BluetoothDevice, BluetoothError, BluetoothSppSession, common::device::SPP_UUID};
+use crate::{
+    BluetoothDevice, BluetoothError, BluetoothSppSession, IoSizes, SessionCapabilities,
+    SessionState, TimeoutOp,
+    common::{
+        device::{SPP_UUID, session_summary},
+        diagnostics::{ConnectDiagnostics, StepOutcome},
+        pairing::{PairingConfig, PairingError},
+        profile::ConnectionProfile,
+        runtime::{ConnectAffinity, block_on},
+        stats::{LatencyStats, SessionStats},
+        status::{ConnectionStatus, validate_link},
+    },
+    mock::fault::{FaultConfig, FaultInjector},
+};
+
+// with_responder设的对端逻辑
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
 
 pub struct MockSession {
     uuid: Uuid,
     device: BluetoothDevice,
+    label: String,
+    affinity: ConnectAffinity,
     need_pairing: bool,
     blocked: bool,
+    blocked_pairing: bool,
+    missing_service: bool,
+    unreachable: Option<u64>,
+    drop_after_connect: bool,
+    required_pin: Option<String>,
+    validate_after_connect: Option<Duration>,
+    cancel: Option<CancellationToken>,
+    diagnostics: Option<ConnectDiagnostics>,
     buffer: Vec<u8>,
     position: usize,
     is_ready: bool,
+    state: SessionState,
+    latency: Duration,
+    written: Vec<u8>,
+    // 已经交给mock但还没"送达"的写，按到期时间排队，带着发起的时间
+    in_flight: VecDeque<(Instant, Instant, Vec<u8>)>,
+    flush_delay: Option<Pin<Box<Sleep>>>,
+    // 没数据可读时挂起的读，等数据送达再唤醒
+    read_delay: Option<Pin<Box<Sleep>>>,
+    read_waker: Option<Waker>,
+    stats: SessionStats,
+    // 主动断开以后读写都报NotConnected，模拟真实会话断开后的行为
+    closed: bool,
+    faults: Option<FaultInjector>,
+    auto_flush: bool,
+    reads_paused: bool,
+    // finish_reads时缓冲区的长度，读到这里就是EOF
+    eof_at: Option<usize>,
+    // 自动flush时已经收下、正在等送达的那次写的长度
+    draining_write: Option<usize>,
+    // 在途的写最多排几个，满了poll_write就挂起
+    max_pending_writes: Option<usize>,
+    max_write_chunk: Option<usize>,
+    // 队列满时等最早那笔送达
+    write_delay: Option<Pin<Box<Sleep>>>,
+    // 下一次读写要报的错，报一次就清掉
+    read_error: Option<BluetoothError>,
+    write_error: Option<BluetoothError>,
+    // 设了以后送达的写不再原样回环，而是换成它的返回值
+    responder: Option<Responder>,
 }
 
 impl MockSession {
@@ -23,62 +84,442 @@ impl MockSession {
         return MockSession {
             uuid: SPP_UUID,
             device: BluetoothDevice::empty(),
+            label: String::new(),
+            affinity: ConnectAffinity::default(),
             need_pairing: true,
             blocked: false,
+            blocked_pairing: false,
+            missing_service: false,
+            unreachable: None,
+            drop_after_connect: false,
+            required_pin: None,
+            validate_after_connect: None,
+            cancel: None,
+            diagnostics: None,
             buffer: Vec::new(),
             position: 0,
             is_ready: false,
+            state: SessionState::Disconnected,
+            latency: Duration::ZERO,
+            written: Vec::new(),
+            in_flight: VecDeque::new(),
+            flush_delay: None,
+            read_delay: None,
+            read_waker: None,
+            stats: SessionStats::new(),
+            closed: false,
+            faults: None,
+            auto_flush: false,
+            reads_paused: false,
+            eof_at: None,
+            draining_write: None,
+            max_pending_writes: None,
+            max_write_chunk: None,
+            write_delay: None,
+            read_error: None,
+            write_error: None,
+            responder: None,
         };
     }
 
+    /// 模拟一个简单的对端：每次写送达时用写入的数据调`f`，返回值当作对端的回复放进读缓冲区，
+    /// 不再原样回环。`written`记的还是写入的原始数据，返回空的`Vec`就是不回复
+    pub fn with_responder(mut self, f: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static) -> Self {
+        self.responder = Some(Box::new(f));
+        self
+    }
+
     pub fn blocked_connect(&mut self, blocked: bool) {
         self.blocked = blocked;
     }
+
+    /// 需要配对的连接卡在配对这一步
+    pub fn blocked_pairing(&mut self, blocked: bool) {
+        self.blocked_pairing = blocked;
+    }
+
+    /// 连接时找不到服务，返回`ServiceNotFound`；需要配对的连接返回`ServiceNotFoundAfterPairing`
+    pub fn missing_service(&mut self, missing: bool) {
+        self.missing_service = missing;
+    }
+
+    /// 连这个地址时找不到设备，返回`DeviceNotFound`，模拟地址变了的设备
+    pub fn unreachable_address(&mut self, addr: Option<u64>) {
+        self.unreachable = addr;
+    }
+
+    /// 模拟设备接受连接后马上断开：连接照样成功，只有打开了`set_validate_after_connect`才能发现
+    pub fn drop_after_connect(&mut self, drop: bool) {
+        self.drop_after_connect = drop;
+    }
+
+    /// 模拟要PIN的设备：配对时agent的`provide_pin`要返回同样的PIN才能配上，否则返回配对被拒绝。
+    /// `None`（默认）是只需确认的设备
+    pub fn require_pin(&mut self, pin: Option<&str>) {
+        self.required_pin = pin.map(str::to_string);
+    }
+
+    /// 同`WinrtSession::set_validate_after_connect`
+    pub fn set_validate_after_connect(&mut self, wait: Option<Duration>) {
+        self.validate_after_connect = wait;
+    }
+
+    /// 同`WinrtSession::set_connect_diagnostics`
+    pub fn set_connect_diagnostics(&mut self, enabled: bool) {
+        self.diagnostics = enabled.then(ConnectDiagnostics::new);
+    }
+
+    pub fn connect_diagnostics(&self) -> Option<&ConnectDiagnostics> {
+        self.diagnostics.as_ref()
+    }
+
+    /// 模拟链路延迟：写入会立刻被接受，但要过`latency`之后数据才算送达
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    /// 读的时候按`config`随机切短、翻转位、丢字节，传`None`关掉
+    pub fn set_fault_injection(&mut self, config: Option<FaultConfig>) {
+        self.faults = config.map(FaultInjector::new);
+    }
+
+    /// 同`WinrtSession::pause_reads`，暂停期间送达的数据先攒着，恢复后再读出来
+    pub fn pause_reads(&mut self) {
+        self.reads_paused = true;
+    }
+
+    pub fn resume_reads(&mut self) {
+        self.reads_paused = false;
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 模拟对端关闭：已经送达的数据读完以后返回EOF，之后再写进来的数据读不到，只记在`written`里
+    pub fn finish_reads(&mut self) {
+        self.land_due();
+        self.eof_at = Some(self.buffer.len());
+    }
+
+    /// 模拟对端发来`data`：之后的读会读到它，不经过写入也不记在`written`里。
+    ///
+    /// 和回环写进来的数据排在同一个队列里，按调用顺序读出来；`finish_reads`之后再放的读不到
+    pub fn push_read_data(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 下一次读返回`err`，只报一次，之后照常读
+    pub fn set_fail_on_read(&mut self, err: BluetoothError) {
+        self.read_error = Some(err);
+
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 下一次写返回`err`，只报一次，这次的数据不会被收下
+    pub fn set_fail_on_write(&mut self, err: BluetoothError) {
+        self.write_error = Some(err);
+    }
+
+    /// 模拟链路悄悄断掉：之后的读写报`NotConnected`，但`state`要等`check_alive`或者`disconnect`才会变
+    pub fn drop_link(&mut self) {
+        self.closed = true;
+    }
+
+    /// 同`WinrtSession::check_alive`，探测要花一次链路延迟，延迟超过`timeout`时返回`TimedOut`
+    pub async fn check_alive(&mut self, timeout: Duration) -> crate::Result<bool> {
+        if self.state != SessionState::Connected {
+            return Ok(false);
+        }
+
+        if self.latency > timeout {
+            sleep(timeout).await;
+            return Err(BluetoothError::TimedOut {
+                duration: timeout,
+                operation: TimeoutOp::Read,
+            });
+        }
+        sleep(self.latency).await;
+
+        if self.closed {
+            self.disconnect();
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// 在途的写最多排`max`个（至少1个），满了以后写会挂起，等最早那笔送达再收，`None`不限。
+    ///
+    /// 对端卡住时靠它限制内存，当前排了几个见`stats().pending_writes()`
+    pub fn set_max_pending_writes(&mut self, max: Option<usize>) {
+        self.max_pending_writes = max.map(|max| max.max(1));
+    }
+
+    /// 同`WinrtSession::set_max_write_chunk`
+    pub fn set_max_write_chunk(&mut self, max: Option<usize>) {
+        self.max_write_chunk = max.map(|max| max.max(1));
+    }
+
+    /// 同`WinrtSession::set_auto_flush`：打开后每次写都等数据送达才返回
+    pub fn set_auto_flush(&mut self, auto_flush: bool) {
+        self.auto_flush = auto_flush;
+    }
+
+    /// 和`WinrtSession::write_latency_stats`一样，mock里一次写从发起到送达算完成
+    pub fn write_latency_stats(&self) -> &LatencyStats {
+        self.stats.write_latency()
+    }
+
+    /// 同`WinrtSession::rebind_to`，只换记下的设备，其他配置和当前连接不动
+    pub fn rebind_to(&mut self, device: &BluetoothDevice) {
+        self.device = device.clone();
+    }
+
+    /// 同`WinrtSession::connection_profile`，mock没有服务名和适配器
+    pub fn connection_profile(&self) -> ConnectionProfile {
+        ConnectionProfile::new(self.device.clone(), self.uuid)
+    }
+
+    /// 同`WinrtSession::can_read_without_blocking`：缓冲区里已经有数据（在途的写到期了也算），
+    /// 这时`poll_read`会立刻返回`Ready`
+    pub fn can_read_without_blocking(&self) -> bool {
+        // mock的第一次读总会先Pending一次
+        if self.closed || self.reads_paused || !self.is_ready {
+            return false;
+        }
+
+        if let Some(end) = self.eof_at {
+            return self.position < end;
+        }
+
+        let now = Instant::now();
+        self.position < self.buffer.len()
+            || self
+                .in_flight
+                .front()
+                .is_some_and(|(_, due, _)| *due <= now)
+    }
+
+    /// 目前为止已经送达的所有写入数据
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// 同步connect用的runtime类型，见`ConnectAffinity`
+    pub fn set_connect_affinity(&mut self, affinity: ConnectAffinity) {
+        self.affinity = affinity;
+    }
+
+    /// 给会话打个标签，只用于日志，不影响连接
+    pub fn set_label(&mut self, label: String) {
+        self.label = label;
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// 一行式的状态摘要，格式和`WinrtSession::summary`一致
+    pub fn summary(&self) -> String {
+        // mock的读不会挂起WinRT那种长时间的操作
+        session_summary(
+            &self.label,
+            &self.device,
+            self.uuid,
+            self.is_connected(),
+            false,
+        )
+    }
+
+    /// 和`WinrtSession::write_vectored`一样，几段数据连在一起当成一次写入
+    pub async fn write_vectored(&mut self, bufs: &[&[u8]]) -> crate::Result<usize> {
+        let data = bufs.concat();
+        let len = data.len();
+        self.write_all(&data)
+            .await
+            .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+        Ok(len)
+    }
+
+    fn check_cancelled(&self) -> crate::Result<()> {
+        if self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.is_cancelled())
+        {
+            return Err(BluetoothError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn begin_step(&mut self, name: &'static str) {
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            diagnostics.begin(name);
+        }
+    }
+
+    fn end_step(&mut self, outcome: StepOutcome) {
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            diagnostics.end(outcome);
+        }
+    }
+
+    fn deliver(&mut self, data: Vec<u8>) {
+        self.written.extend_from_slice(&data);
+        match self.responder.as_mut() {
+            Some(respond) => self.buffer.extend_from_slice(&respond(&data)),
+            None => self.buffer.extend_from_slice(&data),
+        }
+
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    // 等在途的写全部送达
+    fn poll_drain(&mut self, cx: &mut std::task::Context<'_>) -> Poll<()> {
+        self.land_due();
+
+        // 还有在途的写，等最后一笔到期
+        if let Some((_, due, _)) = self.in_flight.back() {
+            let due = *due;
+            let delay = self
+                .flush_delay
+                .get_or_insert_with(|| Box::pin(sleep_until(due)));
+            delay.as_mut().reset(due);
+
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            self.flush_delay = None;
+            self.land_due();
+        }
+
+        Poll::Ready(())
+    }
+
+    fn land_due(&mut self) {
+        let now = Instant::now();
+        while let Some((_, due, _)) = self.in_flight.front() {
+            if *due > now {
+                break;
+            }
+
+            if let Some((started, _, data)) = self.in_flight.pop_front() {
+                self.stats.record_write_latency(now - started);
+                self.deliver(data);
+            }
+        }
+        self.stats.set_pending_writes(self.in_flight.len());
+    }
+
+    // 队列满的时候等最早那笔到期腾出位置
+    fn poll_queue_space(&mut self, cx: &mut std::task::Context<'_>) -> Poll<()> {
+        loop {
+            self.land_due();
+
+            let Some(max) = self.max_pending_writes else {
+                return Poll::Ready(());
+            };
+            let Some((_, due, _)) = self.in_flight.front() else {
+                return Poll::Ready(());
+            };
+            if self.in_flight.len() < max {
+                self.write_delay = None;
+                return Poll::Ready(());
+            }
+
+            let due = *due;
+            let delay = self
+                .write_delay
+                .get_or_insert_with(|| Box::pin(sleep_until(due)));
+            delay.as_mut().reset(due);
+
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for MockSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockSession")
+            .field("label", &self.label)
+            .field("device", &self.device)
+            .field("uuid", &self.uuid)
+            .field("state", &self.state)
+            .finish()
+    }
 }
 
 impl BluetoothSppSession for MockSession {
-    fn connect(&mut self, device: &BluetoothDevice, need_pairing: bool) -> crate::Result<()> {
-        self.connect_by_uuid(device, SPP_UUID, need_pairing)
+    fn connect(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.connect_by_uuid(device, SPP_UUID, pairing)
     }
 
     fn connect_timeout(
         &mut self,
         device: &BluetoothDevice,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
         timeout: std::time::Duration,
     ) -> crate::Result<()> {
-        self.connect_by_uuid_timeout(device, SPP_UUID, need_pairing, timeout)
+        self.connect_by_uuid_timeout(device, SPP_UUID, pairing, timeout)
     }
 
     fn connect_by_uuid(
         &mut self,
         device: &BluetoothDevice,
         uuid: Uuid,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
     ) -> crate::Result<()> {
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-
-        rt.block_on(async { self.connect_by_uuid_async(device, uuid, need_pairing).await })
+        let pairing = pairing.into();
+        block_on(self.affinity, async {
+            self.connect_by_uuid_async(device, uuid, pairing).await
+        })?
     }
 
     fn connect_by_uuid_timeout(
         &mut self,
         device: &BluetoothDevice,
         uuid: Uuid,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
         timeout: std::time::Duration,
     ) -> crate::Result<()> {
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-
-        let result = rt.block_on(async {
+        let pairing = pairing.into();
+        let affinity = self.affinity;
+        let result = block_on(affinity, async {
             time::timeout(timeout, async {
-                self.connect_by_uuid_async(device, uuid, need_pairing).await
+                self.connect_by_uuid_async(device, uuid, pairing).await
             })
             .await
-        });
+        })?;
 
         if let Err(_) = result {
-            return Err(BluetoothError::TimedOut(timeout));
+            if let Some(diagnostics) = self.diagnostics.as_mut() {
+                diagnostics.time_out_pending();
+            }
+
+            // 卡在配对就报配对超时
+            let operation = match self.state {
+                SessionState::Pairing => TimeoutOp::Pairing,
+                _ => TimeoutOp::Connect,
+            };
+            self.state = SessionState::Failed;
+            return Err(BluetoothError::TimedOut {
+                duration: timeout,
+                operation,
+            });
         } else if let Ok(Err(err)) = result {
             return Err(err);
         }
@@ -90,26 +531,121 @@ impl BluetoothSppSession for MockSession {
         &mut self,
         device: &BluetoothDevice,
         uuid: Uuid,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
     ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        let need_pairing = pairing.is_required();
+        self.clear_buffers();
         self.device = device.clone();
         self.uuid = uuid;
         self.need_pairing = need_pairing;
+        self.closed = false;
+        self.state = SessionState::Connecting;
+        if self.diagnostics.is_some() {
+            self.diagnostics = Some(ConnectDiagnostics::new());
+        }
 
-        while self.blocked {
+        // 步骤名和WinrtSession的对应，mock里的"查设备"就是blocked_connect卡住的地方
+        self.begin_step("FindAll");
+        while self.blocked && self.check_cancelled().is_ok() {
             sleep(Duration::from_millis(10)).await;
         }
+        if self.unreachable == Some(device.addr()) {
+            self.end_step(StepOutcome::Found(0));
+            self.state = SessionState::Failed;
+            return Err(BluetoothError::DeviceNotFound);
+        }
+        self.end_step(StepOutcome::Found(1));
+        self.check_cancelled()?;
+
+        if let Some(agent) = pairing.agent() {
+            self.state = SessionState::Pairing;
+            self.begin_step("Pair");
+
+            // 会话被独占着，没人能把它改回来，只能靠外面的超时或者取消结束
+            if self.blocked_pairing {
+                match self.cancel.clone() {
+                    Some(cancel) => cancel.cancelled().await,
+                    None => std::future::pending::<()>().await,
+                }
+            }
+
+            // 设备要PIN时agent给的得对得上，否则只需确认
+            let accepted = match &self.required_pin {
+                Some(pin) => agent.provide_pin(device).as_ref() == Some(pin),
+                None => agent.confirm(device),
+            };
+            if !accepted {
+                let err = BluetoothError::from(PairingError::Rejected);
+                self.end_step(StepOutcome::Failed(err.to_string()));
+                self.state = SessionState::Failed;
+                return Err(err);
+            }
+            self.end_step(StepOutcome::Ok);
+            self.check_cancelled()?;
+        }
+
+        self.begin_step("GetServices");
+        if self.missing_service {
+            self.end_step(StepOutcome::Found(0));
+            self.state = SessionState::Failed;
+            return Err(if need_pairing {
+                BluetoothError::ServiceNotFoundAfterPairing
+            } else {
+                BluetoothError::ServiceNotFound
+            });
+        }
+        self.end_step(StepOutcome::Found(1));
+        self.check_cancelled()?;
+
+        self.begin_step("Connect");
+        self.closed = self.drop_after_connect;
+        self.end_step(StepOutcome::Ok);
 
+        if let Some(wait) = self.validate_after_connect {
+            self.begin_step("Validate");
+            let status = if self.closed {
+                ConnectionStatus::Disconnected
+            } else {
+                ConnectionStatus::Connected
+            };
+            let result = validate_link(wait, async { Ok(status) }).await;
+            if let Err(err) = result {
+                self.end_step(StepOutcome::Failed(err.to_string()));
+                self.state = SessionState::Failed;
+                return Err(err);
+            }
+            self.end_step(StepOutcome::Ok);
+        }
+
+        self.state = SessionState::Connected;
         Ok(())
     }
 
     async fn connect_async(
         &mut self,
         device: &BluetoothDevice,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
     ) -> crate::Result<()> {
-        self.connect_by_uuid_async(device, SPP_UUID, need_pairing)
-            .await
+        self.connect_by_uuid_async(device, SPP_UUID, pairing).await
+    }
+
+    // 同`WinrtSession`，卡在`blocked_connect`或`blocked_pairing`时也能取消
+    async fn connect_with_cancel(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+        cancel: CancellationToken,
+    ) -> crate::Result<()> {
+        self.cancel = Some(cancel);
+        let result = self.connect_by_uuid_async(device, uuid, pairing).await;
+        self.cancel = None;
+
+        if let Err(BluetoothError::Cancelled) = result {
+            self.disconnect();
+        }
+        result
     }
 
     fn device(&self) -> &BluetoothDevice {
@@ -119,6 +655,76 @@ impl BluetoothSppSession for MockSession {
     fn into_device(self) -> BluetoothDevice {
         self.device
     }
+
+    fn disconnect(&mut self) {
+        // 在途的数据直接丢掉
+        self.in_flight.clear();
+        self.stats.set_pending_writes(0);
+        self.flush_delay = None;
+        self.write_delay = None;
+        self.draining_write = None;
+        self.cancel_read();
+        // 带取消的connect被中途扔掉时令牌还留着，不能影响下一次connect
+        self.cancel = None;
+        self.closed = true;
+        self.state = SessionState::Disconnected;
+    }
+
+    async fn close(&mut self) -> crate::Result<()> {
+        let result = self
+            .flush()
+            .await
+            .map_err(|err| BluetoothError::RuntimeError(err.to_string()));
+
+        self.disconnect();
+
+        result
+    }
+
+    fn state(&self) -> SessionState {
+        self.state
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state == SessionState::Connected
+    }
+
+    fn cancel_read(&mut self) {
+        self.read_delay = None;
+        self.read_waker = None;
+    }
+
+    fn clear_buffers(&mut self) {
+        self.cancel_read();
+        self.buffer.clear();
+        self.position = 0;
+        self.eof_at = None;
+        self.in_flight.clear();
+        self.stats.set_pending_writes(0);
+        self.flush_delay = None;
+        self.write_delay = None;
+        self.draining_write = None;
+    }
+
+    fn capabilities(&self) -> SessionCapabilities {
+        // 读写各走各的队列，互不影响
+        SessionCapabilities {
+            concurrent_read_write: true,
+            ..SessionCapabilities::default()
+        }
+    }
+
+    fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    fn effective_io_sizes(&self) -> IoSizes {
+        // 读是有多少给多少，不拆
+        IoSizes {
+            read_chunk: None,
+            write_chunk: self.max_write_chunk,
+        }
+    }
 }
 
 impl AsyncRead for MockSession {
@@ -128,28 +734,132 @@ impl AsyncRead for MockSession {
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
         let self_mut = self.get_mut();
+        if self_mut.closed {
+            return Poll::Ready(Err(BluetoothError::NotConnected.into()));
+        }
 
-        if self_mut.is_ready {
-            let data = &self_mut.buffer[self_mut.position..];
-            buf.put_slice(data);
-            self_mut.position += data.len();
-            Poll::Ready(Ok(()))
-        } else {
+        if let Some(err) = self_mut.read_error.take() {
+            return Poll::Ready(Err(err.into()));
+        }
+
+        self_mut.land_due();
+
+        if self_mut.reads_paused {
+            self_mut.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        if !self_mut.is_ready {
             self_mut.is_ready = true;
             cx.waker().wake_by_ref();
-            Poll::Pending
+            return Poll::Pending;
+        }
+
+        // 调用方的缓冲区已经满了，和WinrtSession一样直接算这次读完了
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let end = self_mut.eof_at.unwrap_or(self_mut.buffer.len());
+        if self_mut.eof_at.is_some() && self_mut.position >= end {
+            return Poll::Ready(Ok(()));
+        }
+
+        while self_mut.position < end {
+            self_mut.read_delay = None;
+            let data = &self_mut.buffer[self_mut.position..end];
+            let mut len = data.len().min(buf.remaining());
+
+            let Some(faults) = self_mut.faults.as_mut() else {
+                buf.put_slice(&data[..len]);
+                self_mut.position += len;
+                self_mut.stats.record_read(len);
+                return Poll::Ready(Ok(()));
+            };
+
+            len = faults.read_len(len);
+            let out = faults.corrupt(&data[..len]);
+            self_mut.position += len;
+
+            // 全被丢掉了就接着读，不能交出0字节，那等于EOF
+            if !out.is_empty() || len == 0 {
+                buf.put_slice(&out);
+                self_mut.stats.record_read(out.len());
+                return Poll::Ready(Ok(()));
+            }
         }
+
+        // 没数据：有在途的写就等最早那笔到期，否则等下一次写入唤醒
+        if let Some((_, due, _)) = self_mut.in_flight.front() {
+            let due = *due;
+            let delay = self_mut
+                .read_delay
+                .get_or_insert_with(|| Box::pin(sleep_until(due)));
+            delay.as_mut().reset(due);
+
+            if delay.as_mut().poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        } else {
+            self_mut.read_waker = Some(cx.waker().clone());
+        }
+
+        Poll::Pending
     }
 }
 
 impl AsyncWrite for MockSession {
     fn poll_write(
         self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         let self_mut = self.get_mut();
-        self_mut.buffer.extend_from_slice(buf);
+        if self_mut.closed {
+            self_mut.draining_write = None;
+            return Poll::Ready(Err(BluetoothError::NotConnected.into()));
+        }
+
+        if let Some(err) = self_mut.write_error.take() {
+            self_mut.draining_write = None;
+            return Poll::Ready(Err(err.into()));
+        }
+
+        // 上次已经收下了，这次只是接着等它送达
+        if let Some(len) = self_mut.draining_write {
+            if self_mut.poll_drain(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self_mut.draining_write = None;
+            return Poll::Ready(Ok(len));
+        }
+
+        if self_mut.poll_queue_space(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let buf = match self_mut.max_write_chunk {
+            Some(max) => &buf[..buf.len().min(max)],
+            None => buf,
+        };
+
+        if self_mut.latency.is_zero() {
+            self_mut.stats.record_write_latency(Duration::ZERO);
+            self_mut.deliver(buf.to_vec());
+        } else {
+            let now = Instant::now();
+            self_mut
+                .in_flight
+                .push_back((now, now + self_mut.latency, buf.to_vec()));
+            self_mut.stats.set_pending_writes(self_mut.in_flight.len());
+        }
+        self_mut.stats.record_write(buf.len());
+
+        if self_mut.auto_flush && self_mut.poll_drain(cx).is_pending() {
+            self_mut.draining_write = Some(buf.len());
+            return Poll::Pending;
+        }
+
         Poll::Ready(Ok(buf.len()))
     }
 
@@ -158,6 +868,9 @@ impl AsyncWrite for MockSession {
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         let self_mut = self.get_mut();
+        if self_mut.poll_drain(cx).is_pending() {
+            return Poll::Pending;
+        }
 
         if self_mut.is_ready {
             Poll::Ready(Ok(()))
@@ -170,8 +883,16 @@ impl AsyncWrite for MockSession {
 
     fn poll_shutdown(
         self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+        let self_mut = self.get_mut();
+        if self_mut.closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        // 在途的数据先送完再关，和WinrtSession一样
+        let flushed = ready!(Pin::new(&mut *self_mut).poll_flush(cx));
+        self_mut.disconnect();
+        Poll::Ready(flushed)
     }
 }
@@ -0,0 +1,501 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use futures::{FutureExt, Stream};
+use tokio::{sync::mpsc, time};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+use uuid::Uuid;
+use windows::{
+    Devices::{
+        Bluetooth,
+        Enumeration::{
+            DeviceInformation, DeviceInformationKind, DeviceInformationUpdate, DeviceWatcher,
+        },
+    },
+    Foundation::{DateTime, IReference, TypedEventHandler},
+    core::{HSTRING, IInspectable, Interface, Ref},
+};
+use windows_collections::{IIterable, IMapView};
+
+use crate::{
+    BluetoothError, TimeoutOp,
+    common::{
+        device::{BluetoothDevice, DeviceInfo, SPP_UUID},
+        discovery::{
+            DeviceEvent, DeviceTracker, DeviceUpdate, DiscoveryEvent, RawDeviceEntry,
+            SERVICE_RESOLVE_CONCURRENCY, collect_devices, collect_devices_limited,
+            device_from_entry, devices_matching, devices_with_service, discovery_events,
+            map_device_entries, resolve_services,
+        },
+        sdp::ServiceInfo,
+    },
+    windows::{
+        session::sdp_service_name,
+        utils::{winrt_async, winrt_error_wrap, winrt_none_error_wrap},
+    },
+};
+
+// 经典蓝牙的关联终结点协议id
+const BLUETOOTH_PROTOCOL_SELECTOR: &str =
+    "System.Devices.Aep.ProtocolId:=\"{e0cbf06c-cd8b-4647-bb8a-263b43f0f974}\"";
+
+const ADDRESS_PROPERTY: &str = "System.Devices.Aep.DeviceAddress";
+const PAIRED_PROPERTY: &str = "System.Devices.Aep.IsPaired";
+const CONNECTED_PROPERTY: &str = "System.Devices.Aep.IsConnected";
+const SIGNAL_STRENGTH_PROPERTY: &str = "System.Devices.Aep.SignalStrength";
+const LAST_SEEN_PROPERTY: &str = "System.Devices.Aep.Bluetooth.LastSeenTime";
+const COD_MAJOR_PROPERTY: &str = "System.Devices.Aep.Bluetooth.Cod.Major";
+const COD_MINOR_PROPERTY: &str = "System.Devices.Aep.Bluetooth.Cod.Minor";
+// 设备接口上没有AEP的地址，用接口自己的，是不带分隔符的12位十六进制
+const INTERFACE_ADDRESS_PROPERTY: &str = "System.DeviceInterface.Bluetooth.DeviceAddress";
+
+// 1601-01-01到1970-01-01之间的100ns个数
+const FILETIME_UNIX_EPOCH: i64 = 116_444_736_000_000_000;
+
+/// 扫描附近的经典蓝牙设备，超时返回`TimedOut`
+pub async fn scan_devices(timeout: Duration) -> crate::Result<Vec<DeviceInfo>> {
+    match time::timeout(timeout, find_devices()).await {
+        Ok(result) => result,
+        Err(_) => Err(BluetoothError::TimedOut {
+            duration: timeout,
+            operation: TimeoutOp::Discovery,
+        }),
+    }
+}
+
+/// 同`scan_devices`，但只要设备本身（名字和地址），不关心配对、信号这些状态。
+///
+/// 地址解析不了的条目直接跳过，不会让整次扫描失败；超时返回`TimedOut`。
+pub async fn discover_devices(timeout: Duration) -> crate::Result<Vec<BluetoothDevice>> {
+    let devices = scan_devices(timeout).await?;
+    Ok(devices.into_iter().map(|info| info.device).collect())
+}
+
+async fn find_devices() -> crate::Result<Vec<DeviceInfo>> {
+    let list = winrt_async(
+        DeviceInformation::FindAllAsyncWithKindAqsFilterAndAdditionalProperties(
+            &HSTRING::from(BLUETOOTH_PROTOCOL_SELECTOR),
+            &aep_properties(),
+            DeviceInformationKind::AssociationEndpoint,
+        ),
+    )
+    .await?;
+
+    let mut devices = Vec::new();
+    for info in list {
+        if let Some(device) = device_info(&info) {
+            devices.push(device);
+        }
+    }
+
+    Ok(devices)
+}
+
+/// `discover_with_selector`按哪种对象枚举，AQS过滤串要按这种对象的属性来写
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectorKind {
+    /// 关联端点（AEP），和`scan_devices`一样，可以按`System.Devices.Aep.*`过滤
+    #[default]
+    AssociationEndpoint,
+    /// 设备接口，可以按接口类（`System.Devices.InterfaceClassGuid`）之类的过滤
+    DeviceInterface,
+}
+
+impl SelectorKind {
+    fn winrt_kind(self) -> DeviceInformationKind {
+        match self {
+            SelectorKind::AssociationEndpoint => DeviceInformationKind::AssociationEndpoint,
+            SelectorKind::DeviceInterface => DeviceInformationKind::DeviceInterface,
+        }
+    }
+}
+
+/// 用调用方自己的AQS过滤串枚举设备，超时返回`TimedOut`。
+///
+/// `kind`决定按哪种对象枚举，`aqs`换掉内置的蓝牙过滤，可以按那种对象的任意属性过滤。
+/// 取的属性和`scan_devices`一样，设备接口的地址用接口自己的蓝牙地址；
+/// 没有地址的结果会被跳过。`aqs`为空返回`InvalidSelector`。
+pub async fn discover_with_selector(
+    aqs: &str,
+    kind: SelectorKind,
+    timeout: Duration,
+) -> crate::Result<Vec<DeviceInfo>> {
+    if aqs.trim().is_empty() {
+        return Err(BluetoothError::InvalidSelector(aqs.to_string()));
+    }
+
+    let find = async {
+        let list = winrt_async(
+            DeviceInformation::FindAllAsyncWithKindAqsFilterAndAdditionalProperties(
+                &HSTRING::from(aqs),
+                &selector_properties(),
+                kind.winrt_kind(),
+            ),
+        )
+        .await?;
+
+        Ok::<_, BluetoothError>(
+            list.into_iter()
+                .filter_map(|info| raw_entry(&info))
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    match time::timeout(timeout, find).await {
+        Ok(entries) => Ok(map_device_entries(entries?)),
+        Err(_) => Err(BluetoothError::TimedOut {
+            duration: timeout,
+            operation: TimeoutOp::Discovery,
+        }),
+    }
+}
+
+/// 和`scan_devices`一样扫描附近设备，但可以用`cancel`提前结束（比如UI上的"停止扫描"）。
+///
+/// 超时或者取消时停掉扫描，返回到那一刻为止发现的设备，而不是报错。
+pub async fn scan_devices_with_cancel(
+    timeout: Duration,
+    cancel: CancellationToken,
+) -> crate::Result<Vec<DeviceInfo>> {
+    let live = live_devices()?;
+    Ok(collect_devices(live, timeout, cancel).await)
+}
+
+/// 扫描附近设备，发现`max`个就停，不用等满`timeout`，适合"随便找一个能用的"这种场景。
+///
+/// 超时时返回到那一刻为止发现的设备，可能不到`max`个。
+pub async fn discover_devices_limited(
+    max: usize,
+    timeout: Duration,
+) -> crate::Result<Vec<DeviceInfo>> {
+    let live = live_devices()?;
+    Ok(collect_devices_limited(live, max, timeout, CancellationToken::new()).await)
+}
+
+/// 持续扫描，设备出现、属性变化、消失都会作为`DeviceEvent`推出来，没有超时。
+///
+/// 返回的流被drop时会停掉底层的`DeviceWatcher`并注销回调。
+pub fn live_devices() -> crate::Result<LiveDevices> {
+    live_devices_inner(None)
+}
+
+/// 和`live_devices`一样，`cancel`被取消后立刻停掉watcher，流随之结束
+pub fn live_devices_with_cancel(cancel: CancellationToken) -> crate::Result<LiveDevices> {
+    live_devices_inner(Some(cancel))
+}
+
+/// 持续扫描，设备出现和消失时推出来，适合UI上边扫边显示。
+///
+/// 只是`live_devices`去掉了属性变化，流被drop时同样会停掉watcher。
+pub fn watch_devices() -> crate::Result<impl Stream<Item = DiscoveryEvent>> {
+    Ok(discovery_events(live_devices()?))
+}
+
+fn live_devices_inner(cancel: Option<CancellationToken>) -> crate::Result<LiveDevices> {
+    let watcher = winrt_error_wrap(
+        DeviceInformation::CreateWatcherWithKindAqsFilterAndAdditionalProperties(
+            &HSTRING::from(BLUETOOTH_PROTOCOL_SELECTOR),
+            &aep_properties(),
+            DeviceInformationKind::AssociationEndpoint,
+        ),
+    )?;
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let tracker = Arc::new(Mutex::new(DeviceTracker::default()));
+
+    let added = {
+        let (sender, tracker) = (sender.clone(), tracker.clone());
+        winrt_error_wrap(watcher.Added(&TypedEventHandler::new(
+            move |_: Ref<'_, DeviceWatcher>, info: Ref<'_, DeviceInformation>| {
+                if let Some(info) = info.as_ref()
+                    && let Some(device) = device_info(info)
+                {
+                    let id = info.Id()?.to_string();
+                    let event = tracker.lock().unwrap().added(&id, device);
+                    let _ = sender.send(event);
+                }
+                Ok(())
+            },
+        )))?
+    };
+
+    let updated = {
+        let (sender, tracker) = (sender.clone(), tracker.clone());
+        winrt_error_wrap(watcher.Updated(&TypedEventHandler::new(
+            move |_: Ref<'_, DeviceWatcher>, update: Ref<'_, DeviceInformationUpdate>| {
+                if let Some(update) = update.as_ref() {
+                    let id = update.Id()?.to_string();
+                    let changes = device_update(&update.Properties()?);
+                    if let Some(event) = tracker.lock().unwrap().updated(&id, changes) {
+                        let _ = sender.send(event);
+                    }
+                }
+                Ok(())
+            },
+        )))?
+    };
+
+    let removed = winrt_error_wrap(watcher.Removed(&TypedEventHandler::new(
+        move |_: Ref<'_, DeviceWatcher>, update: Ref<'_, DeviceInformationUpdate>| {
+            if let Some(update) = update.as_ref() {
+                let id = update.Id()?.to_string();
+                if let Some(event) = tracker.lock().unwrap().removed(&id) {
+                    let _ = sender.send(event);
+                }
+            }
+            Ok(())
+        },
+    )))?;
+
+    let live = LiveDevices::new(watcher, Some([added, updated, removed]), receiver, cancel);
+    winrt_none_error_wrap(live.watcher.Start())?;
+
+    Ok(live)
+}
+
+/// `live_devices`返回的事件流
+pub struct LiveDevices {
+    watcher: DeviceWatcher,
+    // Added、Updated、Removed三个回调的token，停掉之后是None
+    tokens: Option<[i64; 3]>,
+    receiver: mpsc::UnboundedReceiver<DeviceEvent>,
+    cancelled: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    // 取消以后就一直是结束状态，缓冲区里剩下的事件也不吐了
+    finished: bool,
+}
+
+impl LiveDevices {
+    pub(crate) fn new(
+        watcher: DeviceWatcher,
+        tokens: Option<[i64; 3]>,
+        receiver: mpsc::UnboundedReceiver<DeviceEvent>,
+        cancel: Option<CancellationToken>,
+    ) -> LiveDevices {
+        LiveDevices {
+            watcher,
+            tokens,
+            receiver,
+            cancelled: cancel.map(|cancel| Box::pin(cancel.cancelled_owned())),
+            finished: false,
+        }
+    }
+
+    /// 停止扫描：注销回调并停掉watcher，之后流会在吐完已经收到的事件后结束
+    pub fn stop(&mut self) {
+        if let Some([added, updated, removed]) = self.tokens.take() {
+            let _ = self.watcher.RemoveAdded(added);
+            let _ = self.watcher.RemoveUpdated(updated);
+            let _ = self.watcher.RemoveRemoved(removed);
+            let _ = self.watcher.Stop();
+        }
+        self.receiver.close();
+    }
+}
+
+impl Stream for LiveDevices {
+    type Item = DeviceEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.finished {
+            return Poll::Ready(None);
+        }
+
+        // 取消之后不再吐任何事件
+        if let Some(cancelled) = this.cancelled.as_mut()
+            && cancelled.poll_unpin(cx).is_ready()
+        {
+            this.cancelled = None;
+            this.finished = true;
+            this.stop();
+            return Poll::Ready(None);
+        }
+
+        this.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for LiveDevices {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+const AEP_PROPERTIES: [&str; 7] = [
+    ADDRESS_PROPERTY,
+    PAIRED_PROPERTY,
+    CONNECTED_PROPERTY,
+    SIGNAL_STRENGTH_PROPERTY,
+    LAST_SEEN_PROPERTY,
+    COD_MAJOR_PROPERTY,
+    COD_MINOR_PROPERTY,
+];
+
+fn aep_properties() -> IIterable<HSTRING> {
+    properties(&AEP_PROPERTIES)
+}
+
+// 自定义过滤时可能是设备接口，多要一个接口上的地址
+fn selector_properties() -> IIterable<HSTRING> {
+    let mut names = AEP_PROPERTIES.to_vec();
+    names.push(INTERFACE_ADDRESS_PROPERTY);
+    properties(&names)
+}
+
+fn properties(names: &[&str]) -> IIterable<HSTRING> {
+    IIterable::from(
+        names
+            .iter()
+            .map(|name| HSTRING::from(*name))
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn device_info(info: &DeviceInformation) -> Option<DeviceInfo> {
+    device_from_entry(raw_entry(info)?)
+}
+
+fn raw_entry(info: &DeviceInformation) -> Option<RawDeviceEntry> {
+    let props = info.Properties().ok()?;
+
+    Some(RawDeviceEntry {
+        name: info.Name().map(|name| name.to_string()).unwrap_or_default(),
+        address: lookup::<HSTRING>(&props, ADDRESS_PROPERTY)
+            .or_else(|| lookup::<HSTRING>(&props, INTERFACE_ADDRESS_PROPERTY))
+            .map(|addr| addr.to_string()),
+        properties: device_update(&props),
+        cod_major: lookup::<u16>(&props, COD_MAJOR_PROPERTY),
+        cod_minor: lookup::<u16>(&props, COD_MINOR_PROPERTY),
+    })
+}
+
+fn device_update(props: &IMapView<HSTRING, IInspectable>) -> DeviceUpdate {
+    DeviceUpdate {
+        name: None,
+        paired: lookup::<bool>(props, PAIRED_PROPERTY),
+        connected: lookup::<bool>(props, CONNECTED_PROPERTY),
+        rssi: lookup::<i32>(props, SIGNAL_STRENGTH_PROPERTY).map(|rssi| rssi as i16),
+        last_seen: lookup::<DateTime>(props, LAST_SEEN_PROPERTY).and_then(datetime_to_system_time),
+    }
+}
+
+// 属性不存在或者类型不对都当成没有
+fn lookup<T>(props: &IMapView<HSTRING, IInspectable>, name: &str) -> Option<T>
+where
+    T: windows::core::RuntimeType + 'static,
+{
+    let value = props.Lookup(&HSTRING::from(name)).ok()?;
+    value.cast::<IReference<T>>().ok()?.Value().ok()
+}
+
+// 系统给的时间可能是乱填的，减纪元、换算成纳秒、加到SystemTime上都可能溢出，溢出就当没有
+pub(crate) fn datetime_to_system_time(datetime: DateTime) -> Option<SystemTime> {
+    let ticks = datetime.UniversalTime.checked_sub(FILETIME_UNIX_EPOCH)?;
+    // 一个tick是100纳秒，拆成微秒加余数，不用乘法
+    let abs = ticks.unsigned_abs();
+    let offset = Duration::from_micros(abs / 10) + Duration::from_nanos(abs % 10 * 100);
+    if ticks >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(offset)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(offset)
+    }
+}
+
+/// 列出系统里已经配对的经典蓝牙设备
+pub async fn paired_devices() -> crate::Result<Vec<BluetoothDevice>> {
+    let filter =
+        winrt_error_wrap(Bluetooth::BluetoothDevice::GetDeviceSelectorFromPairingState(true))?;
+    let list = winrt_async(DeviceInformation::FindAllAsyncAqsFilter(&filter)).await?;
+
+    let mut devices = Vec::new();
+    for info in list {
+        let id = winrt_error_wrap(info.Id())?;
+        let device = winrt_async(Bluetooth::BluetoothDevice::FromIdAsync(&id)).await?;
+
+        let name = device
+            .Name()
+            .map(|name| name.to_string())
+            .unwrap_or_default();
+        let addr = winrt_error_wrap(device.BluetoothAddress())?;
+        devices.push(BluetoothDevice::new(name, addr));
+    }
+
+    Ok(devices)
+}
+
+/// 名字里包含`pattern`的已配对设备，不分大小写，给重连界面的搜索框用
+pub async fn paired_devices_matching(pattern: &str) -> crate::Result<Vec<BluetoothDevice>> {
+    Ok(devices_matching(paired_devices().await?, pattern))
+}
+
+/// 设备提供的所有RFCOMM服务的UUID
+pub async fn device_services(device: &BluetoothDevice) -> crate::Result<Vec<Uuid>> {
+    let winrt_device = winrt_async(Bluetooth::BluetoothDevice::FromBluetoothAddressAsync(
+        device.addr(),
+    ))
+    .await?;
+    let result = winrt_async(winrt_device.GetRfcommServicesAsync()).await?;
+    let services = winrt_error_wrap(result.Services())?;
+
+    let mut uuids = Vec::new();
+    for service in services {
+        let service_id = winrt_error_wrap(service.ServiceId())?;
+        let guid = service_id
+            .Uuid()
+            .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+        uuids.push(Uuid::from_u128(guid.to_u128()));
+    }
+
+    Ok(uuids)
+}
+
+/// 设备提供的所有RFCOMM服务，带着服务名，顺序和系统返回的一致。
+///
+/// 组合设备常常有好几个服务，连之前用它挑一个，再按UUID或者`connect_by_rfcomm_service_name`连。
+/// 每个服务都要多读一次SDP属性，比`device_services`慢
+pub async fn list_services(device: &BluetoothDevice) -> crate::Result<Vec<ServiceInfo>> {
+    let winrt_device = winrt_async(Bluetooth::BluetoothDevice::FromBluetoothAddressAsync(
+        device.addr(),
+    ))
+    .await?;
+    let result = winrt_async(winrt_device.GetRfcommServicesAsync()).await?;
+    let services = winrt_error_wrap(result.Services())?;
+
+    let mut infos = Vec::new();
+    for service in services {
+        let service_id = winrt_error_wrap(service.ServiceId())?;
+        let guid = service_id
+            .Uuid()
+            .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+        infos.push(ServiceInfo {
+            uuid: Uuid::from_u128(guid.to_u128()),
+            name: sdp_service_name(&service).await,
+        });
+    }
+
+    Ok(infos)
+}
+
+/// 所有已配对设备以及各自提供的服务，查服务失败的设备对应空列表
+pub async fn paired_devices_with_services() -> crate::Result<Vec<(BluetoothDevice, Vec<Uuid>)>> {
+    let devices = paired_devices().await?;
+
+    Ok(
+        resolve_services(devices, SERVICE_RESOLVE_CONCURRENCY, |device| async move {
+            device_services(&device).await
+        })
+        .await,
+    )
+}
+
+/// 已配对并且提供SPP服务的设备，"重连我那台串口设备"最常用的就是这个列表。
+///
+/// 服务查询并发进行（最多`SERVICE_RESOLVE_CONCURRENCY`个），查询失败的设备被当成不支持SPP跳过。
+pub async fn paired_spp_devices() -> crate::Result<Vec<BluetoothDevice>> {
+    let devices = paired_devices_with_services().await?;
+    Ok(devices_with_service(devices, SPP_UUID))
+}
@@ -0,0 +1,148 @@
+use tokio::{sync::mpsc, task::JoinHandle};
+use uuid::Uuid;
+use windows::{
+    Devices::Bluetooth::Rfcomm::RfcommServiceProvider,
+    Foundation::TypedEventHandler,
+    Networking::Sockets::{
+        SocketProtectionLevel, StreamSocket, StreamSocketListener,
+        StreamSocketListenerConnectionReceivedEventArgs,
+    },
+    core::Ref,
+};
+
+use crate::{
+    BluetoothError,
+    common::sdp::ServiceRecord,
+    windows::{
+        session::WinrtSession,
+        utils::{
+            winrt_async, winrt_async_action, winrt_error_wrap, winrt_none_error_wrap,
+            write_output_buffer,
+        },
+        uuid::create_service_id,
+    },
+};
+
+/// 服务端模式用的名字，和`RfcommListener`是同一个东西
+pub type SppListener = RfcommListener;
+
+pub struct RfcommListener {
+    uuid: Uuid,
+    provider: RfcommServiceProvider,
+    listener: StreamSocketListener,
+    token: i64,
+    receiver: Option<mpsc::UnboundedReceiver<StreamSocket>>,
+    handler_task: Option<JoinHandle<()>>,
+}
+
+impl RfcommListener {
+    /// 以`uuid`注册一个RFCOMM服务并开始广播，之后进来的连接通过`accept`或者`on_connection`拿到
+    pub async fn bind(uuid: Uuid) -> crate::Result<RfcommListener> {
+        Self::bind_with_record(ServiceRecord::new(uuid)).await
+    }
+
+    /// 和`bind`一样，但会把`record`里的服务名和其他SDP属性一起发布出去
+    pub async fn bind_with_record(record: ServiceRecord) -> crate::Result<RfcommListener> {
+        let uuid = record.uuid;
+        let service_id = winrt_error_wrap(create_service_id(uuid))?;
+        let provider = winrt_async(RfcommServiceProvider::CreateAsync(&service_id)).await?;
+
+        // SDP属性必须在StartAdvertising之前设置好
+        let sdp_attributes = winrt_error_wrap(provider.SdpRawAttributes())?;
+        for (id, data) in record.raw_attributes() {
+            let buffer = winrt_error_wrap(write_output_buffer(data))?;
+            winrt_error_wrap(sdp_attributes.Insert(id, &buffer))?;
+        }
+
+        let listener = winrt_error_wrap(StreamSocketListener::new())?;
+
+        // 新连接进来时先塞进channel，由accept或者回调任务去取
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let token = winrt_error_wrap(listener.ConnectionReceived(&TypedEventHandler::new(
+            move |_: Ref<'_, StreamSocketListener>,
+                  args: Ref<'_, StreamSocketListenerConnectionReceivedEventArgs>| {
+                if let Some(args) = args.as_ref() {
+                    let _ = sender.send(args.Socket()?);
+                }
+
+                Ok(())
+            },
+        )))?;
+
+        winrt_async_action(listener.BindServiceNameWithProtectionLevelAsync(
+            &winrt_error_wrap(service_id.AsString())?,
+            SocketProtectionLevel::BluetoothEncryptionAllowNullAuthentication,
+        ))
+        .await?;
+
+        winrt_none_error_wrap(provider.StartAdvertising(&listener))?;
+
+        Ok(RfcommListener {
+            uuid,
+            provider,
+            listener,
+            token,
+            receiver: Some(receiver),
+            handler_task: None,
+        })
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// 等待下一个进来的连接。
+    ///
+    /// 可以放心地放进`tokio::select!`：没等到就被取消时不会丢连接，下次`accept`照样能拿到。
+    pub async fn accept(&mut self) -> crate::Result<WinrtSession> {
+        let receiver = match self.receiver.as_mut() {
+            Some(receiver) => receiver,
+            // 已经交给on_connection的回调了
+            None => return Err(callback_mode_error()),
+        };
+
+        match receiver.recv().await {
+            Some(socket) => Ok(WinrtSession::from_socket(socket)),
+            None => Err(BluetoothError::NotConnected),
+        }
+    }
+
+    /// 改成回调模式：起一个任务把每个进来的连接交给`handler`，之后`accept`就不能用了。
+    ///
+    /// 只能调一次，已经是回调模式时返回错误，`handler`不会被用到。
+    /// 任务跟着listener走，listener被drop时会一起取消。需要在tokio runtime里调用。
+    pub fn on_connection(
+        &mut self,
+        mut handler: impl FnMut(WinrtSession) + Send + 'static,
+    ) -> crate::Result<()> {
+        let mut receiver = self.receiver.take().ok_or_else(callback_mode_error)?;
+
+        self.handler_task = Some(tokio::spawn(async move {
+            while let Some(socket) = receiver.recv().await {
+                handler(WinrtSession::from_socket(socket));
+            }
+        }));
+
+        Ok(())
+    }
+}
+
+// 连接都交给on_connection的回调了，accept和第二次on_connection都拿不到
+fn callback_mode_error() -> BluetoothError {
+    BluetoothError::RuntimeError(
+        "Listener is already in callback mode, connections go to the on_connection handler"
+            .to_string(),
+    )
+}
+
+impl Drop for RfcommListener {
+    fn drop(&mut self) {
+        if let Some(task) = self.handler_task.take() {
+            task.abort();
+        }
+
+        let _ = self.listener.RemoveConnectionReceived(self.token);
+        let _ = self.provider.StopAdvertising();
+        let _ = self.listener.Close();
+    }
+}
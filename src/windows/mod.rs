@@ -1,3 +1,5 @@
+pub mod discovery;
+pub mod listener;
 pub mod pair;
 pub mod session;
 pub mod utils;
@@ -6,15 +8,39 @@ pub mod uuid;
 #[cfg(test)]
 mod tests {
 
-    use std::time::Duration;
+    use std::{
+        cell::RefCell,
+        time::{Duration, SystemTime},
+    };
 
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::StreamExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc,
+    };
     use tokio_test::block_on;
+    use tokio_util::sync::CancellationToken;
+    use windows::{Devices::Enumeration::DeviceInformation, Foundation::DateTime};
 
     use crate::{
-        BluetoothSppSession,
-        common::device::{BluetoothDevice, SPP_UUID},
-        windows::{session::WinrtSession, utils::hex_stream_to_bytes, uuid::create_service_id},
+        BluetoothError, BluetoothSppSession, SessionState,
+        common::{
+            device::{BluetoothDevice, DeviceInfo, SPP_UUID},
+            discovery::DeviceEvent,
+        },
+        windows::{
+            discovery::{
+                LiveDevices, SelectorKind, datetime_to_system_time, discover_with_selector,
+            },
+            listener::RfcommListener,
+            session::{WinrtSession, read_request_size},
+            utils::{
+                E_SHARING_VIOLATION, SocketBufferControl, apply_outbound_buffer_size,
+                fill_output_buffer, hex_stream_to_bytes, is_peer_disconnect, put_read_data,
+                read_input_buffer, winrt_connect_error, winrt_write_error, write_output_buffer,
+            },
+            uuid::create_service_id,
+        },
     };
 
     #[test]
@@ -43,11 +69,8 @@ mod tests {
     #[test]
     fn test_connect() {
         let mut winrt = WinrtSession::new();
-        let device = BluetoothDevice::new_by_addr_string(
-            "Test".to_string(),
-            &"D0:AE:05:05:1A:22".to_string(),
-        )
-        .unwrap();
+        let device =
+            BluetoothDevice::new_by_addr_string("Test".to_string(), "D0:AE:05:05:1A:22").unwrap();
 
         let err = winrt.connect_timeout(&device, true, Duration::from_secs(500));
         if let Err(e) = err {
@@ -70,4 +93,295 @@ mod tests {
             println!("{:?}", res);
         })
     }
+
+    #[test]
+    fn test_abandon_reads_stress() {
+        let mut winrt = WinrtSession::new();
+        let device =
+            BluetoothDevice::new_by_addr_string("Test".to_string(), "D0:AE:05:05:1A:22").unwrap();
+
+        // 没有真实设备就跳过
+        if winrt
+            .connect_timeout(&device, true, Duration::from_secs(10))
+            .is_err()
+        {
+            return;
+        }
+
+        // 反复发起读、poll一次就扔掉，每次都要先Cancel再丢缓冲区
+        for _ in 0..200 {
+            let mut buf = [0u8; 64];
+            let mut read = tokio_test::task::spawn(winrt.read(&mut buf));
+            let _ = read.poll();
+            drop(read);
+            winrt.cancel_read();
+        }
+
+        block_on(async {
+            let _ = winrt.write(&[0x00]).await;
+        });
+        winrt.disconnect();
+    }
+
+    #[test]
+    fn test_listener_on_connection_drop() {
+        block_on(async {
+            // 没有蓝牙适配器时注册服务会失败，直接跳过
+            let mut listener = match RfcommListener::bind(SPP_UUID).await {
+                Ok(listener) => listener,
+                Err(_) => return,
+            };
+
+            listener.on_connection(drop).unwrap();
+
+            // 回调模式下再注册或者accept都要报错，不能悄悄吞掉
+            assert!(matches!(
+                listener.on_connection(drop),
+                Err(BluetoothError::RuntimeError(_))
+            ));
+            assert!(matches!(
+                listener.accept().await,
+                Err(BluetoothError::RuntimeError(_))
+            ));
+            drop(listener);
+
+            // 让被取消的任务有机会跑完
+            tokio::task::yield_now().await;
+        })
+    }
+
+    #[test]
+    fn test_drop_unconnected_session() {
+        // new()之后没连过，drop不能panic
+        drop(WinrtSession::new());
+
+        let device = WinrtSession::new().into_device();
+        assert_eq!(device.addr(), 0);
+    }
+
+    #[test]
+    fn test_effective_io_sizes() {
+        let mut session = WinrtSession::new();
+        assert_eq!(session.effective_io_sizes().read_chunk, Some(4096));
+        assert_eq!(session.effective_io_sizes().write_chunk, None);
+
+        session.set_max_write_chunk(Some(512));
+        assert_eq!(session.effective_io_sizes().write_chunk, Some(512));
+    }
+
+    #[test]
+    fn test_is_connected_unconnected_session() {
+        let session = WinrtSession::new();
+        assert!(!session.is_connected());
+        assert_eq!(session.state(), SessionState::Disconnected);
+    }
+
+    #[test]
+    fn test_watch_connection_status_not_connected() {
+        let session = WinrtSession::new();
+        assert!(matches!(
+            session.watch_connection_status(),
+            Err(BluetoothError::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn test_flush_not_connected() {
+        let mut session = WinrtSession::new();
+        let err = block_on(session.flush()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_device_details_not_connected() {
+        let session = WinrtSession::new();
+        assert!(matches!(
+            session.device_details(),
+            Err(BluetoothError::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn test_outbound_buffer_size() {
+        struct StubControl(RefCell<Vec<u32>>);
+
+        impl SocketBufferControl for StubControl {
+            fn set_outbound_buffer_size(&self, size: u32) -> windows::core::Result<()> {
+                self.0.borrow_mut().push(size);
+                Ok(())
+            }
+        }
+
+        let mut winrt = WinrtSession::new();
+        assert_eq!(winrt.outbound_buffer_size(), None);
+        winrt.set_outbound_buffer_size(128 * 1024).unwrap();
+        assert_eq!(winrt.outbound_buffer_size(), Some(128 * 1024));
+
+        let stub = StubControl(RefCell::new(Vec::new()));
+        apply_outbound_buffer_size(&stub, winrt.outbound_buffer_size().unwrap()).unwrap();
+        assert_eq!(stub.0.into_inner(), [128 * 1024]);
+    }
+
+    #[test]
+    fn test_read_request_size_capped() {
+        // 1MB的ReadBuf也只发起一个池缓冲区大小的读
+        assert_eq!(read_request_size(1 << 20, 4096), 4096);
+        assert_eq!(read_request_size(4096, 4096), 4096);
+        assert_eq!(read_request_size(16, 4096), 16);
+        assert_eq!(read_request_size(1 << 20, 64 * 1024), 64 * 1024);
+    }
+
+    #[test]
+    fn test_set_read_chunk_size() {
+        let mut session = WinrtSession::new();
+        assert_eq!(session.effective_io_sizes().read_chunk, Some(4096));
+
+        session.set_read_chunk_size(512);
+        assert_eq!(session.effective_io_sizes().read_chunk, Some(512));
+
+        session.set_read_chunk_size(0);
+        assert_eq!(session.effective_io_sizes().read_chunk, Some(1));
+    }
+
+    #[test]
+    fn test_sharing_violation_error() {
+        let err = winrt_connect_error(windows::core::Error::from_hresult(E_SHARING_VIOLATION));
+        assert!(matches!(err, BluetoothError::ConnectionRefused(_)));
+
+        // 其他HRESULT还是走通用的RuntimeError
+        let err = winrt_connect_error(windows::core::Error::from_hresult(windows::core::HRESULT(
+            0x80004005_u32 as i32,
+        )));
+        assert!(matches!(err, BluetoothError::RuntimeError(_)));
+    }
+
+    #[test]
+    fn test_peer_disconnect_errors() {
+        use windows::core::{Error, HRESULT};
+
+        // WSAECONNRESET：读当EOF，写报BrokenPipe
+        let reset = Error::from_hresult(HRESULT(0x80072746_u32 as i32));
+        assert!(is_peer_disconnect(&reset));
+        assert_eq!(
+            winrt_write_error(reset).kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+
+        let other = Error::from_hresult(HRESULT(0x80004005_u32 as i32));
+        assert!(!is_peer_disconnect(&other));
+        assert_ne!(
+            winrt_write_error(other).kind(),
+            std::io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_read_input_buffer_uses_length() {
+        use windows::Storage::Streams::Buffer;
+
+        // 容量8字节但只有前3个有效
+        let buffer = write_output_buffer(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        buffer.SetLength(3).unwrap();
+        assert_eq!(read_input_buffer(buffer).unwrap(), vec![1, 2, 3]);
+
+        // 刚创建的缓冲区Length是0，一个字节都不该读出来
+        let empty = Buffer::Create(16).unwrap();
+        assert!(read_input_buffer(empty.into()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fill_output_buffer_reuses_buffer() {
+        use windows::Storage::Streams::Buffer;
+
+        // 同一个缓冲区反复填，Length跟着最后一次写入走
+        let buffer = Buffer::Create(8).unwrap();
+        fill_output_buffer(&buffer, &[1, 2, 3, 4, 5]).unwrap();
+        fill_output_buffer(&buffer, &[9, 8]).unwrap();
+        assert_eq!(
+            read_input_buffer(buffer.clone().into()).unwrap(),
+            vec![9, 8]
+        );
+
+        // 超过容量的不能填
+        assert!(fill_output_buffer(&buffer, &[0; 9]).is_err());
+    }
+
+    #[test]
+    fn test_put_read_data_after_prefilled() {
+        let mut storage = [0u8; 8];
+        let mut buf = tokio::io::ReadBuf::new(&mut storage);
+        buf.put_slice(&[0xAA, 0xBB]);
+
+        // WinRT读按剩下的6个字节发起
+        assert_eq!(buf.remaining(), 6);
+
+        let rest = put_read_data(&mut buf, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(buf.filled(), &[0xAA, 0xBB, 1, 2, 3, 4, 5, 6]);
+        // 多出来的留到下次
+        assert_eq!(rest, vec![7]);
+    }
+
+    #[test]
+    fn test_live_devices_end_after_cancel() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let watcher = DeviceInformation::CreateWatcher().unwrap();
+        let cancel = CancellationToken::new();
+        let mut live = LiveDevices::new(watcher, None, receiver, Some(cancel.clone()));
+
+        let event = |addr| {
+            DeviceEvent::Added(DeviceInfo::observed(
+                BluetoothDevice::new(String::new(), addr),
+                None,
+            ))
+        };
+        sender.send(event(1)).unwrap();
+        sender.send(event(2)).unwrap();
+
+        block_on(async {
+            assert!(live.next().await.is_some());
+
+            // 取消以后缓冲区里还有一个事件，也不能再吐出来
+            cancel.cancel();
+            assert!(live.next().await.is_none());
+            assert!(live.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_discover_with_empty_selector() {
+        assert_eq!(SelectorKind::default(), SelectorKind::AssociationEndpoint);
+
+        // 不管按哪种对象枚举，空的过滤串都不发给系统
+        for kind in [
+            SelectorKind::AssociationEndpoint,
+            SelectorKind::DeviceInterface,
+        ] {
+            let result = block_on(discover_with_selector(" ", kind, Duration::from_secs(1)));
+            assert!(matches!(result, Err(BluetoothError::InvalidSelector(_))));
+        }
+    }
+
+    #[test]
+    fn test_datetime_to_system_time() {
+        let at = |ticks| {
+            datetime_to_system_time(DateTime {
+                UniversalTime: ticks,
+            })
+        };
+        let epoch = 116_444_736_000_000_000;
+
+        assert_eq!(at(epoch), Some(SystemTime::UNIX_EPOCH));
+        assert_eq!(
+            at(epoch + 15),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(1_500))
+        );
+        assert_eq!(
+            at(epoch - 15),
+            Some(SystemTime::UNIX_EPOCH - Duration::from_nanos(1_500))
+        );
+
+        // 乱填的极端值不能panic
+        at(i64::MAX);
+        assert_eq!(at(i64::MIN), None);
+    }
 }
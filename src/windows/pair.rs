@@ -1,23 +1,181 @@
+use std::sync::Arc;
+
 use windows::{
-    Devices::Enumeration::{
-        DeviceInformationCustomPairing, DevicePairingKinds, DevicePairingRequestedEventArgs,
+    Devices::{
+        Bluetooth,
+        Enumeration::{
+            DeviceInformation, DeviceInformationCustomPairing, DeviceInformationPairing,
+            DevicePairingKinds, DevicePairingRequestedEventArgs, DevicePairingResultStatus,
+            DeviceUnpairingResultStatus,
+        },
+    },
+    Foundation::TypedEventHandler,
+    core::{HSTRING, Ref},
+};
+
+use crate::{
+    BluetoothError,
+    common::{
+        device::BluetoothDevice,
+        pairing::{DefaultAgent, PairingAgent, PairingBackend, PairingError, repair_with},
     },
-    core::Ref,
+    windows::utils::{winrt_async_with_error, winrt_error_wrap_with_error},
 };
 
+/// 按`DefaultAgent`处理配对请求：只接受ConfirmOnly，要PIN的请求不再一律Accept，
+/// 要PIN的设备用`PairingConfig::with_pin`或者自己的agent连接
 pub fn pair_handler(
     _pairing: Ref<'_, DeviceInformationCustomPairing>,
     args: Ref<'_, DevicePairingRequestedEventArgs>,
 ) -> windows::core::Result<()> {
-    if let Some(args) = args.as_ref() {
-        match args.PairingKind()? {
-            // 目前只处理直接就能配对的
-            DevicePairingKinds::ConfirmOnly => args.Accept()?,
+    match args.as_ref() {
+        Some(args) => agent_pair_handler(&DefaultAgent, &BluetoothDevice::empty(), args),
+        None => Ok(()),
+    }
+}
 
-            // TODO
-            _ => args.Accept()?,
+// 把系统的配对请求转给agent，不Accept就等于拒绝
+fn agent_pair_handler(
+    agent: &dyn PairingAgent,
+    device: &BluetoothDevice,
+    args: &DevicePairingRequestedEventArgs,
+) -> windows::core::Result<()> {
+    match args.PairingKind()? {
+        DevicePairingKinds::ConfirmOnly if agent.confirm(device) => args.Accept()?,
+        DevicePairingKinds::ProvidePin => {
+            if let Some(pin) = agent.provide_pin(device) {
+                args.AcceptWithPin(&HSTRING::from(pin))?;
+            }
+        }
+        DevicePairingKinds::ConfirmPinMatch
+            if agent.confirm_pin(device, &args.Pin()?.to_string()) =>
+        {
+            args.Accept()?
+        }
+        DevicePairingKinds::DisplayPin => {
+            agent.display_pin(device, &args.Pin()?.to_string());
+            args.Accept()?;
         }
+        _ => {}
     }
 
     Ok(())
 }
+
+pub(crate) struct WinrtPairing {
+    device: BluetoothDevice,
+    pairing: DeviceInformationPairing,
+}
+
+impl WinrtPairing {
+    pub(crate) fn new(device: BluetoothDevice, pairing: DeviceInformationPairing) -> WinrtPairing {
+        WinrtPairing { device, pairing }
+    }
+}
+
+impl PairingBackend for WinrtPairing {
+    async fn is_paired(&mut self) -> Result<bool, PairingError> {
+        self.pairing
+            .IsPaired()
+            .map_err(|err| PairingError::PairFailed(err.to_string()))
+    }
+
+    async fn unpair(&mut self) -> Result<(), PairingError> {
+        let result = self
+            .pairing
+            .UnpairAsync()
+            .map_err(|err| PairingError::UnpairFailed(err.to_string()))?
+            .await
+            .map_err(|err| PairingError::UnpairFailed(err.to_string()))?;
+
+        match result.Status() {
+            Ok(DeviceUnpairingResultStatus::Unpaired)
+            | Ok(DeviceUnpairingResultStatus::AlreadyUnpaired) => Ok(()),
+            Ok(status) => Err(PairingError::UnpairFailed(format!("{:?}", status))),
+            Err(err) => Err(PairingError::UnpairFailed(err.to_string())),
+        }
+    }
+
+    async fn pair(&mut self, agent: Arc<dyn PairingAgent>) -> Result<(), PairingError> {
+        let error = |err: windows::core::Error| PairingError::PairFailed(err.to_string());
+
+        if !self.pairing.CanPair().map_err(error)? {
+            return Err(PairingError::NotPairable);
+        }
+
+        let custom = self.pairing.Custom().map_err(error)?;
+
+        let device = self.device.clone();
+        let handler = custom
+            .PairingRequested(&TypedEventHandler::new(
+                move |_: Ref<'_, DeviceInformationCustomPairing>,
+                      args: Ref<'_, DevicePairingRequestedEventArgs>| {
+                    match args.as_ref() {
+                        Some(args) => agent_pair_handler(agent.as_ref(), &device, args),
+                        None => Ok(()),
+                    }
+                },
+            ))
+            .map_err(error)?;
+
+        let result = async {
+            custom
+                .PairAsync(
+                    DevicePairingKinds::ConfirmOnly
+                        | DevicePairingKinds::ProvidePin
+                        | DevicePairingKinds::ConfirmPinMatch
+                        | DevicePairingKinds::DisplayPin,
+                )
+                .map_err(error)?
+                .await
+                .map_err(error)
+        }
+        .await;
+
+        let _ = custom.RemovePairingRequested(handler);
+
+        match result?.Status().map_err(error)? {
+            DevicePairingResultStatus::Paired | DevicePairingResultStatus::AlreadyPaired => Ok(()),
+            DevicePairingResultStatus::RejectedByHandler => Err(PairingError::Rejected),
+            status => Err(PairingError::PairFailed(format!("{:?}", status))),
+        }
+    }
+}
+
+// 按地址找到系统里的设备，拿到它的配对对象
+pub(crate) async fn device_pairing(
+    device: &BluetoothDevice,
+) -> crate::Result<DeviceInformationPairing> {
+    let filter = winrt_error_wrap_with_error(
+        Bluetooth::BluetoothDevice::GetDeviceSelectorFromBluetoothAddress(device.addr()),
+        BluetoothError::DeviceNotFound,
+    )?;
+
+    let list = winrt_async_with_error(
+        DeviceInformation::FindAllAsyncAqsFilter(&filter),
+        BluetoothError::DeviceNotFound,
+    )
+    .await?;
+
+    if winrt_error_wrap_with_error(list.Size(), BluetoothError::DeviceNotFound)? < 1 {
+        return Err(BluetoothError::DeviceNotFound);
+    }
+
+    let info = winrt_error_wrap_with_error(list.GetAt(0), BluetoothError::DeviceNotFound)?;
+    winrt_error_wrap_with_error(info.Pairing(), BluetoothError::DeviceNotFound)
+}
+
+/// 强制重新配对：已经配对的先解除，再用`agent`走一遍配对。配对信息坏掉时用这个代替手动去设置里删设备。
+pub async fn repair(device: &BluetoothDevice, agent: Arc<dyn PairingAgent>) -> crate::Result<()> {
+    let mut backend = WinrtPairing::new(device.clone(), device_pairing(device).await?);
+
+    repair_with(&mut backend, agent).await
+}
+
+/// 解除配对，系统里不认识这个设备时返回`DeviceNotFound`，本来就没配对算成功。
+/// 配对密钥过期、一直重连失败时先解除再重新配对，要一步做完用`repair`
+pub async fn unpair_device(device: &BluetoothDevice) -> crate::Result<()> {
+    let mut backend = WinrtPairing::new(device.clone(), device_pairing(device).await?);
+
+    Ok(backend.unpair().await?)
+}
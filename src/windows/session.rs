@@ -1,43 +1,120 @@
-use std::{future::IntoFuture, pin::Pin, task::Poll};
+use std::{
+    future::IntoFuture,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use futures::Stream;
 
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    runtime::Builder,
+    sync::mpsc,
     time,
 };
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 use windows::{
     Devices::{
-        Bluetooth::{self},
-        Enumeration::{DeviceInformation, DevicePairingKinds},
+        Bluetooth::{self, BluetoothConnectionStatus, Rfcomm::RfcommDeviceService},
+        Enumeration::DeviceInformation,
     },
     Foundation::TypedEventHandler,
-    Networking::Sockets::StreamSocket,
-    Storage::Streams::{Buffer, IBuffer, InputStreamOptions},
+    Networking::{HostName, Sockets::StreamSocket},
+    Storage::Streams::{Buffer, DataWriter, IBuffer, InputStreamOptions},
+    core::{HSTRING, IInspectable, Ref},
 };
+use windows_future::IAsyncOperationWithProgress;
 
 use crate::{
-    BluetoothError, BluetoothSppSession,
-    common::device::{BluetoothDevice, SPP_UUID},
+    BluetoothError, BluetoothSppSession, IoSizes, SessionCapabilities, SessionState, TimeoutOp,
+    common::{
+        device::{BluetoothDevice, DeviceDetails, SPP_UUID, session_summary},
+        diagnostics::{ConnectDiagnostics, StepOutcome},
+        discovery::connect_or_resolve,
+        mac::mac_string_to_u64,
+        pairing::{PairingConfig, PairingError, pair_if_needed},
+        pool::BufferPool,
+        profile::ConnectionProfile,
+        runtime::{ConnectAffinity, block_on},
+        sdp::{SERVICE_NAME_ATTRIBUTE_ID, decode_text_attribute, select_service_by_name},
+        stats::{LatencyStats, SessionStats},
+        status::{ConnectionStatus, StatusChanges, validate_link},
+    },
     windows::{
-        pair::pair_handler,
+        pair::WinrtPairing,
         utils::{
-            read_input_buffer, winrt_async, winrt_async_action, winrt_async_with_error,
-            winrt_error_wrap, winrt_error_wrap_with_error, winrt_none_error_wrap_with_error,
-            write_output_buffer,
+            apply_outbound_buffer_size, fill_output_buffer, is_peer_disconnect, put_read_data,
+            read_input_buffer, winrt_async, winrt_async_with_error, winrt_connect_error,
+            winrt_error_wrap, winrt_error_wrap_with_error, winrt_io_error, winrt_none_error_wrap,
+            winrt_write_bluetooth_error, winrt_write_error,
         },
         uuid::create_service_id,
     },
 };
 
+// 池里缓冲区的大小，不超过它的读写都从池里拿
+const POOLED_BUFFER_SIZE: u32 = 4096;
+// 同一时间最多一个读一个写在跑，多留两个够用了
+const BUFFER_POOL_LIMIT: usize = 4;
+
 pub struct WinrtSession {
     uuid: Uuid,
     device: BluetoothDevice,
+    label: String,
+    affinity: ConnectAffinity,
     socket: StreamSocket,
+    // 最近一次连接找到的WinRT设备对象，查设备详情用
+    winrt_device: Option<Bluetooth::BluetoothDevice>,
+    // winrt_device上ConnectionStatusChanged回调的token
+    status_token: Option<i64>,
+    status_changes: StatusChanges,
+    // watch_connection_status的流看到断开时置上，下次读写时把ready清掉
+    link_lost: Arc<AtomicBool>,
+    // 打开了诊断时记录最近一次连接的每一步
+    diagnostics: Option<ConnectDiagnostics>,
     ready: bool,
+    state: SessionState,
+    // 留着读操作本身，取消时要调它的Cancel
+    read_op: Option<IAsyncOperationWithProgress<IBuffer, u32>>,
     // 持有正在进行的WinRT future，避免在poll中阻塞等待
-    read_future: Option<Pin<Box<dyn std::future::Future<Output = windows::core::Result<IBuffer>>>>>,
-    write_future: Option<Pin<Box<dyn std::future::Future<Output = windows::core::Result<u32>>>>>,
+    read_future:
+        Option<Pin<Box<dyn std::future::Future<Output = windows::core::Result<IBuffer>> + Send>>>,
+    write_future:
+        Option<Pin<Box<dyn std::future::Future<Output = windows::core::Result<u32>> + Send>>>,
+    // 和read_op一样，扔掉write_future之前要先Cancel
+    write_op: Option<IAsyncOperationWithProgress<u32, u32>>,
+    // poll_flush发起的FlushAsync，没有缓冲区要保活，直接扔掉就行
+    flush_future:
+        Option<Pin<Box<dyn std::future::Future<Output = windows::core::Result<bool>> + Send>>>,
+    // 当前这次写是什么时候发起的，算写耗时用
+    write_started: Option<std::time::Instant>,
+    auto_flush: bool,
+    // 连接前要设到socket上的发送缓冲区大小，None用系统默认
+    outbound_buffer_size: Option<u32>,
+    max_write_chunk: Option<usize>,
+    // 一次ReadAsync最多读多少字节
+    read_chunk_size: u32,
+    validate_after_connect: Option<Duration>,
+    // connect_with_cancel期间的取消令牌，每一步之间检查一次
+    cancel: Option<CancellationToken>,
+    buffer_pool: BufferPool<Buffer>,
+    // 正在被读写操作占用的池缓冲区，操作正常完成后才还回池里
+    read_buffer: Option<Buffer>,
+    write_buffer: Option<Buffer>,
+    stats: SessionStats,
+    // 上一次读到但调用方缓冲区放不下的数据，下次读先给它
+    leftover: Vec<u8>,
+    // 上次连接解析出的服务名和本地适配器，导出ConnectionProfile用
+    service_name: Option<String>,
+    adapter: Option<String>,
+    // pause_reads之后挂起的读，resume时唤醒
+    reads_paused: bool,
+    paused_read_waker: Option<std::task::Waker>,
 }
 
 impl WinrtSession {
@@ -45,77 +122,235 @@ impl WinrtSession {
         return WinrtSession {
             uuid: SPP_UUID,
             device: BluetoothDevice::empty(),
+            label: String::new(),
+            affinity: ConnectAffinity::default(),
             socket: StreamSocket::new().unwrap(),
+            winrt_device: None,
+            status_token: None,
+            status_changes: StatusChanges::new(),
+            link_lost: Arc::new(AtomicBool::new(false)),
+            diagnostics: None,
             ready: false,
+            state: SessionState::Disconnected,
+            read_op: None,
+            write_op: None,
             read_future: None,
             write_future: None,
+            flush_future: None,
+            write_started: None,
+            auto_flush: false,
+            outbound_buffer_size: None,
+            max_write_chunk: None,
+            read_chunk_size: POOLED_BUFFER_SIZE,
+            validate_after_connect: None,
+            cancel: None,
+            buffer_pool: BufferPool::new(BUFFER_POOL_LIMIT),
+            read_buffer: None,
+            write_buffer: None,
+            stats: SessionStats::new(),
+            leftover: Vec::new(),
+            service_name: None,
+            adapter: None,
+            reads_paused: false,
+            paused_read_waker: None,
         };
     }
-}
 
-impl BluetoothSppSession for WinrtSession {
-    fn connect(&mut self, device: &BluetoothDevice, need_pairing: bool) -> crate::Result<()> {
-        self.connect_by_uuid(device, SPP_UUID, need_pairing)
+    // 用listener收到的socket直接构造一个已经连上的会话
+    pub(crate) fn from_socket(socket: StreamSocket) -> WinrtSession {
+        // 远端地址形如"(D0:AE:05:05:1A:22)"
+        let remote = socket
+            .Information()
+            .and_then(|info| info.RemoteAddress())
+            .and_then(|host| host.RawName())
+            .map(|name| name.to_string())
+            .unwrap_or_default();
+        let addr = remote.trim_matches(|c| c == '(' || c == ')').to_string();
+
+        WinrtSession {
+            uuid: SPP_UUID,
+            device: BluetoothDevice::new("".to_string(), mac_string_to_u64(&addr).unwrap_or(0)),
+            label: String::new(),
+            affinity: ConnectAffinity::default(),
+            socket,
+            winrt_device: None,
+            status_token: None,
+            status_changes: StatusChanges::new(),
+            link_lost: Arc::new(AtomicBool::new(false)),
+            diagnostics: None,
+            ready: true,
+            state: SessionState::Connected,
+            read_op: None,
+            write_op: None,
+            read_future: None,
+            write_future: None,
+            flush_future: None,
+            write_started: None,
+            auto_flush: false,
+            outbound_buffer_size: None,
+            max_write_chunk: None,
+            read_chunk_size: POOLED_BUFFER_SIZE,
+            validate_after_connect: None,
+            cancel: None,
+            buffer_pool: BufferPool::new(BUFFER_POOL_LIMIT),
+            read_buffer: None,
+            write_buffer: None,
+            stats: SessionStats::new(),
+            leftover: Vec::new(),
+            service_name: None,
+            adapter: None,
+            reads_paused: false,
+            paused_read_waker: None,
+        }
     }
 
-    fn connect_timeout(
-        &mut self,
-        device: &BluetoothDevice,
-        need_pairing: bool,
-        timeout: std::time::Duration,
-    ) -> crate::Result<()> {
-        self.connect_by_uuid_timeout(device, SPP_UUID, need_pairing, timeout)
+    // 订阅设备的连接状态变化，推到status_changes里
+    fn watch_status(&mut self, device: &Bluetooth::BluetoothDevice) -> crate::Result<()> {
+        let sender = self.status_changes.sender();
+        let token = winrt_error_wrap(device.ConnectionStatusChanged(&TypedEventHandler::new(
+            move |device: Ref<'_, Bluetooth::BluetoothDevice>, _: Ref<'_, IInspectable>| {
+                if let Some(device) = device.as_ref() {
+                    let _ = sender.send(connection_status(device.ConnectionStatus()?));
+                }
+                Ok(())
+            },
+        )))?;
+        self.status_token = Some(token);
+        Ok(())
     }
 
-    fn connect_by_uuid(
-        &mut self,
-        device: &BluetoothDevice,
-        uuid: Uuid,
-        need_pairing: bool,
-    ) -> crate::Result<()> {
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+    // 注销旧设备上的回调，没取走的旧变化也扔掉
+    fn unwatch_status(&mut self) {
+        if let (Some(device), Some(token)) = (self.winrt_device.take(), self.status_token.take()) {
+            let _ = device.RemoveConnectionStatusChanged(token);
+        }
+        self.status_changes.clear();
+    }
 
-        rt.block_on(async { self.connect_by_uuid_async(device, uuid, need_pairing).await })
+    // 连上了就把上一条链路留下的断开标记清掉
+    fn mark_ready(&mut self) {
+        self.link_lost.store(false, Ordering::Relaxed);
+        self.ready = true;
     }
 
-    fn connect_by_uuid_timeout(
-        &mut self,
-        device: &BluetoothDevice,
-        uuid: Uuid,
-        need_pairing: bool,
-        timeout: std::time::Duration,
-    ) -> crate::Result<()> {
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+    // 状态流报过断开的话，从现在起按断开处理
+    fn sync_link_lost(&mut self) {
+        if self.link_lost.swap(false, Ordering::Relaxed) {
+            self.ready = false;
+        }
+    }
 
-        let result = rt.block_on(async {
-            time::timeout(timeout, async {
-                self.connect_by_uuid_async(device, uuid, need_pairing).await
-            })
-            .await
-        });
+    fn begin_step(&mut self, name: &'static str) {
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            diagnostics.begin(name);
+        }
+    }
 
-        if let Err(_) = result {
-            return Err(BluetoothError::TimedOut(timeout));
-        } else if let Ok(Err(err)) = result {
-            return Err(err);
+    fn end_step(&mut self, outcome: StepOutcome) {
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            diagnostics.end(outcome);
         }
+    }
 
-        return Ok(());
+    fn end_step_with<T>(&mut self, result: &crate::Result<T>) {
+        if let Some(diagnostics) = self.diagnostics.as_mut() {
+            diagnostics.end_with(result);
+        }
     }
 
-    async fn connect_by_uuid_async(
-        &mut self,
-        device: &BluetoothDevice,
-        uuid: Uuid,
-        need_pairing: bool,
-    ) -> crate::Result<()> {
+    // 在connect_with_cancel里并且已经取消了，就不往下走了
+    fn check_cancelled(&self) -> crate::Result<()> {
+        if self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.is_cancelled())
+        {
+            return Err(BluetoothError::Cancelled);
+        }
+        Ok(())
+    }
+
+    // 查询类的步骤，成功时记找到了几个
+    fn end_step_found<T>(&mut self, result: &crate::Result<(T, u32)>) {
+        match result {
+            Ok((_, found)) => self.end_step(StepOutcome::Found(*found as usize)),
+            Err(_) => self.end_step_with(result),
+        }
+    }
+
+    // 放弃正在进行的写。要先Cancel让WinRT不再碰缓冲区，再扔掉持有缓冲区的future
+    fn cancel_write(&mut self) {
+        if let Some(op) = self.write_op.take() {
+            let _ = op.Cancel();
+        }
+        self.write_future = None;
+        self.flush_future = None;
+        // 和读一样，被取消的缓冲区不回池
+        self.write_buffer = None;
+        self.write_started = None;
+        self.stats.set_pending_writes(0);
+    }
+
+    // 等挂着的那次写完成，顺便记统计、还缓冲区。没有挂着的写时直接返回0
+    fn poll_write_future(&mut self, cx: &mut Context<'_>) -> Poll<windows::core::Result<u32>> {
+        let Some(future) = self.write_future.as_mut() else {
+            return Poll::Ready(Ok(0));
+        };
+        let result = ready!(future.as_mut().poll(cx));
+
+        self.write_op = None;
+        self.write_future = None;
+        self.stats.set_pending_writes(0);
+        match result {
+            Ok(written) => {
+                // 写完了WinRT就不再碰它，还回池里
+                if let Some(pooled) = self.write_buffer.take() {
+                    self.buffer_pool.put(pooled);
+                }
+                if let Some(started) = self.write_started.take() {
+                    self.stats.record_write_latency(started.elapsed());
+                }
+                self.stats.record_write(written as usize);
+            }
+            Err(_) => {
+                self.write_buffer = None;
+                self.write_started = None;
+                self.ready = false;
+            }
+        }
+
+        Poll::Ready(result)
+    }
+
+    fn pooled_buffer(&mut self) -> windows::core::Result<Buffer> {
+        self.buffer_pool.take(|| Buffer::Create(POOLED_BUFFER_SIZE))
+    }
+
+    // 每次连接前把上一条链路留下的东西都清掉：socket、状态回调、设备对象、断开标记和诊断
+    fn reset_for_connect(&mut self, device: &BluetoothDevice, uuid: Uuid) {
         let _ = self.socket.Close();
 
         self.device = device.clone();
         self.uuid = uuid;
+        self.unwatch_status();
+        // 没注册上回调时unwatch_status不会动它，这里单独清一下
+        self.winrt_device = None;
+        self.link_lost.store(false, Ordering::Relaxed);
         self.ready = false;
-        self.read_future = None;
-        self.write_future = None;
+        self.clear_buffers();
+        if self.diagnostics.is_some() {
+            self.diagnostics = Some(ConnectDiagnostics::new());
+        }
+    }
+
+    // 重置会话，找到设备并按需配对，连接前的公共部分
+    async fn open_device(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: &PairingConfig,
+    ) -> crate::Result<Bluetooth::BluetoothDevice> {
+        self.reset_for_connect(device, uuid);
 
         // 获取查询过滤器
         let addr = self.device.addr();
@@ -124,15 +359,20 @@ impl BluetoothSppSession for WinrtSession {
         )?;
 
         // 查询设备
-        let winrt_device_list = winrt_async_with_error(
-            DeviceInformation::FindAllAsyncAqsFilter(&winrt_device_filter),
-            BluetoothError::DeviceNotFound,
-        )
-        .await?;
-
-        if winrt_error_wrap_with_error(winrt_device_list.Size(), BluetoothError::DeviceNotFound)?
-            < 1
-        {
+        self.begin_step("FindAll");
+        let result = async {
+            let list = winrt_async_with_error(
+                DeviceInformation::FindAllAsyncAqsFilter(&winrt_device_filter),
+                BluetoothError::DeviceNotFound,
+            )
+            .await?;
+            let found = winrt_error_wrap_with_error(list.Size(), BluetoothError::DeviceNotFound)?;
+            Ok((list, found))
+        }
+        .await;
+        self.end_step_found(&result);
+        let (winrt_device_list, found) = result?;
+        if found < 1 {
             return Err(BluetoothError::DeviceNotFound);
         }
 
@@ -142,114 +382,915 @@ impl BluetoothSppSession for WinrtSession {
             BluetoothError::DeviceNotFound,
         )?;
 
-        // 创建设备对象
-        let winrt_device = winrt_async_with_error(
-            Bluetooth::BluetoothDevice::FromIdAsync(&winrt_error_wrap_with_error(
-                device_info.Id(),
+        // 创建设备对象。开始了的步骤里出错也要先记下来再返回
+        self.begin_step("FromId");
+        let winrt_device = async {
+            let id = winrt_error_wrap_with_error(device_info.Id(), BluetoothError::DeviceNotFound)?;
+            winrt_async_with_error(
+                Bluetooth::BluetoothDevice::FromIdAsync(&id),
                 BluetoothError::DeviceNotFound,
-            )?),
-            BluetoothError::DeviceNotFound,
-        )
-        .await?;
+            )
+            .await
+        }
+        .await;
+        self.end_step_with(&winrt_device);
+        let winrt_device = winrt_device?;
+        self.check_cancelled()?;
 
         // 是否需要配对
-        if need_pairing {
-            // 这里要从创建的对象里重新拿一下info
-            let info = winrt_error_wrap_with_error(
-                winrt_device.DeviceInformation(),
-                BluetoothError::DeviceNotPairing,
-            )?;
-
-            let pairing =
-                winrt_error_wrap_with_error(info.Pairing(), BluetoothError::DeviceNotPairing)?;
-
-            let can_pair =
-                winrt_error_wrap_with_error(pairing.CanPair(), BluetoothError::DeviceNotPairing)?;
-            let is_paired =
-                winrt_error_wrap_with_error(pairing.IsPaired(), BluetoothError::DeviceNotPairing)?;
-
-            // 查询是否可配对以及是否已经配对
-            if can_pair && !is_paired {
-                let custom = winrt_error_wrap_with_error(
-                    pairing.Custom(),
+        if let Some(agent) = pairing.agent() {
+            self.state = SessionState::Pairing;
+            self.begin_step("Pair");
+            let paired = async {
+                // 这里要从创建的对象里重新拿一下info
+                let info = winrt_error_wrap_with_error(
+                    winrt_device.DeviceInformation(),
                     BluetoothError::DeviceNotPairing,
                 )?;
 
-                // 弹出授权窗口
-                let handler = winrt_error_wrap_with_error(
-                    custom.PairingRequested(&TypedEventHandler::new(pair_handler)),
-                    BluetoothError::DeviceNotPairing,
-                )?;
+                let info_pairing =
+                    winrt_error_wrap_with_error(info.Pairing(), BluetoothError::DeviceNotPairing)?;
 
-                // 配对
-                winrt_async(
-                    // 目前只处理直接就能配对的
-                    custom.PairAsync(DevicePairingKinds::ConfirmOnly),
-                )
-                .await?;
-
-                // 删除handler
-                winrt_none_error_wrap_with_error(
-                    custom.RemovePairingRequested(handler),
-                    BluetoothError::DeviceNotPairing,
-                )?;
+                // 已经配对的设备直接跳过，不碰Custom()也不注册handler，重连时省掉这段开销。
+                // 设备本身不可配对时和以前一样不管，交给后面的服务查询去报错
+                let mut backend = WinrtPairing::new(self.device.clone(), info_pairing);
+                match pair_if_needed(&mut backend, agent).await {
+                    Ok(_) | Err(PairingError::NotPairable) => Ok(()),
+                    Err(err) => Err(BluetoothError::from(err)),
+                }
             }
+            .await;
+            self.end_step_with(&paired);
+            paired?;
         }
 
+        self.check_cancelled()?;
+        self.state = SessionState::Connecting;
+        self.watch_status(&winrt_device)?;
+        self.winrt_device = Some(winrt_device.clone());
+
+        Ok(winrt_device)
+    }
+
+    // 真正的连接流程，成功失败的状态由connect_by_uuid_async统一收尾
+    async fn connect_inner(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: &PairingConfig,
+    ) -> crate::Result<()> {
+        let winrt_device = self.open_device(device, uuid, pairing).await?;
+
         // 创建服务uuid
         let service_id = winrt_error_wrap(create_service_id(self.uuid))?;
 
         // 获取特定服务
-        let winrt_service_list = winrt_async_with_error(
-            winrt_device.GetRfcommServicesForIdAsync(&service_id),
-            BluetoothError::ServiceNotFound,
-        )
-        .await?;
-
-        // 获取服务列表
-        let list_services = winrt_error_wrap_with_error(
-            winrt_service_list.Services(),
-            BluetoothError::ServiceNotFound,
-        )?;
+        self.begin_step("GetServices");
+        let result = async {
+            let winrt_service_list = winrt_async_with_error(
+                winrt_device.GetRfcommServicesForIdAsync(&service_id),
+                BluetoothError::ServiceNotFound,
+            )
+            .await?;
 
-        if winrt_error_wrap_with_error(list_services.Size(), BluetoothError::DeviceNotFound)? < 1 {
+            // 获取服务列表
+            let list_services = winrt_error_wrap_with_error(
+                winrt_service_list.Services(),
+                BluetoothError::ServiceNotFound,
+            )?;
+            let found =
+                winrt_error_wrap_with_error(list_services.Size(), BluetoothError::DeviceNotFound)?;
+            // IVectorView不是Send，不能留到后面的await之后
+            Ok((list_services.into_iter().collect::<Vec<_>>(), found))
+        }
+        .await;
+        self.end_step_found(&result);
+        let (services, found) = result?;
+        if found < 1 {
+            // 配对是成功的就说清楚，免得以为配对出了问题。配对不撤销
+            if pairing.is_required() && is_paired(&winrt_device) {
+                return Err(BluetoothError::ServiceNotFoundAfterPairing);
+            }
             return Err(BluetoothError::ServiceNotFound);
         }
 
         // 获取服务对象
-        let winrt_service =
-            winrt_error_wrap_with_error(list_services.GetAt(0), BluetoothError::ServiceNotFound)?;
+        let winrt_service = services
+            .into_iter()
+            .next()
+            .ok_or(BluetoothError::ServiceNotFound)?;
+        self.check_cancelled()?;
+
+        self.connect_service(&winrt_service).await
+    }
 
-        // 创建socket
+    // 连到一个已经找到的服务上
+    async fn connect_service(&mut self, winrt_service: &RfcommDeviceService) -> crate::Result<()> {
+        // 创建socket，缓冲区大小只能在连接前设
         self.socket = winrt_error_wrap(StreamSocket::new())?;
+        if let Some(size) = self.outbound_buffer_size {
+            let control = winrt_error_wrap(self.socket.Control())?;
+            winrt_none_error_wrap(apply_outbound_buffer_size(&control, size))?;
+        }
+
+        // 发起连接，这里的错误要保留HRESULT，方便区分通道被占用的情况
+        let service_name = winrt_service.ConnectionServiceName().unwrap();
+        self.begin_step("Connect");
+        let connected = async {
+            self.socket
+                .ConnectAsync(&winrt_service.ConnectionHostName().unwrap(), &service_name)
+                .map_err(winrt_connect_error)?
+                .await
+                .map_err(winrt_connect_error)
+        }
+        .await;
+        self.end_step_with(&connected);
+        connected?;
 
-        // 发起连接
-        winrt_async_action(self.socket.ConnectAsync(
-            &winrt_service.ConnectionHostName().unwrap(),
-            &winrt_service.ConnectionServiceName().unwrap(),
-        ))
+        if let Some(wait) = self.validate_after_connect {
+            self.begin_step("Validate");
+            let probe = async {
+                let device = winrt_error_wrap(winrt_service.Device())?;
+                winrt_error_wrap(device.ConnectionStatus()).map(connection_status)
+            };
+            let validated = validate_link(wait, probe).await;
+            self.end_step_with(&validated);
+            if validated.is_err() {
+                let _ = self.socket.Close();
+            }
+            validated?;
+        }
+
+        self.service_name = Some(service_name.to_string());
+        self.adapter = self.local_address();
+        self.mark_ready();
+
+        Ok(())
+    }
+
+    /// 按服务在SDP里发布的名字（ServiceName属性）连接，适合设备文档只给了服务名的情况。
+    ///
+    /// 会列出设备的所有RFCOMM服务逐个读服务名，名字完全一致的才连；一个都对不上返回`ServiceNotFound`。
+    pub fn connect_by_rfcomm_service_name(
+        &mut self,
+        device: &BluetoothDevice,
+        service_name: &str,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        block_on(self.affinity, async {
+            self.connect_by_rfcomm_service_name_async(device, service_name, pairing)
+                .await
+        })?
+    }
+
+    pub async fn connect_by_rfcomm_service_name_async(
+        &mut self,
+        device: &BluetoothDevice,
+        service_name: &str,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.state = SessionState::Connecting;
+
+        let result = self
+            .connect_by_service_name_inner(device, service_name, &pairing.into())
+            .await;
+        self.state = match result {
+            Ok(_) => SessionState::Connected,
+            Err(_) => SessionState::Failed,
+        };
+
+        result
+    }
+
+    async fn connect_by_service_name_inner(
+        &mut self,
+        device: &BluetoothDevice,
+        service_name: &str,
+        pairing: &PairingConfig,
+    ) -> crate::Result<()> {
+        let winrt_device = self.open_device(device, self.uuid, pairing).await?;
+
+        // 不按UUID过滤，拿到所有服务
+        let result = winrt_async_with_error(
+            winrt_device.GetRfcommServicesAsync(),
+            BluetoothError::ServiceNotFound,
+        )
         .await?;
+        // 先拿出来，IVectorView不是Send，不能跨下面的await
+        let services: Vec<RfcommDeviceService> =
+            winrt_error_wrap_with_error(result.Services(), BluetoothError::ServiceNotFound)?
+                .into_iter()
+                .collect();
 
-        self.ready = true;
+        let mut named = Vec::new();
+        for service in services {
+            let name = sdp_service_name(&service).await;
+            named.push((service, name));
+        }
+
+        let winrt_service =
+            select_service_by_name(named, service_name).ok_or(BluetoothError::ServiceNotFound)?;
+
+        // 按名字连的时候UUID以实际连上的服务为准
+        if let Ok(guid) = winrt_service.ServiceId().and_then(|id| id.Uuid()) {
+            self.uuid = Uuid::from_u128(guid.to_u128());
+        }
+
+        self.connect_service(&winrt_service).await
+    }
+
+    /// 换成另一个设备地址，比如硬件换了或者恢复出厂后地址变了。
+    ///
+    /// 只改记下的设备，UUID、服务名、标签这些配置都保留，当前连接也不动；
+    /// 之后的`connection_profile`和重连用新地址。
+    pub fn rebind_to(&mut self, device: &BluetoothDevice) {
+        self.device = device.clone();
+    }
+
+    /// 先按`device`的地址连，设备找不到时按它的名字重新找一遍地址，找到就换过去再连一次。
+    ///
+    /// 适合现场换了同型号硬件、地址变了但名字没变的情况；`device`没有名字时不会重找。
+    pub fn connect_by_name(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        block_on(self.affinity, async {
+            self.connect_by_name_async(device, uuid, pairing).await
+        })?
+    }
+
+    pub async fn connect_by_name_async(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        connect_or_resolve(
+            self,
+            device,
+            uuid,
+            pairing.into(),
+            |name| async move { resolve_device_by_name(&name).await },
+            WinrtSession::rebind_to,
+        )
+        .await
+    }
+
+    // 本地适配器的地址，形如"(00:1A:7D:DA:71:13)"
+    fn local_address(&self) -> Option<String> {
+        self.socket
+            .Information()
+            .and_then(|info| info.LocalAddress())
+            .and_then(|host| host.RawName())
+            .map(|name| name.to_string())
+            .ok()
+    }
+
+    /// 已连接设备的详细信息，系统给不出来的字段是`None`，不会因此报错。未连接时返回`NotConnected`。
+    pub fn device_details(&self) -> crate::Result<DeviceDetails> {
+        if !self.ready {
+            return Err(BluetoothError::NotConnected);
+        }
+
+        // listener收到的连接没有查过设备对象，只能给个空的
+        let Some(device) = self.winrt_device.as_ref() else {
+            return Ok(DeviceDetails::default());
+        };
 
+        Ok(DeviceDetails {
+            device_id: device
+                .BluetoothDeviceId()
+                .and_then(|id| id.Id())
+                .map(|id| id.to_string())
+                .ok(),
+            class_of_device: device
+                .ClassOfDevice()
+                .and_then(|class| class.RawValue())
+                .ok(),
+            connected: device
+                .ConnectionStatus()
+                .map(|status| status == BluetoothConnectionStatus::Connected)
+                .ok(),
+            secure_connection: device.WasSecureConnectionUsedForPairing().ok(),
+        })
+    }
+
+    /// 等下一次系统报上来的连接状态变化，比如断线后等它重新连上。
+    ///
+    /// 上次取完之后已经有变化了就立刻返回最早的那个；还没连过（没有设备可订阅）时返回`NotConnected`。
+    pub async fn next_status_change(&mut self) -> crate::Result<ConnectionStatus> {
+        if self.status_token.is_none() {
+            return Err(BluetoothError::NotConnected);
+        }
+
+        self.status_changes.next().await
+    }
+
+    /// 设备连接状态变化的流，设备关机、走出范围时会推出`Disconnected`。
+    ///
+    /// 收到断开以后会话按断开处理，之后的读写直接返回`NotConnected`，不用等WinRT那边超时。
+    /// 流被drop时注销系统回调。还没连过（没有设备可订阅）时返回`NotConnected`。
+    pub fn watch_connection_status(&self) -> crate::Result<ConnectionStatusWatch> {
+        let Some(device) = self.winrt_device.clone() else {
+            return Err(BluetoothError::NotConnected);
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let link_lost = self.link_lost.clone();
+        let token = winrt_error_wrap(device.ConnectionStatusChanged(&TypedEventHandler::new(
+            move |device: Ref<'_, Bluetooth::BluetoothDevice>, _: Ref<'_, IInspectable>| {
+                if let Some(device) = device.as_ref() {
+                    let status = connection_status(device.ConnectionStatus()?);
+                    if status == ConnectionStatus::Disconnected {
+                        link_lost.store(true, Ordering::Relaxed);
+                    }
+                    let _ = sender.send(status);
+                }
+                Ok(())
+            },
+        )))?;
+
+        Ok(ConnectionStatusWatch {
+            device,
+            token,
+            receiver,
+        })
+    }
+
+    /// 查一下链路还在不在，空闲了很久、发关键指令之前用。
+    ///
+    /// 不读数据，而是在`timeout`内重新向系统查询设备的连接状态。发现已经断了会像`disconnect`一样
+    /// 关掉socket并把状态改成断开，返回`false`；系统没按时回答返回`TimedOut`，状态不动。
+    /// listener收到的连接没有设备对象可查，只看会话自己记的状态。
+    pub async fn check_alive(&mut self, timeout: Duration) -> crate::Result<bool> {
+        if !self.ready {
+            return Ok(false);
+        }
+
+        let Some(device) = self.winrt_device.as_ref() else {
+            return Ok(true);
+        };
+
+        let device_id = winrt_error_wrap(device.DeviceId())?;
+        let query = async {
+            let device = winrt_async(Bluetooth::BluetoothDevice::FromIdAsync(&device_id)).await?;
+            winrt_error_wrap(device.ConnectionStatus())
+        };
+
+        let status = match time::timeout(timeout, query).await {
+            Ok(status) => status?,
+            Err(_) => {
+                return Err(BluetoothError::TimedOut {
+                    duration: timeout,
+                    operation: TimeoutOp::Read,
+                });
+            }
+        };
+
+        if connection_status(status) == ConnectionStatus::Connected {
+            Ok(true)
+        } else {
+            self.disconnect();
+            Ok(false)
+        }
+    }
+
+    /// 连接时socket的发送缓冲区大小（字节），下次连接时生效。
+    ///
+    /// 不设就用系统默认值，一般够用；持续大量发送（比如传文件、刷固件）时调到64KB到256KB
+    /// 能少一些停顿。StreamSocket只认连接前的设置，已连接时调用返回`Unsupported`。
+    /// 接收缓冲区StreamSocketControl没有提供，没法设。
+    pub fn set_outbound_buffer_size(&mut self, size: u32) -> crate::Result<()> {
+        if self.ready {
+            return Err(BluetoothError::Unsupported(
+                "socket buffer size must be set before connecting",
+            ));
+        }
+
+        self.outbound_buffer_size = Some(size);
         Ok(())
     }
 
-    async fn connect_async(
+    pub fn outbound_buffer_size(&self) -> Option<u32> {
+        self.outbound_buffer_size
+    }
+
+    /// 一次`WriteAsync`最多交多少字节（至少1），多出来的由调用方下次再写，`None`不拆。
+    ///
+    /// 有些串口桥一次收太多会丢数据，这时候调小。实际用的值见`effective_io_sizes`
+    pub fn set_max_write_chunk(&mut self, max: Option<usize>) {
+        self.max_write_chunk = max.map(|max| max.max(1));
+    }
+
+    /// 一次`ReadAsync`最多读多少字节（至少1），默认4096。
+    ///
+    /// 调用方给的`ReadBuf`再大也只按这个大小建WinRT缓冲区，没填满的由调用方接着读。
+    /// 不超过4096的从缓冲池里拿，持续大量接收时可以调大，每次读会单独分配一个缓冲区
+    pub fn set_read_chunk_size(&mut self, size: u32) {
+        self.read_chunk_size = size.max(1);
+    }
+
+    /// 连上以后等`wait`再查一次链路状态，已经断了就让连接失败，返回`ConnectionRefused`，`None`关掉（默认）。
+    ///
+    /// 有些配置不对的设备接受RFCOMM连接后几毫秒就断开，不打开的话连接照样算成功，要到第一次读写才报错。
+    /// 打开后每次连接多花`wait`，几十毫秒一般就够了
+    pub fn set_validate_after_connect(&mut self, wait: Option<Duration>) {
+        self.validate_after_connect = wait;
+    }
+
+    /// 打开后每次连接都记录一条时间线（查设备、配对、查服务、连socket各花了多久、结果如何），
+    /// 连接失败也能用`connect_diagnostics`取回来。关掉时清空。
+    pub fn set_connect_diagnostics(&mut self, enabled: bool) {
+        self.diagnostics = enabled.then(ConnectDiagnostics::new);
+    }
+
+    /// 最近一次连接的时间线，没打开诊断时是`None`
+    pub fn connect_diagnostics(&self) -> Option<&ConnectDiagnostics> {
+        self.diagnostics.as_ref()
+    }
+
+    /// 导出这次连接的配置，存起来下次可以用`connect_from_profile`直接重连
+    pub fn connection_profile(&self) -> ConnectionProfile {
+        ConnectionProfile {
+            device: self.device.clone(),
+            uuid: self.uuid,
+            service_name: self.service_name.clone(),
+            adapter: self.adapter.clone(),
+        }
+    }
+
+    /// 按保存的配置重连。有服务名时直接连，跳过设备和服务查询；没有就走普通的`connect_by_uuid`。
+    ///
+    /// 不会再配对，配置里的设备应该已经配好对了。
+    pub fn connect_from_profile(&mut self, profile: &ConnectionProfile) -> crate::Result<()> {
+        block_on(self.affinity, async {
+            self.connect_from_profile_async(profile).await
+        })?
+    }
+
+    pub async fn connect_from_profile_async(
+        &mut self,
+        profile: &ConnectionProfile,
+    ) -> crate::Result<()> {
+        let service_name = match &profile.service_name {
+            Some(name) => name.clone(),
+            None => {
+                return self
+                    .connect_by_uuid_async(&profile.device, profile.uuid, false)
+                    .await;
+            }
+        };
+
+        self.reset_for_connect(&profile.device, profile.uuid);
+        self.state = SessionState::Connecting;
+
+        // 跳过了查设备和查服务，诊断里只有连接这一步
+        self.begin_step("Connect");
+        let result = async {
+            // RFCOMM的主机名就是括号包起来的设备地址
+            let host = winrt_error_wrap(HostName::CreateHostName(&HSTRING::from(format!(
+                "({})",
+                profile.device.addr_string()
+            ))))?;
+            self.socket = winrt_error_wrap(StreamSocket::new())?;
+            self.socket
+                .ConnectAsync(&host, &HSTRING::from(service_name.as_str()))
+                .map_err(winrt_connect_error)?
+                .await
+                .map_err(winrt_connect_error)
+        }
+        .await;
+        self.end_step_with(&result);
+
+        match result {
+            Ok(()) => {
+                self.service_name = Some(service_name);
+                self.adapter = self.local_address();
+                self.mark_ready();
+                self.state = SessionState::Connected;
+                Ok(())
+            }
+            Err(err) => {
+                self.state = SessionState::Failed;
+                Err(err)
+            }
+        }
+    }
+
+    /// 同步connect的工作线程进哪种COM套间。驱动或配对组件要求STA时设成`SingleThread`，见`ConnectAffinity`。
+    pub fn set_connect_affinity(&mut self, affinity: ConnectAffinity) {
+        self.affinity = affinity;
+    }
+
+    /// 给会话打个标签，多设备时方便在日志里区分，只是元数据，不影响连接
+    pub fn set_label(&mut self, label: String) {
+        self.label = label;
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// 暂停读：之后的`poll_read`一直返回`Pending`，也不再发起新的`ReadAsync`，连接保持不动。
+    ///
+    /// 已经发出去的那次读不会被取消，它的结果等`resume_reads`之后再交出去。
+    pub fn pause_reads(&mut self) {
+        self.reads_paused = true;
+    }
+
+    /// 恢复读，唤醒暂停期间挂起的读
+    pub fn resume_reads(&mut self) {
+        self.reads_paused = false;
+        if let Some(waker) = self.paused_read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// 上次读多出来的数据还没交出去时返回`true`，这时`poll_read`会立刻返回`Ready`。
+    ///
+    /// 挂着的WinRT读操作不算：它可能已经完成了，但不poll一次是不知道的。
+    pub fn can_read_without_blocking(&self) -> bool {
+        self.ready && !self.reads_paused && !self.leftover.is_empty()
+    }
+
+    /// 打开后每次`write`/`write_all`在`WriteAsync`完成后还会`FlushAsync`，数据真正发出去才返回，
+    /// 默认关闭。
+    ///
+    /// 适合一问一答的协议，不用再记得手动flush；代价是每次写都要多等一轮，
+    /// 连续写很多小块时吞吐会明显下降，这种场景还是关掉、最后统一flush比较好。
+    pub fn set_auto_flush(&mut self, auto_flush: bool) {
+        self.auto_flush = auto_flush;
+    }
+
+    /// 每次写从发起`WriteAsync`到完成的耗时，链路质量变差时这里会先涨
+    pub fn write_latency_stats(&self) -> &LatencyStats {
+        self.stats.write_latency()
+    }
+
+    /// 给日志用的一行摘要，例如`OBDII (00:02:B0:57:7D:D6) service=SPP ready=true read_pending=false`。
+    ///
+    /// 和`Debug`不同，这个格式是稳定的，可以直接给用户看。
+    pub fn summary(&self) -> String {
+        session_summary(
+            &self.label,
+            &self.device,
+            self.uuid,
+            self.ready,
+            self.read_future.is_some(),
+        )
+    }
+
+    /// 把几段数据按顺序一次写出去，比如分开编码的帧头和负载，省掉调用方自己拼接的那次拷贝。
+    ///
+    /// 所有分段都写进同一个`DataWriter`，只发起一次`WriteAsync`，返回实际写出的字节数。
+    /// 之前被放弃的`write`还没写完时，会先等它写完，数据不会交错。
+    pub async fn write_vectored(&mut self, bufs: &[&[u8]]) -> crate::Result<usize> {
+        self.sync_link_lost();
+        if !self.ready {
+            return Err(BluetoothError::NotConnected);
+        }
+
+        // 同一时间只能有一个WriteAsync
+        std::future::poll_fn(|cx| self.poll_write_future(cx))
+            .await
+            .map_err(winrt_write_bluetooth_error)?;
+
+        let writer = winrt_error_wrap(DataWriter::new())?;
+        for buf in bufs {
+            winrt_none_error_wrap(writer.WriteBytes(buf))?;
+        }
+        let buffer = winrt_error_wrap(writer.DetachBuffer())?;
+
+        let stream = winrt_error_wrap(self.socket.OutputStream())?;
+        let started = std::time::Instant::now();
+        self.stats.set_pending_writes(1);
+        let result = async { stream.WriteAsync(&buffer)?.await }.await;
+        self.stats.set_pending_writes(0);
+
+        match result {
+            Ok(written) => {
+                self.stats.record_write_latency(started.elapsed());
+                self.stats.record_write(written as usize);
+                Ok(written as usize)
+            }
+            Err(err) => {
+                self.ready = false;
+                Err(winrt_write_bluetooth_error(err))
+            }
+        }
+    }
+}
+
+/// `WinrtSession::watch_connection_status`返回的状态流，drop时注销系统回调
+pub struct ConnectionStatusWatch {
+    device: Bluetooth::BluetoothDevice,
+    token: i64,
+    receiver: mpsc::UnboundedReceiver<ConnectionStatus>,
+}
+
+impl Stream for ConnectionStatusWatch {
+    type Item = ConnectionStatus;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for ConnectionStatusWatch {
+    fn drop(&mut self) {
+        let _ = self.device.RemoveConnectionStatusChanged(self.token);
+    }
+}
+
+fn connection_status(status: BluetoothConnectionStatus) -> ConnectionStatus {
+    if status == BluetoothConnectionStatus::Connected {
+        ConnectionStatus::Connected
+    } else {
+        ConnectionStatus::Disconnected
+    }
+}
+
+// 一次ReadAsync请求多少字节。Partial模式下有多少给多少，大的ReadBuf靠调用方接着读来填满
+pub(crate) fn read_request_size(remaining: usize, chunk_size: u32) -> u32 {
+    remaining.min(chunk_size as usize) as u32
+}
+
+fn is_paired(device: &Bluetooth::BluetoothDevice) -> bool {
+    device
+        .DeviceInformation()
+        .and_then(|info| info.Pairing())
+        .and_then(|pairing| pairing.IsPaired())
+        .unwrap_or(false)
+}
+
+// 按设备名找系统里的设备，用它现在的地址，同名的有多个时取第一个
+async fn resolve_device_by_name(name: &str) -> crate::Result<BluetoothDevice> {
+    let filter = winrt_error_wrap(Bluetooth::BluetoothDevice::GetDeviceSelectorFromDeviceName(
+        &HSTRING::from(name),
+    ))?;
+    let list = winrt_async_with_error(
+        DeviceInformation::FindAllAsyncAqsFilter(&filter),
+        BluetoothError::DeviceNotFound,
+    )
+    .await?;
+
+    let info = winrt_error_wrap_with_error(list.GetAt(0), BluetoothError::DeviceNotFound)?;
+    let winrt_device = winrt_async_with_error(
+        Bluetooth::BluetoothDevice::FromIdAsync(&winrt_error_wrap_with_error(
+            info.Id(),
+            BluetoothError::DeviceNotFound,
+        )?),
+        BluetoothError::DeviceNotFound,
+    )
+    .await?;
+    let addr = winrt_error_wrap_with_error(
+        winrt_device.BluetoothAddress(),
+        BluetoothError::DeviceNotFound,
+    )?;
+
+    Ok(BluetoothDevice::new(name.to_string(), addr))
+}
+
+// 读服务的ServiceName属性，读不到或者不是文本都当成没有名字
+pub(crate) async fn sdp_service_name(service: &RfcommDeviceService) -> Option<String> {
+    let attributes = service.GetSdpRawAttributesAsync().ok()?.await.ok()?;
+    let raw = attributes.Lookup(SERVICE_NAME_ATTRIBUTE_ID).ok()?;
+    decode_text_attribute(&read_input_buffer(raw).ok()?)
+}
+
+impl std::fmt::Debug for WinrtSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WinrtSession")
+            .field("label", &self.label)
+            .field("device", &self.device)
+            .field("uuid", &self.uuid)
+            .field("state", &self.state())
+            .field("read_pending", &self.read_future.is_some())
+            .field("write_pending", &self.write_future.is_some())
+            .finish()
+    }
+}
+
+impl BluetoothSppSession for WinrtSession {
+    fn connect(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.connect_by_uuid(device, SPP_UUID, pairing)
+    }
+
+    fn connect_timeout(
         &mut self,
         device: &BluetoothDevice,
-        need_pairing: bool,
+        pairing: impl Into<PairingConfig>,
+        timeout: std::time::Duration,
     ) -> crate::Result<()> {
-        self.connect_by_uuid_async(device, SPP_UUID, need_pairing)
+        self.connect_by_uuid_timeout(device, SPP_UUID, pairing, timeout)
+    }
+
+    fn connect_by_uuid(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        block_on(self.affinity, async {
+            self.connect_by_uuid_async(device, uuid, pairing).await
+        })?
+    }
+
+    fn connect_by_uuid_timeout(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+        timeout: std::time::Duration,
+    ) -> crate::Result<()> {
+        let pairing = pairing.into();
+        let affinity = self.affinity;
+        let result = block_on(affinity, async {
+            time::timeout(timeout, async {
+                self.connect_by_uuid_async(device, uuid, pairing).await
+            })
             .await
+        })?;
+
+        if let Err(_) = result {
+            if let Some(diagnostics) = self.diagnostics.as_mut() {
+                diagnostics.time_out_pending();
+            }
+
+            // 卡在配对就报配对超时
+            let operation = match self.state {
+                SessionState::Pairing => TimeoutOp::Pairing,
+                _ => TimeoutOp::Connect,
+            };
+            self.state = SessionState::Failed;
+            return Err(BluetoothError::TimedOut {
+                duration: timeout,
+                operation,
+            });
+        } else if let Ok(Err(err)) = result {
+            return Err(err);
+        }
+
+        return Ok(());
+    }
+
+    async fn connect_by_uuid_async(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.state = SessionState::Connecting;
+
+        let result = self.connect_inner(device, uuid, &pairing.into()).await;
+        self.state = match result {
+            Ok(_) => SessionState::Connected,
+            Err(_) => SessionState::Failed,
+        };
+
+        result
+    }
+
+    async fn connect_async(
+        &mut self,
+        device: &BluetoothDevice,
+        pairing: impl Into<PairingConfig>,
+    ) -> crate::Result<()> {
+        self.connect_by_uuid_async(device, SPP_UUID, pairing).await
+    }
+
+    // 查设备、配对、查服务、连socket每一步之间检查一次`cancel`，正在跑的那一步会等它跑完，
+    // 不会像直接扔掉future那样把WinRT操作留在后台。取消后会话是断开状态，不算连接失败
+    async fn connect_with_cancel(
+        &mut self,
+        device: &BluetoothDevice,
+        uuid: Uuid,
+        pairing: impl Into<PairingConfig>,
+        cancel: CancellationToken,
+    ) -> crate::Result<()> {
+        self.cancel = Some(cancel);
+        let result = self.connect_by_uuid_async(device, uuid, pairing).await;
+        self.cancel = None;
+
+        if let Err(BluetoothError::Cancelled) = result {
+            self.unwatch_status();
+            self.disconnect();
+        }
+        result
     }
 
     fn device(&self) -> &BluetoothDevice {
         &self.device
     }
 
-    fn into_device(self) -> BluetoothDevice {
-        self.device
+    fn into_device(mut self) -> BluetoothDevice {
+        // 实现了Drop，不能直接把字段移出去
+        std::mem::replace(&mut self.device, BluetoothDevice::empty())
+    }
+
+    fn disconnect(&mut self) {
+        // 挂起的读写直接扔掉，不等WinRT那边完成
+        self.cancel_read();
+        self.cancel_write();
+        let _ = self.socket.Close();
+        // 带取消的connect被中途扔掉时令牌还留着，不能影响下一次connect
+        self.cancel = None;
+        self.ready = false;
+        self.state = SessionState::Disconnected;
+    }
+
+    async fn close(&mut self) -> crate::Result<()> {
+        let result = async {
+            // 之前没写完的先写完
+            if let Some(future) = self.write_future.take() {
+                future
+                    .await
+                    .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+            }
+
+            // 再把输出流flush掉
+            if self.ready {
+                let stream = winrt_error_wrap(self.socket.OutputStream())?;
+                winrt_async(stream.FlushAsync()).await?;
+            }
+
+            Ok(())
+        }
+        .await;
+
+        // 不管上面成不成功都要把socket关掉
+        self.disconnect();
+
+        result
+    }
+
+    fn state(&self) -> SessionState {
+        // 读写出错时只会把ready清掉，这里按断开处理
+        match self.state {
+            SessionState::Connected if !self.is_connected() => SessionState::Disconnected,
+            state => state,
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.ready
+            && self.state == SessionState::Connected
+            && !self.link_lost.load(Ordering::Relaxed)
+    }
+
+    fn cancel_read(&mut self) {
+        // 顺序不能反：先Cancel，WinRT停下来以后才能扔掉future和它保活的缓冲区
+        if let Some(op) = self.read_op.take() {
+            let _ = op.Cancel();
+        }
+        self.read_future = None;
+        // 被取消的操作可能还在往里写，这个缓冲区不能再回池
+        self.read_buffer = None;
+        self.leftover.clear();
+    }
+
+    fn clear_buffers(&mut self) {
+        // 读了没交出去的leftover在cancel_read里清掉
+        self.cancel_read();
+        self.cancel_write();
+    }
+
+    fn capabilities(&self) -> SessionCapabilities {
+        // StreamSocket的输入输出流是分开的，读写可以同时挂着
+        SessionCapabilities {
+            concurrent_read_write: true,
+            ..SessionCapabilities::default()
+        }
+    }
+
+    fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    fn effective_io_sizes(&self) -> IoSizes {
+        IoSizes {
+            read_chunk: Some(read_request_size(usize::MAX, self.read_chunk_size) as usize),
+            write_chunk: self.max_write_chunk,
+        }
+    }
+}
+
+// 没重连就丢掉的会话也要把RFCOMM连接和系统句柄放掉。没连过的会话Close也不会出错
+impl Drop for WinrtSession {
+    fn drop(&mut self) {
+        self.cancel_read();
+        self.cancel_write();
+        self.unwatch_status();
+        let _ = self.socket.Close();
     }
 }
 
@@ -260,11 +1301,12 @@ impl AsyncRead for WinrtSession {
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
         let self_mut = self.get_mut();
+        self_mut.sync_link_lost();
 
-        // 如果连接未准备好，直接踹踹包然后返回Pending并清理旧future
+        // 连接没准备好就直接报错，清理旧future，免得调用方一直挂着
         if !self_mut.ready {
-            self_mut.read_future = None;
-            return Poll::Pending;
+            self_mut.cancel_read();
+            return Poll::Ready(Err(BluetoothError::NotConnected.into()));
         }
 
         // 缓冲区没有可写空间，则认为本次读取已经完成
@@ -272,24 +1314,48 @@ impl AsyncRead for WinrtSession {
             return Poll::Ready(Ok(()));
         }
 
+        if self_mut.reads_paused {
+            self_mut.paused_read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        // 上次没放下的数据先交出去
+        if !self_mut.leftover.is_empty() {
+            let data = std::mem::take(&mut self_mut.leftover);
+            let len = data.len();
+            self_mut.leftover = put_read_data(buf, data);
+            self_mut.stats.record_read(len - self_mut.leftover.len());
+            return Poll::Ready(Ok(()));
+        }
+
         // 没有挂起的读future时，发起新的ra请求
         if self_mut.read_future.is_none() {
             let stream = match self_mut.socket.InputStream() {
                 Ok(s) => s,
-                Err(_) => {
-                    // 获取输入流失败，标记会话未就绪然后给Pending
+                Err(err) => {
+                    // 获取输入流失败，标记会话未就绪，把错误交给调用方
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
             };
 
-            let cap = buf.remaining() as u32;
-            let buffer = match Buffer::Create(cap) {
+            // 只按没填的部分算，ReadBuf里可能已经有调用方（比如BufReader）填好的数据。
+            // 一次最多读read_chunk_size，调用方给的ReadBuf再大也不跟着建大缓冲区
+            let cap = read_request_size(buf.remaining(), self_mut.read_chunk_size);
+            let buffer = if cap <= POOLED_BUFFER_SIZE {
+                self_mut.pooled_buffer().inspect(|b| {
+                    self_mut.read_buffer = Some(b.clone());
+                })
+            } else {
+                // 比池缓冲区大的不回池，读完就扔
+                Buffer::Create(cap)
+            };
+            let buffer = match buffer {
                 Ok(b) => b,
-                Err(_) => {
-                    // 缓冲区创建失败，也给Pending
+                Err(err) => {
+                    // 缓冲区创建失败，也报错
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
             };
 
@@ -297,6 +1363,7 @@ impl AsyncRead for WinrtSession {
             self_mut.read_future = match stream.ReadAsync(&buffer, cap, InputStreamOptions::Partial)
             {
                 Ok(op) => {
+                    self_mut.read_op = Some(op.clone());
                     let buffer_clone = buffer.clone();
                     Some(Box::pin(async move {
                         // 打个flag，确保WinRT缓冲区在future完成前不被释放
@@ -304,10 +1371,11 @@ impl AsyncRead for WinrtSession {
                         op.into_future().await
                     }))
                 }
-                Err(_) => {
-                    // 发起异步读取失败，等待上层重新触发
+                Err(err) => {
+                    // 发起异步读取失败
+                    self_mut.read_buffer = None;
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
             };
         }
@@ -318,24 +1386,39 @@ impl AsyncRead for WinrtSession {
             match future.as_mut().poll(cx) {
                 // WinRT成功返回数据，拷贝到上层缓冲区
                 Poll::Ready(Ok(buffer)) => {
+                    self_mut.read_op = None;
                     self_mut.read_future = None;
-                    match read_input_buffer(buffer) {
+                    let result = read_input_buffer(buffer);
+                    // 数据已经拷出来了，操作也结束了，可以还回池里
+                    if let Some(pooled) = self_mut.read_buffer.take() {
+                        self_mut.buffer_pool.put(pooled);
+                    }
+                    match result {
                         Ok(vec) => {
-                            // 将WinRT缓冲区内容拷贝到调用者提供的缓冲区
-                            buf.put_slice(&vec);
+                            // 将WinRT缓冲区内容接到调用者缓冲区已有内容的后面。
+                            // 读是按第一次poll时的ReadBuf发起的，之后换了更小的ReadBuf也不能丢数据
+                            let len = vec.len();
+                            self_mut.leftover = put_read_data(buf, vec);
+                            self_mut.stats.record_read(len - self_mut.leftover.len());
                             return Poll::Ready(Ok(()));
                         }
-                        Err(_) => {
+                        Err(err) => {
                             self_mut.ready = false;
-                            return Poll::Pending;
+                            return Poll::Ready(Err(winrt_io_error(err)));
                         }
                     }
                 }
-                // WinRT future报错，重置状态等待下一次调用
-                Poll::Ready(Err(_)) => {
+                // WinRT future报错，重置状态并把错误交出去。对端断开按EOF处理，
+                // 调用方的read拿到0，read_exact拿到UnexpectedEof
+                Poll::Ready(Err(err)) => {
+                    self_mut.read_op = None;
                     self_mut.read_future = None;
+                    self_mut.read_buffer = None;
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    if is_peer_disconnect(&err) {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
                 // 仍然未完成，返回Pending继续等待
                 // 这就和block_on一样实现阻塞逻辑了
@@ -356,82 +1439,151 @@ impl AsyncWrite for WinrtSession {
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         let self_mut = self.get_mut();
+        self_mut.sync_link_lost();
 
         // 这一堆狗屎逻辑和上面的read一样
         if !self_mut.ready {
-            self_mut.write_future = None;
-            return Poll::Pending;
+            self_mut.cancel_write();
+            return Poll::Ready(Err(BluetoothError::NotConnected.into()));
         }
 
         if buf.is_empty() {
             return Poll::Ready(Ok(0));
         }
 
+        // 超过上限的只写前面一段，剩下的调用方（比如write_all）会接着写
+        let buf = match self_mut.max_write_chunk {
+            Some(max) => &buf[..buf.len().min(max)],
+            None => buf,
+        };
+
         if self_mut.write_future.is_none() {
             let stream = match self_mut.socket.OutputStream() {
                 Ok(s) => s,
-                Err(_) => {
+                Err(err) => {
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
             };
 
-            // 数据转IBuffer
-            let buffer = match write_output_buffer(buf.to_vec()) {
+            // 数据拷进Buffer，放得下就用池里的
+            let buffer = if buf.len() <= POOLED_BUFFER_SIZE as usize {
+                self_mut.pooled_buffer().and_then(|b| {
+                    fill_output_buffer(&b, buf)?;
+                    self_mut.write_buffer = Some(b.clone());
+                    Ok(b)
+                })
+            } else {
+                // 和读一样，比池缓冲区大的不回池
+                Buffer::Create(buf.len() as u32).and_then(|b| {
+                    fill_output_buffer(&b, buf)?;
+                    Ok(b)
+                })
+            };
+            let buffer = match buffer {
                 Ok(b) => b,
-                Err(_) => {
+                Err(err) => {
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
             };
 
+            self_mut.write_started = Some(std::time::Instant::now());
+            self_mut.stats.set_pending_writes(1);
             self_mut.write_future = match stream.WriteAsync(&buffer) {
                 Ok(op) => {
+                    self_mut.write_op = Some(op.clone());
                     let buffer_clone = buffer.clone();
+                    // IOutputStream不是Send，要flush时从socket重新拿
+                    let flush_socket = self_mut.auto_flush.then(|| self_mut.socket.clone());
                     Some(Box::pin(async move {
                         // poll同款keep-alive
                         let _keep_alive = buffer_clone;
-                        op.into_future().await
+                        let written = op.into_future().await?;
+                        if let Some(socket) = flush_socket {
+                            let flush = socket.OutputStream()?.FlushAsync()?;
+                            flush.await?;
+                        }
+                        Ok(written)
                     }))
                 }
-                Err(_) => {
+                Err(err) => {
+                    self_mut.write_buffer = None;
+                    self_mut.stats.set_pending_writes(0);
                     self_mut.ready = false;
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_io_error(err)));
                 }
             };
         }
 
-        if let Some(future) = self_mut.write_future.as_mut() {
-            match future.as_mut().poll(cx) {
-                Poll::Ready(Ok(written)) => {
-                    self_mut.write_future = None;
-                    return Poll::Ready(Ok(written as usize));
-                }
-                Poll::Ready(Err(_)) => {
-                    self_mut.write_future = None;
+        // 对端断开报BrokenPipe
+        self_mut.poll_write_future(cx).map(|result| {
+            result
+                .map(|written| written as usize)
+                .map_err(winrt_write_error)
+        })
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        let self_mut = self.get_mut();
+        self_mut.sync_link_lost();
+
+        if !self_mut.ready {
+            self_mut.flush_future = None;
+            return Poll::Ready(Err(BluetoothError::NotConnected.into()));
+        }
+
+        // 和poll_write一样把future留着，下次poll接着等
+        if self_mut.flush_future.is_none() {
+            let flush = self_mut
+                .socket
+                .OutputStream()
+                .and_then(|stream| stream.FlushAsync());
+            match flush {
+                Ok(op) => self_mut.flush_future = Some(Box::pin(op.into_future())),
+                Err(err) => {
                     self_mut.ready = false;
-                    return Poll::Pending;
-                }
-                Poll::Pending => {
-                    return Poll::Pending;
+                    return Poll::Ready(Err(winrt_write_error(err)));
                 }
             }
         }
 
-        Poll::Pending
-    }
+        let Some(future) = self_mut.flush_future.as_mut() else {
+            return Poll::Pending;
+        };
 
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+        match future.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                self_mut.flush_future = None;
+                if let Err(err) = result {
+                    self_mut.ready = false;
+                    return Poll::Ready(Err(winrt_write_error(err)));
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 
     fn poll_shutdown(
         self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+        let self_mut = self.get_mut();
+        self_mut.sync_link_lost();
+
+        // 已经断开的再shutdown一次也算成功，顺手把socket关掉
+        if !self_mut.ready {
+            self_mut.disconnect();
+            return Poll::Ready(Ok(()));
+        }
+
+        // 先把输出流flush完再关socket，flush失败也照样关，错误交给调用方
+        let flushed = ready!(Pin::new(&mut *self_mut).poll_flush(cx));
+        self_mut.disconnect();
+        Poll::Ready(flushed)
     }
 }
@@ -1,9 +1,88 @@
+use tokio::io::ReadBuf;
 use windows::{
-    Storage::Streams::{DataReader, DataWriter, IBuffer},
-    core,
+    Networking::Sockets::StreamSocketControl,
+    Storage::Streams::{Buffer, DataReader, DataWriter, IBuffer},
+    Win32::System::WinRT::{
+        IBufferByteAccess, RO_INIT_MULTITHREADED, RO_INIT_SINGLETHREADED, RoInitialize,
+        RoUninitialize,
+    },
+    core::{self, HRESULT, Interface},
 };
 
-use crate::BluetoothError;
+use crate::{BluetoothError, common::runtime::ConnectAffinity};
+
+// HRESULT_FROM_WIN32(ERROR_SHARING_VIOLATION)，别的程序已经占着这个RFCOMM通道时ConnectAsync会报这个
+pub const E_SHARING_VIOLATION: HRESULT = HRESULT(0x80070020_u32 as i32);
+
+// 连接阶段的错误需要看HRESULT，不能先转成字符串再判断
+pub fn winrt_connect_error(err: core::Error) -> BluetoothError {
+    if err.code() == E_SHARING_VIOLATION {
+        BluetoothError::ConnectionRefused(
+            "RFCOMM channel is in use by another application".to_string(),
+        )
+    } else {
+        BluetoothError::RuntimeError(err.to_string())
+    }
+}
+
+// 同步connect的工作线程进入的COM套间，drop时退出
+pub(crate) struct Apartment;
+
+impl Apartment {
+    pub(crate) fn enter(affinity: ConnectAffinity) -> crate::Result<Apartment> {
+        let init = match affinity {
+            ConnectAffinity::MultiThread => RO_INIT_MULTITHREADED,
+            ConnectAffinity::SingleThread => RO_INIT_SINGLETHREADED,
+        };
+        // 新建的线程还没进过套间，不会碰到RPC_E_CHANGED_MODE
+        unsafe { RoInitialize(init) }
+            .map_err(|err| BluetoothError::RuntimeError(err.to_string()))?;
+        Ok(Apartment)
+    }
+}
+
+impl Drop for Apartment {
+    fn drop(&mut self) {
+        unsafe { RoUninitialize() };
+    }
+}
+
+// AsyncRead/AsyncWrite里的WinRT错误，转成io::Error交给调用方
+pub(crate) fn winrt_io_error(err: core::Error) -> std::io::Error {
+    BluetoothError::RuntimeError(err.to_string()).into()
+}
+
+// 对端断开时读写会报的HRESULT：WSAECONNABORTED、WSAECONNRESET、WSAENOTCONN、WSAESHUTDOWN，
+// 还有socket已经被关掉时的RO_E_CLOSED
+const PEER_DISCONNECT_CODES: [HRESULT; 5] = [
+    HRESULT(0x80072745_u32 as i32),
+    HRESULT(0x80072746_u32 as i32),
+    HRESULT(0x80072749_u32 as i32),
+    HRESULT(0x8007274A_u32 as i32),
+    HRESULT(0x80000013_u32 as i32),
+];
+
+pub(crate) fn is_peer_disconnect(err: &core::Error) -> bool {
+    PEER_DISCONNECT_CODES.contains(&err.code())
+}
+
+// 写的时候对端断开报BrokenPipe，别的错误照旧
+pub(crate) fn winrt_write_error(err: core::Error) -> std::io::Error {
+    if is_peer_disconnect(&err) {
+        BluetoothError::PeerDisconnected.into()
+    } else {
+        winrt_io_error(err)
+    }
+}
+
+// 同`winrt_write_error`，给返回`crate::Result`的写用
+pub(crate) fn winrt_write_bluetooth_error(err: core::Error) -> BluetoothError {
+    if is_peer_disconnect(&err) {
+        BluetoothError::PeerDisconnected
+    } else {
+        BluetoothError::RuntimeError(err.to_string())
+    }
+}
 
 pub fn winrt_error_wrap<T: core::RuntimeType + 'static>(
     result: core::Result<T>,
@@ -91,12 +170,26 @@ pub async fn winrt_async_action_with_error(
     }
 }
 
+// 只读Length范围内的有效数据，Capacity后面那段是没初始化的
 pub fn read_input_buffer(buffer: IBuffer) -> core::Result<Vec<u8>> {
+    let valid = buffer.Length()? as usize;
+    if valid == 0 {
+        return Ok(Vec::new());
+    }
+
     let reader = DataReader::FromBuffer(&buffer)?;
-    let len = reader.UnconsumedBufferLength()? as usize;
+    let len = valid.min(reader.UnconsumedBufferLength()? as usize);
     let mut value = vec![0; len];
     reader.ReadBytes(value.as_mut_slice())?;
-    Ok(value.to_vec())
+    Ok(value)
+}
+
+// 把读到的数据接在ReadBuf已经填好的内容后面，放不下的部分原样返回
+pub(crate) fn put_read_data(buf: &mut ReadBuf<'_>, mut data: Vec<u8>) -> Vec<u8> {
+    let len = data.len().min(buf.remaining());
+    let rest = data.split_off(len);
+    buf.put_slice(&data);
+    rest
 }
 
 pub fn write_output_buffer(bytes: Vec<u8>) -> core::Result<IBuffer> {
@@ -105,6 +198,41 @@ pub fn write_output_buffer(bytes: Vec<u8>) -> core::Result<IBuffer> {
     writer.DetachBuffer()
 }
 
+// 把数据直接拷进一个已有的Buffer，池里的缓冲区靠它复用，不用每次都新建DataWriter
+pub fn fill_output_buffer(buffer: &Buffer, bytes: &[u8]) -> core::Result<()> {
+    if bytes.len() > buffer.Capacity()? as usize {
+        // HRESULT_FROM_WIN32(ERROR_INSUFFICIENT_BUFFER)
+        return Err(core::Error::from_hresult(HRESULT(0x8007007A_u32 as i32)));
+    }
+
+    let access: IBufferByteAccess = buffer.cast()?;
+    unsafe {
+        // 长度上面已经检查过，不会写出Capacity
+        let data = access.Buffer()?;
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), data, bytes.len());
+    }
+    buffer.SetLength(bytes.len() as u32)
+}
+
+// socket发送缓冲区的设置，单独抽出来测试时可以换成假的
+pub(crate) trait SocketBufferControl {
+    fn set_outbound_buffer_size(&self, size: u32) -> core::Result<()>;
+}
+
+impl SocketBufferControl for StreamSocketControl {
+    fn set_outbound_buffer_size(&self, size: u32) -> core::Result<()> {
+        self.SetOutboundBufferSizeInBytes(size)
+    }
+}
+
+// 必须在ConnectAsync之前调，连上以后再改系统不认
+pub(crate) fn apply_outbound_buffer_size(
+    control: &impl SocketBufferControl,
+    size: u32,
+) -> core::Result<()> {
+    control.set_outbound_buffer_size(size)
+}
+
 pub fn to_hex_string(data: &[u8]) -> String {
     data.iter().map(|b| format!("{:02x}", b)).collect()
 }